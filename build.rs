@@ -1,16 +1,26 @@
 use std::env;
 use std::fs;
 
+const PROTOS: [&str; 5] = [
+    "proto/axon_server/command.proto",
+    "proto/axon_server/control.proto",
+    "proto/axon_server/event.proto",
+    "proto/axon_server/query.proto",
+    "proto/axon_server/common.proto"
+];
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if env::var("CARGO_FEATURE_TEST_SERVER").is_ok() {
+        // The server side is only needed by the test server; it is generated in OUT_DIR, so that
+        // the checked-in client code stays the same.
+        tonic_build::configure()
+            .build_server(true)
+            .out_dir(env::var("OUT_DIR")?)
+            .compile(&PROTOS, &["proto/axon_server"])?;
+    }
     env::set_var("OUT_DIR", "src");
     tonic_build::configure().build_server(false).compile(
-        &[
-            "proto/axon_server/command.proto",
-            "proto/axon_server/control.proto",
-            "proto/axon_server/event.proto",
-            "proto/axon_server/query.proto",
-            "proto/axon_server/common.proto"
-        ],
+        &PROTOS,
         &["proto/axon_server"]
     )?;
     fs::remove_file("src/google.protobuf.rs")?;
@@ -2,10 +2,52 @@
 //!
 //! This module contains a Rust implementation of the AxonSever.
 
+#[cfg(not(feature = "test-server"))]
 pub mod command;
+#[cfg(not(feature = "test-server"))]
 pub mod common;
+#[cfg(not(feature = "test-server"))]
 pub mod control;
+#[cfg(not(feature = "test-server"))]
 pub mod event;
+#[cfg(not(feature = "test-server"))]
 pub mod query;
 
+// With feature `test-server`, the same protos are generated with the server side included (see `build.rs`).
+#[cfg(feature = "test-server")]
+pub mod command {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/io.axoniq.axonserver.grpc.command.rs"
+    ));
+}
+#[cfg(feature = "test-server")]
+pub mod common {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/io.axoniq.axonserver.grpc.common.rs"
+    ));
+}
+#[cfg(feature = "test-server")]
+pub mod control {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/io.axoniq.axonserver.grpc.control.rs"
+    ));
+}
+#[cfg(feature = "test-server")]
+pub mod event {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/io.axoniq.axonserver.grpc.event.rs"
+    ));
+}
+#[cfg(feature = "test-server")]
+pub mod query {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/io.axoniq.axonserver.grpc.query.rs"
+    ));
+}
+
 pub use common::{ErrorMessage, FlowControl, SerializedObject};
@@ -0,0 +1,500 @@
+use super::command_worker::{get_handler, internal_handle_command, AggregateBackend};
+use super::deadline::{deadline_command, ScheduledDeadline};
+use super::error::AxonError;
+use super::event_scheduler::{encode_payload, ScheduleToken};
+use super::fixture::{assert_error, assert_payloads, assert_success};
+use super::metadata::{empty_meta_data, MetaData};
+use super::upcaster::{empty_upcaster_registry, UpcasterRegistry};
+use super::{AggregateDefinition, VecU8Message};
+use crate::axon_server::command::Command;
+use crate::axon_server::event::Event;
+use crate::axon_server::SerializedObject;
+use anyhow::Result;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// Given-when-then test fixture for an aggregate.
+///
+/// The fixture handles a command with the command handlers and sourcing handlers of an `AggregateDefinition`
+/// against a list of given past events, without connecting to AxonServer. Only the given events of the aggregate
+/// that the command targets are applied to its projection:
+///
+/// ```ignore
+/// aggregate_test_fixture(create_greeter_aggregate_definition())
+///     .given(&aggregate_identifier, "GreetedEvent", &GreetedEvent { message: Some(greeting) })?
+///     .when("GreetCommand", &GreetCommand { aggregate_identifier, message: Some(greeting) })
+///     .await
+///     .expect_events(&[("GreetedEvent", &GreetedEvent { message: Some(greeting) })]);
+/// ```
+pub struct AggregateTestFixture<P: VecU8Message + Send + Sync + Clone + 'static> {
+    aggregate_definition: Arc<AggregateDefinition<P>>,
+    given_events: Vec<Event>,
+    upcasters: UpcasterRegistry,
+    meta_data: MetaData,
+}
+
+/// Creates a test fixture for the aggregate with the given definition.
+pub fn aggregate_test_fixture<P: VecU8Message + Send + Sync + Clone + Debug + 'static>(
+    aggregate_definition: AggregateDefinition<P>,
+) -> AggregateTestFixture<P> {
+    AggregateTestFixture {
+        aggregate_definition: Arc::new(aggregate_definition),
+        given_events: Vec::new(),
+        upcasters: empty_upcaster_registry(),
+        meta_data: empty_meta_data(),
+    }
+}
+
+impl<P: VecU8Message + Send + Sync + Clone + Debug + 'static> AggregateTestFixture<P> {
+    /// Upcasts the given events with the given registry before they are applied to the projection.
+    pub fn with_upcasters(mut self, upcasters: UpcasterRegistry) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// Sets the metadata of the commands that are handled by this fixture.
+    pub fn with_meta_data(mut self, meta_data: MetaData) -> Self {
+        self.meta_data = meta_data;
+        self
+    }
+
+    /// Adds a past event of the aggregate with the given identifier.
+    pub fn given(
+        self,
+        aggregate_id: &str,
+        event_type: &str,
+        event: &(dyn VecU8Message + Sync),
+    ) -> Result<Self> {
+        Ok(self.given_serialized(aggregate_id, encode_payload(event_type, event)?))
+    }
+
+    /// Adds a past event of the aggregate with the given identifier that is already serialized, e.g., with an
+    /// older revision.
+    pub fn given_serialized(mut self, aggregate_id: &str, event: SerializedObject) -> Self {
+        let sequence_number = self
+            .given_events
+            .iter()
+            .filter(|given| given.aggregate_identifier == aggregate_id)
+            .count() as i64;
+        self.given_events.push(Event {
+            message_identifier: format!("{}", Uuid::new_v4()),
+            timestamp: 0,
+            aggregate_identifier: aggregate_id.to_string(),
+            aggregate_sequence_number: sequence_number,
+            aggregate_type: self.aggregate_definition.projection_name.clone(),
+            payload: Some(event),
+            meta_data: empty_meta_data(),
+            snapshot: false,
+        });
+        self
+    }
+
+    /// Handles a command against the given events.
+    pub async fn when(
+        &self,
+        command_type: &str,
+        command: &(dyn VecU8Message + Sync),
+    ) -> AggregateTestResult {
+        let command = encode_payload(command_type, command).map(|payload| Command {
+            message_identifier: format!("{}", Uuid::new_v4()),
+            name: command_type.to_string(),
+            payload: Some(payload),
+            client_id: "".to_string(),
+            component_name: "".to_string(),
+            meta_data: self.meta_data.clone(),
+            processing_instructions: Vec::new(),
+            timestamp: 0,
+        });
+        self.handle(command).await
+    }
+
+    /// Fires a deadline of the aggregate with the given identifier against the given events.
+    pub async fn when_deadline(
+        &self,
+        aggregate_id: &str,
+        deadline_name: &str,
+        payload_type: &str,
+        payload: &(dyn VecU8Message + Sync),
+    ) -> AggregateTestResult {
+        let command = encode_payload(payload_type, payload).and_then(|payload| {
+            deadline_command(
+                &self.aggregate_definition.projection_name,
+                aggregate_id,
+                deadline_name,
+                payload,
+                &self.meta_data,
+                SystemTime::now(),
+            )
+        });
+        self.handle(command).await
+    }
+
+    async fn handle(&self, command: Result<Command>) -> AggregateTestResult {
+        match self.try_handle(command).await {
+            Ok(result) => result,
+            Err(error) => AggregateTestResult {
+                events: Vec::new(),
+                response: None,
+                error: Some(error),
+                deadlines: Vec::new(),
+                cancelled_deadlines: Vec::new(),
+            },
+        }
+    }
+
+    async fn try_handle(&self, command: Result<Command>) -> Result<AggregateTestResult> {
+        let command = command?;
+        self.aggregate_definition.clear_cache()?;
+        let (handler, aggregate_id) = get_handler(&command, &self.aggregate_definition)?;
+        let handler = handler.ok_or_else(|| {
+            AxonError::missing_handler(format!("Missing command handler: {:?}", command.name))
+        })?;
+        let handled = internal_handle_command(
+            handler,
+            &command,
+            aggregate_id.as_deref(),
+            self.aggregate_definition.clone(),
+            AggregateBackend::Given(self.given_events.clone()),
+            &self.upcasters,
+        )
+        .await?;
+        let mut events = Vec::new();
+        for (event_type, revision, event) in &handled.events {
+            let mut data = Vec::new();
+            event.encode_u8(&mut data)?;
+            events.push(SerializedObject {
+                r#type: event_type.clone(),
+                revision: revision.clone(),
+                data,
+            });
+        }
+        Ok(AggregateTestResult {
+            events,
+            response: handled.result,
+            error: None,
+            deadlines: handled.scheduled_deadlines,
+            cancelled_deadlines: handled.cancelled_deadlines,
+        })
+    }
+}
+
+/// The outcome of handling a command with an `AggregateTestFixture`.
+///
+/// Fields:
+/// * `events`: The events that were emitted by the command handler.
+/// * `response`: The response of the command handler.
+/// * `error`: The error of the command handler. If it is set, no events were emitted.
+/// * `deadlines`: The deadlines that were scheduled by the command handler.
+/// * `cancelled_deadlines`: The tokens of the deadlines that were cancelled by the command handler.
+#[derive(Debug)]
+pub struct AggregateTestResult {
+    pub events: Vec<SerializedObject>,
+    pub response: Option<SerializedObject>,
+    pub error: Option<anyhow::Error>,
+    pub deadlines: Vec<ScheduledDeadline>,
+    pub cancelled_deadlines: Vec<ScheduleToken>,
+}
+
+impl AggregateTestResult {
+    /// Panics unless the command succeeded and emitted exactly the given events, in order.
+    ///
+    /// Events are compared by type and encoded payload.
+    pub fn expect_events(&self, expected: &[(&str, &(dyn VecU8Message + Sync))]) -> &Self {
+        assert_success(&self.error, "command");
+        assert_payloads(&self.events, expected, "events");
+        self
+    }
+
+    /// Panics unless the command succeeded without emitting events.
+    pub fn expect_no_events(&self) -> &Self {
+        self.expect_events(&[])
+    }
+
+    /// Panics unless the command succeeded with the given response.
+    pub fn expect_response(
+        &self,
+        response_type: &str,
+        response: &(dyn VecU8Message + Sync),
+    ) -> &Self {
+        assert_success(&self.error, "command");
+        assert_payloads(
+            self.response.as_slice(),
+            &[(response_type, response)],
+            "response",
+        );
+        self
+    }
+
+    /// Panics unless the command succeeded and scheduled deadlines with the given names, in order.
+    pub fn expect_deadlines(&self, deadline_names: &[&str]) -> &Self {
+        assert_success(&self.error, "command");
+        let actual: Vec<&str> = self
+            .deadlines
+            .iter()
+            .map(|deadline| deadline.deadline_name.as_str())
+            .collect();
+        assert_eq!(actual, deadline_names, "Unexpected deadlines");
+        self
+    }
+
+    /// Panics unless the command failed. Returns the error that the sender of the command would get.
+    pub fn expect_error(&self) -> AxonError {
+        assert_error(&self.error, "command")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axon_utils::{
+        business_error, create_aggregate_definition, empty_handler_registry, AggregateContext,
+        AggregateContextTrait, ApplicableTo, HandlerRegistry, TheHandlerRegistry,
+    };
+    use prost::Message;
+    use std::time::Duration;
+
+    const COUNTER_ID: &str = "counter-1";
+    const OTHER_COUNTER_ID: &str = "counter-2";
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Counter {
+        #[prost(uint32, tag = "1")]
+        count: u32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct IncrementCommand {
+        #[prost(string, tag = "1")]
+        aggregate_identifier: String,
+        #[prost(uint32, tag = "2")]
+        limit: u32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct IncrementedEvent {
+        #[prost(uint32, tag = "1")]
+        count: u32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct ResetEvent {}
+
+    impl ApplicableTo<Counter> for IncrementedEvent {
+        fn apply_to(&self, projection: &mut Counter) -> Result<()> {
+            projection.count = self.count;
+            Ok(())
+        }
+
+        fn box_clone(&self) -> Box<dyn ApplicableTo<Counter>> {
+            Box::from(self.clone())
+        }
+    }
+
+    impl ApplicableTo<Counter> for ResetEvent {
+        fn apply_to(&self, projection: &mut Counter) -> Result<()> {
+            projection.count = 0;
+            Ok(())
+        }
+
+        fn box_clone(&self) -> Box<dyn ApplicableTo<Counter>> {
+            Box::from(self.clone())
+        }
+    }
+
+    type Context = Arc<async_lock::Mutex<AggregateContext<Counter>>>;
+
+    /// Increments the counter up to the limit, and schedules a reset when the limit is reached.
+    async fn handle_increment(command: IncrementCommand, context: Context) -> Result<()> {
+        let mut context = context.lock().await;
+        let counter = context
+            .get_projection(&command.aggregate_identifier)
+            .await?;
+        if counter.count >= command.limit {
+            return Err(business_error("Limit reached", &[]).into());
+        }
+        let count = counter.count + 1;
+        context.emit("IncrementedEvent", Box::new(IncrementedEvent { count }))?;
+        if count == command.limit {
+            context
+                .schedule_deadline(
+                    "reset",
+                    Duration::from_secs(60),
+                    "ResetEvent",
+                    &ResetEvent {},
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_reset(_payload: ResetEvent, context: Context) -> Result<()> {
+        context
+            .lock()
+            .await
+            .emit("ResetEvent", Box::new(ResetEvent {}))
+    }
+
+    async fn apply_incremented(
+        event: IncrementedEvent,
+        mut counter: Counter,
+    ) -> Result<Option<Counter>> {
+        event.apply_to(&mut counter)?;
+        Ok(Some(counter))
+    }
+
+    async fn apply_reset(event: ResetEvent, mut counter: Counter) -> Result<Option<Counter>> {
+        event.apply_to(&mut counter)?;
+        Ok(Some(counter))
+    }
+
+    fn counter_fixture() -> AggregateTestFixture<Counter> {
+        let mut command_handlers: TheHandlerRegistry<Context, SerializedObject> =
+            empty_handler_registry();
+        command_handlers
+            .insert("IncrementCommand", &IncrementCommand::decode, &|c, p| {
+                Box::pin(handle_increment(c, p))
+            })
+            .unwrap();
+        let mut deadline_handlers: TheHandlerRegistry<Context, SerializedObject> =
+            empty_handler_registry();
+        deadline_handlers
+            .insert("reset", &ResetEvent::decode, &|c, p| {
+                Box::pin(handle_reset(c, p))
+            })
+            .unwrap();
+        let mut sourcing_handlers: TheHandlerRegistry<Counter, Counter> = empty_handler_registry();
+        sourcing_handlers
+            .insert_with_output("IncrementedEvent", &IncrementedEvent::decode, &|c, p| {
+                Box::pin(apply_incremented(c, p))
+            })
+            .unwrap();
+        sourcing_handlers
+            .insert_with_output("ResetEvent", &ResetEvent::decode, &|c, p| {
+                Box::pin(apply_reset(c, p))
+            })
+            .unwrap();
+        aggregate_test_fixture(
+            create_aggregate_definition(
+                "Counter".to_string(),
+                Box::from(Counter::default as fn() -> Counter),
+                command_handlers,
+                sourcing_handlers,
+            )
+            .with_deadline_handlers(deadline_handlers),
+        )
+    }
+
+    fn increment(limit: u32) -> IncrementCommand {
+        IncrementCommand {
+            aggregate_identifier: COUNTER_ID.to_string(),
+            limit,
+        }
+    }
+
+    #[tokio::test]
+    async fn command_emits_events_against_given_events() -> Result<()> {
+        counter_fixture()
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 2 },
+            )?
+            .when("IncrementCommand", &increment(5))
+            .await
+            .expect_events(&[("IncrementedEvent", &IncrementedEvent { count: 3 })])
+            .expect_deadlines(&[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn command_ignores_events_of_other_aggregates() -> Result<()> {
+        counter_fixture()
+            .given(
+                OTHER_COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .given(
+                OTHER_COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 2 },
+            )?
+            .when("IncrementCommand", &increment(5))
+            .await
+            .expect_events(&[("IncrementedEvent", &IncrementedEvent { count: 2 })]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_command_reports_business_error() -> Result<()> {
+        let error = counter_fixture()
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .when("IncrementCommand", &increment(1))
+            .await
+            .expect_error();
+        assert!(matches!(error, AxonError::Business(_)));
+        assert_eq!(error.details().message, "Limit reached");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_command_reports_missing_handler() {
+        let error = counter_fixture()
+            .when("DecrementCommand", &increment(1))
+            .await
+            .expect_error();
+        assert!(matches!(error, AxonError::MissingHandler(_)));
+    }
+
+    #[tokio::test]
+    async fn command_schedules_deadline() -> Result<()> {
+        let result = counter_fixture()
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .when("IncrementCommand", &increment(2))
+            .await;
+        result
+            .expect_events(&[("IncrementedEvent", &IncrementedEvent { count: 2 })])
+            .expect_deadlines(&["reset"]);
+        assert_eq!(result.deadlines[0].payload.r#type, "ResetEvent");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deadline_handler_emits_events() -> Result<()> {
+        counter_fixture()
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 1 },
+            )?
+            .given(
+                COUNTER_ID,
+                "IncrementedEvent",
+                &IncrementedEvent { count: 2 },
+            )?
+            .when_deadline(COUNTER_ID, "reset", "ResetEvent", &ResetEvent {})
+            .await
+            .expect_events(&[("ResetEvent", &ResetEvent {})]);
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
-use super::{wait_for_server, AxonServerHandle, CommandSink, VecU8Message};
-use crate::axon_server::command::command_service_client::CommandServiceClient;
+use super::error::AxonError;
+use super::routing::routing_key_instruction;
+use super::{
+    wait_for_server, AxonServerHandle, CommandSink, DispatchOptions, MetaData, VecU8Message,
+};
 use crate::axon_server::command::Command;
 use crate::axon_server::SerializedObject;
 use crate::intellij_work_around::Debuggable;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use log::debug;
-use std::collections::HashMap;
 use std::vec::Vec;
 use uuid::Uuid;
 
@@ -18,10 +20,12 @@ pub async fn init() -> Result<AxonServerHandle> {
 
 #[tonic::async_trait]
 impl CommandSink for AxonServerHandle {
-    async fn send_command(
+    async fn send_command_with_options(
         &self,
         command_type: &str,
-        command: Box<&(dyn VecU8Message + Sync)>,
+        command: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
     ) -> Result<Option<SerializedObject>> {
         debug!(
             "Sending command: {:?}: {:?}",
@@ -36,33 +40,49 @@ impl CommandSink for AxonServerHandle {
             revision: "1".to_string(),
             data: buf,
         };
-        submit_command(self, &serialized_command).await
+        submit_command(self, &serialized_command, meta_data, options).await
     }
 }
 
 async fn submit_command(
     this: &AxonServerHandle,
     message: &SerializedObject,
+    meta_data: MetaData,
+    options: DispatchOptions,
 ) -> Result<Option<SerializedObject>> {
     debug!("Message: {:?}", Debuggable::from(message));
-    let mut client = CommandServiceClient::new(this.conn.clone());
+    let options = options.or(&this.dispatch_options);
+    let mut client = this.command_service_client();
     debug!("Command Service Client: {:?}", client);
     let uuid = Uuid::new_v4();
+    let mut processing_instructions = Vec::new();
+    if let Some(routing_key) = this
+        .routing_keys
+        .routing_key(&message.r#type, &message.data)?
+    {
+        debug!("Routing key: {:?}", routing_key);
+        processing_instructions.push(routing_key_instruction(&routing_key));
+    }
+    processing_instructions.extend(options.processing_instructions());
     let command = Command {
         message_identifier: format!("{}", uuid),
         name: message.r#type.clone(),
         payload: Some(message.clone()),
         client_id: this.client_id.clone(),
         component_name: this.display_name.clone(),
-        meta_data: HashMap::new(),
-        processing_instructions: Vec::new(),
+        meta_data,
+        processing_instructions,
         timestamp: 0,
     };
-    let response = client.dispatch(command).await?;
+    let response = options
+        .enforce_timeout(&message.r#type, async {
+            Ok(client.dispatch(command).await.map_err(AxonError::from)?)
+        })
+        .await?;
     let response = response.into_inner();
     debug!("Response: {:?}", Debuggable::from(&response));
     if let Some(error_message) = response.error_message {
-        return Err(anyhow!(error_message.message));
+        return Err(AxonError::from_error_message(error_message).into());
     }
     Ok(response.payload)
 }
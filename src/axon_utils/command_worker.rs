@@ -1,13 +1,23 @@
-use super::event_query::query_events_from_client;
+use super::deadline::{deadline_aggregate_id, deadline_command_name, deadline_name};
+use super::deadline::{schedule_deadline_command, ScheduledDeadline};
+use super::error::{AxonError, MessageKind};
+use super::event_query::query_events_from_sequence;
+use super::event_scheduler::{cancel_scheduled_event_with_client, encode_payload, ScheduleToken};
 use super::handler_registry::{HandlerRegistry, SubscriptionHandle, TheHandlerRegistry};
+use super::metadata::{caused_by, MetaData};
+use super::routing::RoutingKeyRegistry;
+use super::snapshot::SnapshotPolicy;
+use super::upcaster::UpcasterRegistry;
 use super::{axon_serialize, ApplicableTo, AxonServerHandle, VecU8Message};
 use crate::axon_server::command::command_provider_outbound;
-use crate::axon_server::command::command_service_client::CommandServiceClient;
 use crate::axon_server::command::{command_provider_inbound, Command};
 use crate::axon_server::command::{CommandProviderOutbound, CommandResponse, CommandSubscription};
+use crate::axon_server::common::meta_data_value::Data;
+use crate::axon_server::common::{MetaDataValue, ProcessingKey};
+use crate::axon_server::event::event_scheduler_client::EventSchedulerClient;
 use crate::axon_server::event::event_store_client::EventStoreClient;
 use crate::axon_server::event::Event;
-use crate::axon_server::{ErrorMessage, FlowControl, SerializedObject};
+use crate::axon_server::{FlowControl, SerializedObject};
 use crate::intellij_work_around::Debuggable;
 use anyhow::{anyhow, Result};
 use async_stream::stream;
@@ -16,12 +26,14 @@ use log::{debug, error, warn};
 use lru::LruCache;
 use prost::Message;
 use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{oneshot, Semaphore};
 use tonic::transport::Channel;
-use tonic::Request;
+use tonic::{Request, Status};
 use uuid::Uuid;
 
 /// Creates a struct that can be returned by a command handler to supply the events that have
@@ -49,17 +61,70 @@ pub fn emit_events_and_response<T: Message, P: VecU8Message + Send + Clone>(
 #[tonic::async_trait]
 pub trait AggregateContextTrait<P: VecU8Message + Send + Sync + Clone + 'static> {
     fn emit(&mut self, event_type: &str, event: Box<dyn ApplicableTo<P>>) -> Result<()>;
+    fn emit_with_revision(
+        &mut self,
+        event_type: &str,
+        revision: &str,
+        event: Box<dyn ApplicableTo<P>>,
+    ) -> Result<()>;
     async fn get_projection(&mut self, aggregate_id: &str) -> Result<P>;
+    /// Returns the metadata of the command that is being handled.
+    fn get_meta_data(&self) -> &MetaData;
+    /// Schedules a deadline for the aggregate that is being handled at the given instant. When it fires, the
+    /// deadline handler with the given name is invoked with the payload.
+    ///
+    /// Returns a token that can be used to cancel the deadline. If the command fails, the deadline is cancelled.
+    async fn schedule_deadline_at(
+        &mut self,
+        deadline_name: &str,
+        instant: SystemTime,
+        payload_type: &str,
+        payload: &(dyn VecU8Message + Sync),
+    ) -> Result<ScheduleToken>;
+    /// Schedules a deadline for the aggregate that is being handled after the given delay.
+    async fn schedule_deadline(
+        &mut self,
+        deadline_name: &str,
+        delay: Duration,
+        payload_type: &str,
+        payload: &(dyn VecU8Message + Sync),
+    ) -> Result<ScheduleToken> {
+        self.schedule_deadline_at(
+            deadline_name,
+            SystemTime::now() + delay,
+            payload_type,
+            payload,
+        )
+        .await
+    }
+    /// Cancels a deadline, provided that the command is handled successfully.
+    fn cancel_deadline(&mut self, token: &ScheduleToken) -> Result<()>;
+}
+
+/// Where an aggregate context gets past events from and where it schedules deadlines.
+#[derive(Debug, Clone)]
+pub(crate) enum AggregateBackend {
+    /// Events are read from the event store of AxonServer and deadlines are scheduled with its event scheduler.
+    AxonServer {
+        event_store_client: EventStoreClient<Channel>,
+        event_scheduler_client: EventSchedulerClient<Channel>,
+    },
+    /// Events are given up front and deadlines are only recorded, see `AggregateTestFixture`.
+    Given(Vec<Event>),
 }
 
 #[derive(Debug)]
 pub struct AggregateContext<P: VecU8Message + Send + Sync + Clone + 'static> {
     aggregate_definition: Arc<AggregateDefinition<P>>,
-    event_store_client: EventStoreClient<Channel>,
-    events: Vec<(String, Box<dyn ApplicableTo<P>>)>,
+    backend: AggregateBackend,
+    events: Vec<(String, String, Box<dyn ApplicableTo<P>>)>,
     pub aggregate_id: Option<String>,
     projection: P,
     seq: i64,
+    upcasters: UpcasterRegistry,
+    meta_data: MetaData,
+    scheduled_deadlines: Vec<ScheduledDeadline>,
+    cancelled_deadlines: Vec<ScheduleToken>,
 }
 
 #[tonic::async_trait]
@@ -67,11 +132,19 @@ impl<P: VecU8Message + Send + Sync + Clone + Debug> AggregateContextTrait<P>
     for AggregateContext<P>
 {
     fn emit(&mut self, event_type: &str, event: Box<dyn ApplicableTo<P>>) -> Result<()> {
-        self.events.push((event_type.to_string(), event));
+        self.emit_with_revision(event_type, "", event)
+    }
+    fn emit_with_revision(
+        &mut self,
+        event_type: &str,
+        revision: &str,
+        event: Box<dyn ApplicableTo<P>>,
+    ) -> Result<()> {
+        self.events
+            .push((event_type.to_string(), revision.to_string(), event));
         Ok(())
     }
     async fn get_projection(&mut self, aggregate_id: &str) -> Result<P> {
-        let client = &mut self.event_store_client.clone();
         if let Some(ref existing_aggregate_id) = self.aggregate_id {
             if aggregate_id != existing_aggregate_id {
                 anyhow!(
@@ -98,8 +171,29 @@ impl<P: VecU8Message + Send + Sync + Clone + Debug> AggregateContextTrait<P>
             }
         }
         if self.seq < 0 {
-            let events = query_events_from_client(client, &aggregate_id).await?;
+            let events = match self.backend.clone() {
+                AggregateBackend::AxonServer {
+                    event_store_client, ..
+                } => {
+                    let client = &mut event_store_client.clone();
+                    if let Some(snapshot_policy) = aggregate_definition.snapshot_policy.as_ref() {
+                        if let Some((s, p)) =
+                            snapshot_policy.load_snapshot(client, aggregate_id).await?
+                        {
+                            debug!("Restored snapshot: {:?}: {:?}", aggregate_id, s);
+                            self.projection = p;
+                            self.seq = s;
+                        }
+                    }
+                    query_events_from_sequence(client, aggregate_id, self.seq + 1).await?
+                }
+                AggregateBackend::Given(events) => events
+                    .into_iter()
+                    .filter(|event| event.aggregate_identifier == aggregate_id)
+                    .collect(),
+            };
             for event in events {
+                let event = self.upcasters.upcast_event(event)?;
                 debug!("Replaying event: {:?}", Debuggable::from(&event));
                 if let Some(payload) = event.payload {
                     let sourcing_handler = self
@@ -133,22 +227,85 @@ impl<P: VecU8Message + Send + Sync + Clone + Debug> AggregateContextTrait<P>
         }
         Ok(self.projection.clone())
     }
+    fn get_meta_data(&self) -> &MetaData {
+        &self.meta_data
+    }
+    async fn schedule_deadline_at(
+        &mut self,
+        deadline_name: &str,
+        instant: SystemTime,
+        payload_type: &str,
+        payload: &(dyn VecU8Message + Sync),
+    ) -> Result<ScheduleToken> {
+        let aggregate_id = self
+            .aggregate_id
+            .clone()
+            .ok_or_else(|| anyhow!("Deadline scheduled before projection: {:?}", deadline_name))?;
+        let payload = encode_payload(payload_type, payload)?;
+        let token = match &mut self.backend {
+            AggregateBackend::AxonServer {
+                event_scheduler_client,
+                ..
+            } => {
+                schedule_deadline_command(
+                    event_scheduler_client,
+                    &self.aggregate_definition.projection_name,
+                    &aggregate_id,
+                    deadline_name,
+                    payload.clone(),
+                    &self.meta_data,
+                    instant,
+                )
+                .await?
+            }
+            AggregateBackend::Given(_) => ScheduleToken::from(format!("{}", Uuid::new_v4())),
+        };
+        self.scheduled_deadlines.push(ScheduledDeadline {
+            token: token.clone(),
+            deadline_name: deadline_name.to_string(),
+            instant,
+            payload,
+        });
+        Ok(token)
+    }
+    fn cancel_deadline(&mut self, token: &ScheduleToken) -> Result<()> {
+        self.cancelled_deadlines.push(token.clone());
+        Ok(())
+    }
+}
+
+impl<P: VecU8Message + Send + Sync + Clone> AggregateContext<P> {
+    /// Cancels the deadlines that were scheduled by a command handler that failed.
+    async fn cancel_scheduled_deadlines(&mut self) {
+        if let AggregateBackend::AxonServer {
+            event_scheduler_client,
+            ..
+        } = &mut self.backend
+        {
+            let tokens = self.scheduled_deadlines.iter().map(|d| &d.token);
+            cancel_deadlines(event_scheduler_client, tokens).await;
+        }
+    }
 }
 
 impl<P: VecU8Message + Send + Sync + Clone> Clone for AggregateContext<P> {
     fn clone(&self) -> Self {
         let mut cloned_events = Vec::new();
-        for (event_type, event) in &self.events {
-            let cloned_pair = (event_type.clone(), event.box_clone());
-            cloned_events.push(cloned_pair);
+        for (event_type, revision, event) in &self.events {
+            let cloned_triple = (event_type.clone(), revision.clone(), event.box_clone());
+            cloned_events.push(cloned_triple);
         }
         AggregateContext {
             aggregate_definition: self.aggregate_definition.clone(),
-            event_store_client: self.event_store_client.clone(),
+            backend: self.backend.clone(),
             events: cloned_events,
             aggregate_id: self.aggregate_id.clone(),
             projection: self.projection.clone(),
             seq: self.seq,
+            upcasters: self.upcasters.clone(),
+            meta_data: self.meta_data.clone(),
+            scheduled_deadlines: self.scheduled_deadlines.clone(),
+            cancelled_deadlines: self.cancelled_deadlines.clone(),
         }
     }
 }
@@ -167,7 +324,7 @@ pub struct EmitEventsAndResponse {
 /// The events have to be applicable to the projection type.
 #[derive(Debug)]
 pub struct EmitApplicableEventsAndResponse<P> {
-    events: Vec<(String, Box<dyn ApplicableTo<P>>)>,
+    events: Vec<(String, String, Box<dyn ApplicableTo<P>>)>,
     response: Option<SerializedObject>,
 }
 
@@ -177,7 +334,7 @@ impl<P> Clone for EmitApplicableEventsAndResponse<P> {
             events: self
                 .events
                 .iter()
-                .map(|(n, b)| (n.clone(), b.box_clone()))
+                .map(|(n, r, b)| (n.clone(), r.clone(), b.box_clone()))
                 .collect(),
             response: self.response.clone(),
         }
@@ -187,7 +344,7 @@ impl<P> Clone for EmitApplicableEventsAndResponse<P> {
         self.events = source
             .events
             .iter()
-            .map(|(n, b)| (n.clone(), b.box_clone()))
+            .map(|(n, r, b)| (n.clone(), r.clone(), b.box_clone()))
             .collect();
         self.response = source.response.clone();
     }
@@ -240,6 +397,18 @@ impl AggregateRegistry for TheAggregateRegistry {
     }
 }
 
+impl TheAggregateRegistry {
+    /// Collects the routing key extractors of all registered aggregates, e.g., for
+    /// `AxonServerHandleBuilder::with_routing_keys`.
+    pub fn routing_keys(&self) -> RoutingKeyRegistry {
+        let mut routing_keys = RoutingKeyRegistry::default();
+        for aggregate_handle in self.handlers.values() {
+            routing_keys.extend(&aggregate_handle.routing_keys());
+        }
+        routing_keys
+    }
+}
+
 /// Creates an empty aggregate registry that can be populated with `AggregateHandle`s (most likely: `AggregateDefinition`s).
 pub fn empty_aggregate_registry() -> TheAggregateRegistry {
     TheAggregateRegistry {
@@ -254,8 +423,11 @@ pub trait AggregateHandle: Send + Sync {
         &self,
         command: &Command,
         client: &mut EventStoreClient<Channel>,
+        scheduler: &mut EventSchedulerClient<Channel>,
+        upcasters: &UpcasterRegistry,
     ) -> Result<Option<EmitEventsAndResponse>>;
     fn command_names(&self) -> Vec<String>;
+    fn routing_keys(&self) -> RoutingKeyRegistry;
 }
 
 #[tonic::async_trait]
@@ -269,16 +441,24 @@ impl<P: VecU8Message + Send + Sync + Clone + std::fmt::Debug + 'static> Aggregat
         &self,
         command: &Command,
         client: &mut EventStoreClient<Channel>,
+        scheduler: &mut EventSchedulerClient<Channel>,
+        upcasters: &UpcasterRegistry,
     ) -> Result<Option<EmitEventsAndResponse>> {
-        handle_command(command, self.clone(), client).await
+        handle_command(command, self.clone(), client, scheduler, upcasters).await
     }
     fn command_names(&self) -> Vec<String> {
         let mut result = Vec::new();
         for (command_name, _) in &self.command_handler_registry.handlers {
             result.push((*command_name).clone());
         }
+        for deadline_name in self.deadline_handler_registry.handlers.keys() {
+            result.push(deadline_command_name(&self.projection_name, deadline_name));
+        }
         result
     }
+    fn routing_keys(&self) -> RoutingKeyRegistry {
+        self.routing_keys.clone()
+    }
 }
 
 /// The complete definition of an aggregate.
@@ -288,6 +468,10 @@ impl<P: VecU8Message + Send + Sync + Clone + std::fmt::Debug + 'static> Aggregat
 /// * `cache`: Caches command projections in memory.
 /// * `empty_projection`: Factory method for an empty projection.
 /// * `sourcing_handler_registry`: Registry that assigns a handler for each event that updates the projection.
+/// * `snapshot_policy`: Optional policy for storing and restoring snapshots of the projection.
+/// * `max_retries`: The number of times a command is retried when its events conflict with events that were stored concurrently.
+/// * `routing_keys`: Registry that extracts the routing key (usually the aggregate id) from commands.
+/// * `deadline_handler_registry`: Registry that assigns a handler for each deadline that the aggregate schedules.
 pub struct AggregateDefinition<P: VecU8Message + Send + Sync + Clone + 'static> {
     pub projection_name: String,
     cache: Arc<Mutex<LruCache<String, (i64, P)>>>,
@@ -295,8 +479,86 @@ pub struct AggregateDefinition<P: VecU8Message + Send + Sync + Clone + 'static>
     command_handler_registry:
         TheHandlerRegistry<Arc<async_lock::Mutex<AggregateContext<P>>>, SerializedObject>,
     sourcing_handler_registry: TheHandlerRegistry<P, P>,
+    snapshot_policy: Option<SnapshotPolicy<P>>,
+    max_retries: u32,
+    routing_keys: RoutingKeyRegistry,
+    deadline_handler_registry:
+        TheHandlerRegistry<Arc<async_lock::Mutex<AggregateContext<P>>>, SerializedObject>,
+}
+
+impl<P: VecU8Message + Send + Sync + Clone + 'static> AggregateDefinition<P> {
+    /// Stores snapshots of the projection in AxonServer according to the given policy and uses
+    /// them to restore the projection.
+    pub fn with_snapshot_policy(mut self, snapshot_policy: SnapshotPolicy<P>) -> Self {
+        self.snapshot_policy = Some(snapshot_policy);
+        self
+    }
+
+    /// Sets the number of times a command is retried against a fresh projection when AxonServer
+    /// rejects its events because of a sequence number conflict. The default is 3.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Declares how the routing key is extracted from the commands of this aggregate.
+    ///
+    /// Senders that know these extractors (see `TheAggregateRegistry::routing_keys`) attach the routing key
+    /// to each command, so that AxonServer routes all commands for an aggregate to the same command worker.
+    pub fn with_routing_keys(mut self, routing_keys: RoutingKeyRegistry) -> Self {
+        self.routing_keys = routing_keys;
+        self
+    }
+
+    /// Registers the handlers for the deadlines that the aggregate schedules.
+    ///
+    /// Deadline handlers are invoked like command handlers, but the projection of the aggregate that
+    /// scheduled the deadline is already restored in the context. Deadlines only fire when a
+    /// `deadline_dispatcher` is running.
+    pub fn with_deadline_handlers(
+        mut self,
+        deadline_handler_registry: TheHandlerRegistry<
+            Arc<async_lock::Mutex<AggregateContext<P>>>,
+            SerializedObject,
+        >,
+    ) -> Self {
+        self.deadline_handler_registry = deadline_handler_registry;
+        self
+    }
+
+    /// Forgets all cached projections.
+    pub(crate) fn clear_cache(&self) -> Result<()> {
+        let mut cache = self.cache.lock().map_err(|e| anyhow!(e.to_string()))?;
+        cache.clear();
+        Ok(())
+    }
+
+    fn evict(&self, aggregate_id: &str) -> Result<()> {
+        let mut cache = self.cache.lock().map_err(|e| anyhow!(e.to_string()))?;
+        cache.pop(&aggregate_id.to_string());
+        Ok(())
+    }
 }
 
+/// Error that is reported when the events of a command kept conflicting with events that were
+/// stored concurrently for the same aggregate, even after retrying.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyError {
+    pub aggregate_id: String,
+    pub attempts: u32,
+}
+
+impl Display for ConcurrencyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "Concurrent modification of aggregate: {:?}: gave up after {:?} attempts",
+            self.aggregate_id, self.attempts
+        ))
+    }
+}
+
+impl std::error::Error for ConcurrencyError {}
+
 pub struct ProjectionFactory<P> {
     factory: Box<fn() -> P>,
 }
@@ -332,6 +594,12 @@ pub fn create_aggregate_definition<P: VecU8Message + Send + Sync + Clone>(
         empty_projection,
         command_handler_registry,
         sourcing_handler_registry,
+        snapshot_policy: None,
+        max_retries: 3,
+        routing_keys: RoutingKeyRegistry::default(),
+        deadline_handler_registry: TheHandlerRegistry {
+            handlers: HashMap::new(),
+        },
     }
 }
 
@@ -339,73 +607,183 @@ async fn handle_command<P: VecU8Message + Send + Sync + Clone + std::fmt::Debug
     command: &Command,
     aggregate_definition: Arc<AggregateDefinition<P>>,
     client: &mut EventStoreClient<Channel>,
+    scheduler: &mut EventSchedulerClient<Channel>,
+    upcasters: &UpcasterRegistry,
 ) -> Result<Option<EmitEventsAndResponse>> {
     debug!("Incoming command: {:?}", Debuggable::from(command));
 
-    if let Some(command_handler) = get_command_handler(command.name.clone(), &aggregate_definition)?
-    {
-        let data = command
-            .payload
-            .clone()
-            .map(|p| p.data)
-            .ok_or(anyhow!("No payload data for: {:?}", command.name))?;
+    let (command_handler, aggregate_id) = get_handler(command, &aggregate_definition)?;
+    if let Some(command_handler) = command_handler {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let handled = internal_handle_command(
+                command_handler,
+                command,
+                aggregate_id.as_deref(),
+                aggregate_definition.clone(),
+                AggregateBackend::AxonServer {
+                    event_store_client: client.clone(),
+                    event_scheduler_client: scheduler.clone(),
+                },
+                upcasters,
+            )
+            .await?;
 
-        let (result, events, aggregate_id, seq) =
-            internal_handle_command(&command_handler, data, aggregate_definition.clone(), client)
-                .await?;
+            if handled.events.is_empty() {
+                cancel_deadlines(scheduler, handled.cancelled_deadlines.iter()).await;
+                return Ok(Some(EmitEventsAndResponse {
+                    events: vec![],
+                    response: handled.result,
+                }));
+            }
 
-        if !events.is_empty() {
             let aggregate_name = aggregate_definition.projection_name.clone();
-            let aggregate_id = aggregate_id.ok_or(anyhow!("Missing aggregate id"))?;
-            store_events(client, &aggregate_name, &aggregate_id, &events, seq + 1).await?;
+            let aggregate_id = handled
+                .aggregate_id
+                .ok_or(anyhow!("Missing aggregate id"))?;
+            let seq = handled.seq;
+            let event_meta_data = caused_by(&command.message_identifier, &command.meta_data);
+            if let Err(e) = store_events(
+                client,
+                &aggregate_name,
+                &aggregate_id,
+                &handled.events,
+                &event_meta_data,
+                seq + 1,
+            )
+            .await
+            {
+                aggregate_definition.evict(&aggregate_id)?;
+                let tokens = handled.scheduled_deadlines.iter().map(|d| &d.token);
+                cancel_deadlines(scheduler, tokens).await;
+                if !is_sequence_conflict(&e) {
+                    return Err(e);
+                }
+                if attempts > aggregate_definition.max_retries {
+                    return Err(ConcurrencyError {
+                        aggregate_id,
+                        attempts,
+                    }
+                    .into());
+                }
+                debug!(
+                    "Sequence conflict: retry command: {:?}: {:?}: {:?}",
+                    command.name, aggregate_id, attempts
+                );
+                continue;
+            }
+            if let Some(snapshot_policy) = aggregate_definition.snapshot_policy.as_ref() {
+                let new_seq = seq + handled.events.len() as i64;
+                if let Err(e) = snapshot_policy
+                    .maybe_store_snapshot(
+                        client,
+                        &aggregate_name,
+                        &aggregate_id,
+                        &handled.projection,
+                        seq,
+                        new_seq,
+                    )
+                    .await
+                {
+                    warn!("Could not store snapshot: {:?}: {:?}", aggregate_id, e);
+                }
+            }
+            cancel_deadlines(scheduler, handled.cancelled_deadlines.iter()).await;
+            return Ok(Some(EmitEventsAndResponse {
+                events: vec![],
+                response: handled.result,
+            }));
         }
-        Ok(Some(EmitEventsAndResponse {
-            events: vec![],
-            response: result,
-        }))
     } else {
-        Err(anyhow!("Missing command handler: {:?}", command.name))
+        Err(
+            AxonError::missing_handler(format!("Missing command handler: {:?}", command.name))
+                .into(),
+        )
+    }
+}
+
+/// The outcome of a command handler, together with the projection after applying the emitted events.
+pub(crate) struct HandledCommand<P> {
+    pub(crate) result: Option<SerializedObject>,
+    pub(crate) events: Vec<(String, String, Box<dyn ApplicableTo<P>>)>,
+    pub(crate) aggregate_id: Option<String>,
+    pub(crate) seq: i64,
+    pub(crate) projection: P,
+    pub(crate) scheduled_deadlines: Vec<ScheduledDeadline>,
+    pub(crate) cancelled_deadlines: Vec<ScheduleToken>,
+}
+
+/// Cancels deadlines on a best effort basis: failures are only logged.
+async fn cancel_deadlines<'a>(
+    scheduler: &mut EventSchedulerClient<Channel>,
+    tokens: impl Iterator<Item = &'a ScheduleToken>,
+) {
+    for token in tokens {
+        if let Err(e) = cancel_scheduled_event_with_client(scheduler, token).await {
+            warn!("Could not cancel deadline: {:?}: {:?}", token, e);
+        }
     }
 }
 
-async fn internal_handle_command<
+pub(crate) async fn internal_handle_command<
     P: VecU8Message + Send + Sync + Clone + std::fmt::Debug + 'static,
 >(
     command_handler: &Box<
         dyn SubscriptionHandle<Arc<async_lock::Mutex<AggregateContext<P>>>, SerializedObject>,
     >,
-    data: Vec<u8>,
+    command: &Command,
+    aggregate_id: Option<&str>,
     aggregate_definition: Arc<AggregateDefinition<P>>,
-    event_store_client: &mut EventStoreClient<Channel>,
-) -> Result<(
-    Option<SerializedObject>,
-    Vec<(String, Box<dyn ApplicableTo<P>>)>,
-    Option<String>,
-    i64,
-)> {
+    backend: AggregateBackend,
+    upcasters: &UpcasterRegistry,
+) -> Result<HandledCommand<P>> {
+    let data = command
+        .payload
+        .clone()
+        .map(|p| p.data)
+        .ok_or(anyhow!("No payload data for: {:?}", command.name))?;
     let empty_projection: P = (aggregate_definition.empty_projection.factory)();
     let aggregate_context = Arc::new(async_lock::Mutex::new(AggregateContext {
         aggregate_definition,
-        event_store_client: event_store_client.clone(),
+        backend,
         events: Vec::new(),
         aggregate_id: None,
         projection: empty_projection,
         seq: -1,
+        upcasters: upcasters.clone(),
+        meta_data: command.meta_data.clone(),
+        scheduled_deadlines: Vec::new(),
+        cancelled_deadlines: Vec::new(),
     }));
+    if let Some(aggregate_id) = aggregate_id {
+        aggregate_context
+            .lock()
+            .await
+            .get_projection(aggregate_id)
+            .await?;
+    }
     let result = command_handler
         .handle(data, aggregate_context.clone())
-        .await?;
+        .await;
     let aggregate_context = &mut aggregate_context.deref().lock().await;
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            aggregate_context.cancel_scheduled_deadlines().await;
+            return Err(e);
+        }
+    };
     let last_stored_seq = aggregate_context.seq;
     let aggregate_id = aggregate_context.aggregate_id.as_ref().map(|id| id.clone());
     if let Some(ref aggregate_id) = aggregate_id {
         let mut next_seq: i64 = last_stored_seq + 1;
-        for pair in clone_events(&mut aggregate_context.events) {
+        for triple in clone_events(&mut aggregate_context.events) {
             let aggregate_name = aggregate_context
                 .aggregate_definition
                 .projection_name
                 .clone();
-            let event = encode_event(&pair, &aggregate_name, aggregate_id, next_seq)?;
+            let event = encode_event(&triple, &aggregate_name, aggregate_id, next_seq)?;
             debug!("Replaying new event: {:?}", Debuggable::from(&event));
             if let Some(payload) = event.payload {
                 let sourcing_handler = aggregate_context
@@ -436,28 +814,59 @@ async fn internal_handle_command<
         }
     }
     let mut cloned_events = Vec::new();
-    for (event_type, event) in &aggregate_context.events {
-        let cloned_pair = (event_type.clone(), event.box_clone());
-        cloned_events.push(cloned_pair);
+    for (event_type, revision, event) in &aggregate_context.events {
+        let cloned_triple = (event_type.clone(), revision.clone(), event.box_clone());
+        cloned_events.push(cloned_triple);
     }
-    Ok((
+    Ok(HandledCommand {
         result,
-        cloned_events,
-        aggregate_context.aggregate_id.clone(),
-        last_stored_seq,
-    ))
+        events: cloned_events,
+        aggregate_id: aggregate_context.aggregate_id.clone(),
+        seq: last_stored_seq,
+        projection: aggregate_context.projection.clone(),
+        scheduled_deadlines: aggregate_context.scheduled_deadlines.clone(),
+        cancelled_deadlines: aggregate_context.cancelled_deadlines.clone(),
+    })
 }
 
 fn clone_events<P>(
-    events: &mut Vec<(String, Box<dyn ApplicableTo<P>>)>,
-) -> Vec<(String, Box<dyn ApplicableTo<P>>)> {
+    events: &mut Vec<(String, String, Box<dyn ApplicableTo<P>>)>,
+) -> Vec<(String, String, Box<dyn ApplicableTo<P>>)> {
     let mut cloned_events = Vec::new();
-    for (event_type, event) in events {
-        cloned_events.push((event_type.clone(), event.box_clone()));
+    for (event_type, revision, event) in events {
+        cloned_events.push((event_type.clone(), revision.clone(), event.box_clone()));
     }
     cloned_events
 }
 
+/// Finds the command handler or deadline handler for a command. For deadlines, it also returns the identifier
+/// of the aggregate that scheduled the deadline.
+#[allow(clippy::type_complexity)]
+pub(crate) fn get_handler<'a, P: VecU8Message + Send + Sync + Clone + std::fmt::Debug + 'static>(
+    command: &Command,
+    aggregate_definition: &'a Arc<AggregateDefinition<P>>,
+) -> Result<(
+    Option<
+        &'a Box<
+            dyn SubscriptionHandle<Arc<async_lock::Mutex<AggregateContext<P>>>, SerializedObject>,
+        >,
+    >,
+    Option<String>,
+)> {
+    match deadline_name(&aggregate_definition.projection_name, &command.name) {
+        Some(deadline_name) => Ok((
+            aggregate_definition
+                .deadline_handler_registry
+                .get(deadline_name),
+            Some(deadline_aggregate_id(command)?),
+        )),
+        None => Ok((
+            get_command_handler(command.name.clone(), aggregate_definition)?,
+            None,
+        )),
+    }
+}
+
 fn get_command_handler<P: VecU8Message + Send + Sync + Clone + std::fmt::Debug + 'static>(
     command_name: String,
     aggregate_definition: &Arc<AggregateDefinition<P>>,
@@ -478,7 +887,21 @@ pub fn emit<P: VecU8Message + Send + Clone>(
     type_name: &str,
     event: Box<dyn ApplicableTo<P>>,
 ) -> Result<()> {
-    holder.events.push((type_name.to_string(), event));
+    emit_with_revision(holder, type_name, "", event)
+}
+
+/// Adds an event with a declared revision of its payload to the result of a command handler.
+///
+/// Events that were stored with an older revision can be converted with an `UpcasterRegistry`.
+pub fn emit_with_revision<P: VecU8Message + Send + Clone>(
+    holder: &mut EmitApplicableEventsAndResponse<P>,
+    type_name: &str,
+    revision: &str,
+    event: Box<dyn ApplicableTo<P>>,
+) -> Result<()> {
+    holder
+        .events
+        .push((type_name.to_string(), revision.to_string(), event));
     Ok(())
 }
 
@@ -488,59 +911,175 @@ struct AxonCommandResult {
     result: Result<Option<EmitEventsAndResponse>>,
 }
 
+/// Settings for a command worker.
+///
+/// Fields:
+/// * `load_factor`: AxonServer distributes routing keys over the workers that handle a command type in proportion to their load factor.
+/// * `concurrency`: The maximum number of commands that are handled at the same time. Commands with the same routing key
+///   (usually the aggregate id) are always handled one after the other, in the order in which they arrived, and a command
+///   that waits for its turn does not count. Commands without a routing key are handled independently of each other.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandWorkerSettings {
+    pub load_factor: i32,
+    pub concurrency: usize,
+}
+
+impl Default for CommandWorkerSettings {
+    fn default() -> Self {
+        CommandWorkerSettings {
+            load_factor: 100,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Creates the default settings for a command worker.
+pub fn command_worker_settings() -> CommandWorkerSettings {
+    CommandWorkerSettings::default()
+}
+
+impl CommandWorkerSettings {
+    /// Sets the load factor with which the command worker subscribes to commands.
+    pub fn with_load_factor(mut self, load_factor: i32) -> Self {
+        self.load_factor = load_factor;
+        self
+    }
+
+    /// Sets the maximum number of commands that are handled at the same time.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+/// Keeps commands with the same routing key in order.
+///
+/// For each routing key, it remembers a receiver that fires when the last command with that key is done.
+/// The next command with the same key waits for it.
+#[derive(Default)]
+struct CommandLanes {
+    last: HashMap<String, oneshot::Receiver<()>>,
+}
+
+impl CommandLanes {
+    fn enter(
+        &mut self,
+        routing_key: String,
+        capacity: usize,
+    ) -> (Option<oneshot::Receiver<()>>, oneshot::Sender<()>) {
+        if self.last.len() > capacity {
+            self.last.retain(|_, done| {
+                matches!(done.try_recv(), Err(oneshot::error::TryRecvError::Empty))
+            });
+        }
+        let (done_sender, done_receiver) = oneshot::channel();
+        let previous = self.last.insert(routing_key, done_receiver);
+        (previous, done_sender)
+    }
+}
+
 /// Subscribes  to commands, verifies them against the command projection and sends emitted events to AxonServer.
 pub async fn command_worker(
     axon_server_handle: AxonServerHandle,
     aggregate_registry: &mut TheAggregateRegistry,
 ) -> Result<()> {
-    debug!("Command worker: start");
+    command_worker_with_settings(
+        axon_server_handle,
+        aggregate_registry,
+        CommandWorkerSettings::default(),
+    )
+    .await
+}
+
+/// Like `command_worker`, but with the given settings for load factor and concurrency.
+pub async fn command_worker_with_settings(
+    axon_server_handle: AxonServerHandle,
+    aggregate_registry: &mut TheAggregateRegistry,
+    settings: CommandWorkerSettings,
+) -> Result<()> {
+    debug!("Command worker: start: {:?}", settings);
 
-    let mut client = CommandServiceClient::new(axon_server_handle.conn.clone());
-    let mut event_store_client = EventStoreClient::new(axon_server_handle.conn.clone());
+    let mut client = axon_server_handle.command_service_client();
+    let event_store_client = axon_server_handle.event_store_client();
+    let event_scheduler_client = axon_server_handle.event_scheduler_client();
 
     let mut command_to_aggregate_mapping = HashMap::new();
     let mut command_vec: Vec<String> = vec![];
     aggregate_registry.register_commands(&mut command_vec, &mut command_to_aggregate_mapping);
     let command_box = Box::new(command_vec);
 
-    let (tx, rx): (Sender<AxonCommandResult>, Receiver<AxonCommandResult>) = channel(10);
+    let concurrency = settings.concurrency.max(1);
+    let (tx, rx): (Sender<AxonCommandResult>, Receiver<AxonCommandResult>) =
+        channel(10 + concurrency);
 
-    let outbound = create_output_stream(axon_server_handle, command_box, rx);
+    let upcasters = axon_server_handle.upcasters.clone();
+    let outbound = create_output_stream(axon_server_handle, command_box, settings, rx);
 
     debug!("Command worker: calling open_stream");
     let response = client.open_stream(Request::new(outbound)).await?;
     debug!("Stream response: {:?}", response);
 
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut lanes = CommandLanes::default();
     let mut inbound = response.into_inner();
     loop {
         match inbound.message().await {
             Ok(Some(inbound)) => {
                 debug!("Inbound message: {:?}", Debuggable::from(&inbound));
                 if let Some(command_provider_inbound::Request::Command(command)) = inbound.request {
-                    let command_name = command.name.clone();
-                    let mut result = Err(anyhow!("Could not find aggregate handler"));
-                    if let Some(aggregate_name) = command_to_aggregate_mapping.get(&command_name) {
-                        if let Some(aggregate_definition) = aggregate_registry.get(aggregate_name) {
-                            result = aggregate_definition
-                                .handle(&command, &mut event_store_client)
-                                .await
+                    let aggregate_handle = command_to_aggregate_mapping
+                        .get(&command.name)
+                        .and_then(|aggregate_name| aggregate_registry.get(aggregate_name));
+                    let routing_key = get_routing_key(&command, aggregate_handle.as_ref());
+                    debug!("Command routing key: {:?}: {:?}", command.name, routing_key);
+                    let (previous, done) = lanes.enter(routing_key, concurrency * 2);
+                    let semaphore = semaphore.clone();
+                    let mut event_store_client = event_store_client.clone();
+                    let mut event_scheduler_client = event_scheduler_client.clone();
+                    let upcasters = upcasters.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        if let Some(previous) = previous {
+                            previous.await.ok();
                         }
-                    }
+                        let permit = semaphore.acquire_owned().await;
+                        let result = match aggregate_handle {
+                            Some(aggregate_handle) => {
+                                aggregate_handle
+                                    .handle(
+                                        &command,
+                                        &mut event_store_client,
+                                        &mut event_scheduler_client,
+                                        &upcasters,
+                                    )
+                                    .await
+                            }
+                            None => Err(AxonError::missing_handler(format!(
+                                "Could not find aggregate handler: {:?}",
+                                command.name
+                            ))
+                            .into()),
+                        };
 
-                    match result.as_ref() {
-                        Err(e) => warn!("Error while handling command: {:?}", e),
-                        Ok(result) => debug!("Result from command handler: {:?}", result),
-                    }
+                        match result.as_ref() {
+                            Err(e) => warn!("Error while handling command: {:?}", e),
+                            Ok(result) => debug!("Result from command handler: {:?}", result),
+                        }
 
-                    let axon_command_result = AxonCommandResult {
-                        message_identifier: command.message_identifier,
-                        result,
-                    };
-                    tx.send(axon_command_result).await.unwrap();
+                        let axon_command_result = AxonCommandResult {
+                            message_identifier: command.message_identifier,
+                            result,
+                        };
+                        done.send(()).ok();
+                        drop(permit);
+                        if tx.send(axon_command_result).await.is_err() {
+                            debug!("Command worker: stream closed before result could be sent");
+                        }
+                    });
                 }
             }
             Ok(None) => {
-                debug!("None incoming");
+                return Err(anyhow!("Command stream closed by AxonServer"));
             }
             Err(e) => {
                 error!("Error from AxonServer: {:?}", e);
@@ -550,9 +1089,41 @@ pub async fn command_worker(
     }
 }
 
+/// Takes the routing key from the processing instructions of the command, or else extracts it with the
+/// routing key extractor of the aggregate. Commands without a routing key get their message identifier
+/// as key, so that each of them has a lane of its own.
+fn get_routing_key(
+    command: &Command,
+    aggregate_handle: Option<&Arc<dyn AggregateHandle>>,
+) -> String {
+    for instruction in &command.processing_instructions {
+        if instruction.key == ProcessingKey::RoutingKey as i32 {
+            if let Some(MetaDataValue {
+                data: Some(Data::TextValue(routing_key)),
+            }) = instruction.value.as_ref()
+            {
+                return routing_key.clone();
+            }
+        }
+    }
+    aggregate_handle
+        .and_then(|aggregate_handle| {
+            let data = command.payload.as_ref().map(|p| p.data.as_slice())?;
+            aggregate_handle
+                .routing_keys()
+                .routing_key(&command.name, data)
+                .unwrap_or_else(|e| {
+                    warn!("Could not extract routing key: {:?}: {:?}", command.name, e);
+                    None
+                })
+        })
+        .unwrap_or_else(|| command.message_identifier.clone())
+}
+
 fn create_output_stream(
     axon_server_handle: AxonServerHandle,
     command_box: Box<Vec<String>>,
+    settings: CommandWorkerSettings,
     mut rx: Receiver<AxonCommandResult>,
 ) -> impl Stream<Item = CommandProviderOutbound> {
     stream! {
@@ -567,7 +1138,7 @@ fn create_output_stream(
                 command: command_name.to_string().clone(),
                 client_id: client_id.clone(),
                 component_name: component_name.clone(),
-                load_factor: 100,
+                load_factor: settings.load_factor,
             };
             debug!("Subscribe command: Subscription: {:?}", subscription);
             let instruction_id = Uuid::new_v4();
@@ -579,7 +1150,7 @@ fn create_output_stream(
             yield instruction.to_owned();
         }
 
-        let permits_batch_size: i64 = 3;
+        let permits_batch_size: i64 = settings.concurrency.max(3) as i64;
         let mut permits = permits_batch_size * 2;
         debug!("Command worker: stream: send initial flow-control permits: amount: {:?}", permits);
         let flow_control = FlowControl {
@@ -610,13 +1181,9 @@ fn create_output_stream(
                     response.payload = result.map(|r| r.response).flatten();
                 }
                 Err(e) => {
-                    response.error_code = "ERROR".to_string();
-                    response.error_message = Some(ErrorMessage {
-                        message: e.to_string(),
-                        location: "".to_string(),
-                        details: Vec::new(),
-                        error_code: "ERROR".to_string(),
-                    });
+                    let error_message = AxonError::from(&e).to_error_message(MessageKind::Command, &component_name);
+                    response.error_code = error_message.error_code.clone();
+                    response.error_message = Some(error_message);
                 }
             }
             let instruction_id = Uuid::new_v4();
@@ -651,7 +1218,8 @@ async fn store_events<P: std::fmt::Debug>(
     client: &mut EventStoreClient<Channel>,
     aggregate_name: &str,
     aggregate_id: &str,
-    events: &Vec<(String, Box<dyn ApplicableTo<P>>)>,
+    events: &Vec<(String, String, Box<dyn ApplicableTo<P>>)>,
+    meta_data: &MetaData,
     next_seq: i64,
 ) -> Result<()> {
     debug!("Store events: Client: {:?}: events: {:?}", client, events);
@@ -660,8 +1228,16 @@ async fn store_events<P: std::fmt::Debug>(
     let timestamp = now.duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
     let event_messages: Vec<Event> = events
         .iter()
-        .map(move |e| {
-            encode_event_with_timestamp(e, aggregate_name, aggregate_id, timestamp, next_seq)
+        .enumerate()
+        .map(move |(i, e)| {
+            encode_event_with_timestamp(
+                e,
+                aggregate_name,
+                aggregate_id,
+                meta_data,
+                timestamp,
+                next_seq + i as i64,
+            )
         })
         .collect();
     let request = Request::new(futures_util::stream::iter(event_messages));
@@ -669,8 +1245,24 @@ async fn store_events<P: std::fmt::Debug>(
     Ok(())
 }
 
+/// AxonServer rejects an append with error code AXONIQ-2000 when the sequence number of an event
+/// was already taken by another event of the same aggregate.
+fn is_sequence_conflict(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<Status>() {
+        Some(status) => {
+            status
+                .metadata()
+                .get("axoniq-errorcode")
+                .and_then(|code| code.to_str().ok())
+                == Some("AXONIQ-2000")
+                || status.message().contains("Invalid sequence number")
+        }
+        None => false,
+    }
+}
+
 fn encode_event<P>(
-    e: &(String, Box<dyn ApplicableTo<P>>),
+    e: &(String, String, Box<dyn ApplicableTo<P>>),
     aggregate_name: &str,
     aggregate_id: &str,
     next_seq: i64,
@@ -681,24 +1273,26 @@ fn encode_event<P>(
         e,
         aggregate_name,
         aggregate_id,
+        &MetaData::new(),
         timestamp,
         next_seq,
     ))
 }
 
 fn encode_event_with_timestamp<P>(
-    e: &(String, Box<dyn ApplicableTo<P>>),
+    e: &(String, String, Box<dyn ApplicableTo<P>>),
     aggregate_name: &str,
     aggregate_id: &str,
+    meta_data: &MetaData,
     timestamp: i64,
     next_seq: i64,
 ) -> Event {
-    let (type_name, event) = e;
+    let (type_name, revision, event) = e;
     let mut buf = Vec::new();
     event.encode_u8(&mut buf).unwrap();
     let e = SerializedObject {
         r#type: type_name.to_string(),
-        revision: "".to_string(),
+        revision: revision.to_string(),
         data: buf,
     };
     let message_identifier = Uuid::new_v4();
@@ -709,7 +1303,7 @@ fn encode_event_with_timestamp<P>(
         aggregate_sequence_number: next_seq,
         aggregate_type: aggregate_name.to_string(),
         payload: Some(e),
-        meta_data: HashMap::new(),
+        meta_data: meta_data.clone(),
         snapshot: false,
     }
 }
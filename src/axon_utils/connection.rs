@@ -1,40 +1,322 @@
-use super::AxonServerHandle;
-use crate::axon_server::control::platform_inbound_instruction;
+use super::event_processor::{
+    EventProcessorInstruction, EventProcessorRegistry, ProcessorInstruction,
+};
+use super::{
+    AxonServerHandle, DispatchOptions, QueryUpdateEmitter, ReconnectSignal, RoutingKeyRegistry,
+    UpcasterRegistry,
+};
+use crate::axon_server::command::command_service_client::CommandServiceClient;
+use crate::axon_server::common::{InstructionAck, InstructionResult};
 use crate::axon_server::control::platform_service_client::PlatformServiceClient;
-use crate::axon_server::control::{ClientIdentification, PlatformInboundInstruction};
+use crate::axon_server::control::{platform_inbound_instruction, platform_outbound_instruction};
+use crate::axon_server::control::{
+    ClientIdentification, Heartbeat, PlatformInboundInstruction, PlatformOutboundInstruction,
+};
+use crate::axon_server::event::event_scheduler_client::EventSchedulerClient;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+use crate::axon_server::query::query_service_client::QueryServiceClient;
+use crate::axon_server::ErrorMessage;
 use crate::intellij_work_around::Debuggable;
 use anyhow::{anyhow, Result};
 use async_stream::stream;
 use futures_core::stream::Stream;
-use log::{debug, error};
+use log::{debug, error, warn};
 use std::time;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio::time::sleep;
 use tonic;
-use tonic::transport::Channel;
-use tonic::{Request, Response};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Interceptor, Request, Response};
 use uuid::Uuid;
 
+/// Interval between reports of the status of the event processors to AxonServer.
+const EVENT_PROCESSOR_INFO_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The settings that are used to connect (and reconnect) to AxonServer.
+///
+/// Fields:
+/// * `servers`: The host names and gRPC ports of the AxonServer nodes, in the order in which they are tried.
+/// * `tls`: The TLS configuration. When present, connections use `https`.
+/// * `access_token`: Sent as `AxonIQ-Access-Token` with each request.
+/// * `context`: Sent as `AxonIQ-Context` with each request.
+/// * `connect_timeout`: Maximum duration of an attempt to connect to a node.
+/// * `keep_alive_interval`: Interval of HTTP/2 keep-alive pings.
+/// * `keep_alive_timeout`: Time to wait for the response to a keep-alive ping.
+/// * `heartbeat_interval`: Interval of heartbeats on the platform stream. No heartbeats are sent when absent.
+/// * `heartbeat_timeout`: Time to wait, after a heartbeat was due, for any message from AxonServer
+///   before the connection is considered to be dead.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionSettings {
+    pub servers: Vec<(String, u32)>,
+    pub tls: Option<ClientTlsConfig>,
+    pub access_token: Option<String>,
+    pub context: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub keep_alive_interval: Option<Duration>,
+    pub keep_alive_timeout: Option<Duration>,
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_timeout: Option<Duration>,
+}
+
+impl ConnectionSettings {
+    fn url(&self, host: &str, port: u32) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, host, port)
+    }
+
+    /// Creates an interceptor that adds the access token and the context to each request.
+    ///
+    /// An access token or context that is not a valid metadata value is left out.
+    pub fn interceptor(&self) -> Interceptor {
+        let access_token = metadata_value(self.access_token.as_deref(), "access token");
+        let context = metadata_value(self.context.as_deref(), "context name");
+        Interceptor::new(move |mut request: Request<()>| {
+            if let Some(access_token) = access_token.as_ref() {
+                request
+                    .metadata_mut()
+                    .insert("axoniq-access-token", access_token.clone());
+            }
+            if let Some(context) = context.as_ref() {
+                request
+                    .metadata_mut()
+                    .insert("axoniq-context", context.clone());
+            }
+            Ok(request)
+        })
+    }
+
+    /// Returns an error if the access token or the context cannot be sent as metadata.
+    fn check(&self) -> Result<()> {
+        if let Some(access_token) = self.access_token.as_deref() {
+            MetadataValue::from_str(access_token).map_err(|_| anyhow!("Invalid access token"))?;
+        }
+        if let Some(context) = self.context.as_deref() {
+            MetadataValue::from_str(context)
+                .map_err(|_| anyhow!("Invalid context name: {:?}", context))?;
+        }
+        Ok(())
+    }
+}
+
+fn metadata_value(value: Option<&str>, description: &str) -> Option<MetadataValue<Ascii>> {
+    let value = value?;
+    match MetadataValue::from_str(value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Invalid {}: left out of requests", description);
+            None
+        }
+    }
+}
+
+/// Builds an `AxonServerHandle` with custom connection settings.
+#[derive(Debug, Clone)]
+pub struct AxonServerHandleBuilder {
+    label: String,
+    settings: ConnectionSettings,
+    upcasters: UpcasterRegistry,
+    routing_keys: RoutingKeyRegistry,
+    dispatch_options: DispatchOptions,
+}
+
+/// Creates a builder for an `AxonServerHandle`. Without further settings, it connects to `localhost:8124`.
+pub fn axon_server_handle_builder(label: &str) -> AxonServerHandleBuilder {
+    AxonServerHandleBuilder {
+        label: label.to_string(),
+        settings: ConnectionSettings::default(),
+        upcasters: UpcasterRegistry::default(),
+        routing_keys: RoutingKeyRegistry::default(),
+        dispatch_options: DispatchOptions::default(),
+    }
+}
+
+impl AxonServerHandleBuilder {
+    /// Adds an AxonServer node. Nodes are tried in the order in which they were added.
+    pub fn with_server(mut self, host: &str, port: u32) -> Self {
+        self.settings.servers.push((host.to_string(), port));
+        self
+    }
+
+    /// Connects with TLS using the given configuration.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.settings.tls = Some(tls);
+        self
+    }
+
+    /// Connects with TLS and verifies the server against the given PEM encoded CA certificate.
+    pub fn with_ca_certificate(mut self, pem: impl AsRef<[u8]>) -> Self {
+        let tls = self.settings.tls.take().unwrap_or_default();
+        self.settings.tls = Some(tls.ca_certificate(Certificate::from_pem(pem)));
+        self
+    }
+
+    /// Connects with TLS and authenticates with the given PEM encoded client certificate and key.
+    pub fn with_client_identity(mut self, cert: impl AsRef<[u8]>, key: impl AsRef<[u8]>) -> Self {
+        let tls = self.settings.tls.take().unwrap_or_default();
+        self.settings.tls = Some(tls.identity(Identity::from_pem(cert, key)));
+        self
+    }
+
+    /// Sends the given access token with each request.
+    pub fn with_access_token(mut self, access_token: &str) -> Self {
+        self.settings.access_token = Some(access_token.to_string());
+        self
+    }
+
+    /// Uses the given context of AxonServer instead of the default context.
+    pub fn with_context(mut self, context: &str) -> Self {
+        self.settings.context = Some(context.to_string());
+        self
+    }
+
+    /// Gives up an attempt to connect to a node after the given duration.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.settings.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Sends HTTP/2 keep-alive pings every `interval` and drops the connection when a ping is not
+    /// answered within `timeout`.
+    pub fn with_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.settings.keep_alive_interval = Some(interval);
+        self.settings.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// Lets the platform worker send heartbeats every `interval`. When nothing is received from AxonServer
+    /// within `interval` plus `timeout`, the connection is marked unhealthy and supervised workers reconnect.
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.settings.heartbeat_interval = Some(interval);
+        self.settings.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Upcasts the payloads of events that are read from the event store with the given registry.
+    pub fn with_upcasters(mut self, upcasters: UpcasterRegistry) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// Attaches a routing key to commands that are sent with the resulting handle, using the given registry.
+    pub fn with_routing_keys(mut self, routing_keys: RoutingKeyRegistry) -> Self {
+        self.routing_keys = routing_keys;
+        self
+    }
+
+    /// Uses the given options for commands and queries that are sent with the resulting handle, unless
+    /// they are overridden for a particular message.
+    pub fn with_dispatch_options(mut self, dispatch_options: DispatchOptions) -> Self {
+        self.dispatch_options = dispatch_options;
+        self
+    }
+
+    /// Polls AxonServer until it is available and ready and returns a handle for it.
+    pub async fn connect(mut self) -> Result<AxonServerHandle> {
+        self.settings.check()?;
+        if self.settings.servers.is_empty() {
+            self.settings.servers.push(("localhost".to_string(), 8124));
+        }
+        let conn = wait_for_connection(&self.settings, &self.label).await;
+        let client_id = format!("{}", Uuid::new_v4());
+        debug!(
+            "Axon server handle: {:?}: {:?}: {:?}",
+            self.label, client_id, conn
+        );
+        let connection = AxonServerHandle {
+            display_name: self.label,
+            client_id,
+            connection_settings: self.settings,
+            conn,
+            event_processors: EventProcessorRegistry::default(),
+            query_update_emitter: QueryUpdateEmitter::default(),
+            reconnect_signal: ReconnectSignal::default(),
+            upcasters: self.upcasters,
+            routing_keys: self.routing_keys,
+            dispatch_options: self.dispatch_options,
+        };
+        Ok(connection)
+    }
+}
+
+impl AxonServerHandle {
+    /// Creates an interceptor that adds the access token and the context of this handle to each request.
+    pub fn interceptor(&self) -> Interceptor {
+        self.connection_settings.interceptor()
+    }
+
+    /// Creates a client for the command service that uses the channel and metadata of this handle.
+    pub fn command_service_client(&self) -> CommandServiceClient<Channel> {
+        CommandServiceClient::with_interceptor(self.conn.clone(), self.interceptor())
+    }
+
+    /// Creates a client for the event store that uses the channel and metadata of this handle.
+    pub fn event_store_client(&self) -> EventStoreClient<Channel> {
+        EventStoreClient::with_interceptor(self.conn.clone(), self.interceptor())
+    }
+
+    /// Creates a client for the event scheduler that uses the channel and metadata of this handle.
+    pub fn event_scheduler_client(&self) -> EventSchedulerClient<Channel> {
+        EventSchedulerClient::with_interceptor(self.conn.clone(), self.interceptor())
+    }
+
+    /// Creates a client for the platform service that uses the channel and metadata of this handle.
+    pub fn platform_service_client(&self) -> PlatformServiceClient<Channel> {
+        PlatformServiceClient::with_interceptor(self.conn.clone(), self.interceptor())
+    }
+
+    /// Creates a client for the query service that uses the channel and metadata of this handle.
+    pub fn query_service_client(&self) -> QueryServiceClient<Channel> {
+        QueryServiceClient::with_interceptor(self.conn.clone(), self.interceptor())
+    }
+}
+
 /// Polls AxonServer until it is available and ready.
 pub async fn wait_for_server(host: &str, port: u32, label: &str) -> Result<AxonServerHandle> {
-    let url = format!("http://{}:{}", host, port);
-    let conn = wait_for_connection(&url, label).await;
-    let client_id = format!("{}", Uuid::new_v4());
+    wait_for_cluster(&[(host, port)], label).await
+}
+
+/// Polls the nodes of an AxonServer cluster until one of them is available and ready.
+///
+/// The nodes in `servers` are tried in order, failing over to the next node when one is unreachable.
+/// When the node that answers reports another node as the primary node for this client, the
+/// connection is redirected to that node.
+pub async fn wait_for_cluster(servers: &[(&str, u32)], label: &str) -> Result<AxonServerHandle> {
+    if servers.is_empty() {
+        return Err(anyhow!("No AxonServer nodes given"));
+    }
+    let mut builder = axon_server_handle_builder(label);
+    for (host, port) in servers {
+        builder = builder.with_server(host, *port);
+    }
+    builder.connect().await
+}
+
+/// Tries once to establish a new channel to one of the AxonServer nodes of the given handle.
+///
+/// The new handle shares the client id and all other state with the given handle.
+pub(crate) async fn reconnect(axon_server_handle: &AxonServerHandle) -> Option<AxonServerHandle> {
+    let conn = try_to_connect_to_any(
+        &axon_server_handle.connection_settings,
+        &axon_server_handle.display_name,
+    )
+    .await?;
     debug!(
-        "Axon server handle: {:?}: {:?}: {:?}",
-        label, client_id, conn
+        "Reconnected to AxonServer: {:?}: {:?}",
+        axon_server_handle.display_name, axon_server_handle.client_id
     );
-    let connection = AxonServerHandle {
-        display_name: label.to_string(),
-        client_id,
+    Some(AxonServerHandle {
         conn,
-    };
-    Ok(connection)
+        ..axon_server_handle.clone()
+    })
 }
 
-async fn wait_for_connection(url: &str, label: &str) -> Channel {
+async fn wait_for_connection(settings: &ConnectionSettings, label: &str) -> Channel {
     let interval = time::Duration::from_secs(1);
     loop {
-        if let Some(conn) = try_to_connect(url, label).await {
+        if let Some(conn) = try_to_connect_to_any(settings, label).await {
             return conn;
         }
         sleep(interval).await;
@@ -42,8 +324,23 @@ async fn wait_for_connection(url: &str, label: &str) -> Channel {
     }
 }
 
-async fn try_to_connect(url: &str, label: &str) -> Option<Channel> {
-    connect(url, label)
+async fn try_to_connect_to_any(settings: &ConnectionSettings, label: &str) -> Option<Channel> {
+    for (host, port) in &settings.servers {
+        if let Some(conn) = try_to_connect(settings, host, *port, label).await {
+            return Some(conn);
+        }
+        debug!(". AxonServer node is not available: {:?}: {:?}", host, port);
+    }
+    None
+}
+
+async fn try_to_connect(
+    settings: &ConnectionSettings,
+    host: &str,
+    port: u32,
+    label: &str,
+) -> Option<Channel> {
+    connect(settings, host, port, label)
         .await
         .map_err(|e| {
             debug!("Error while trying to connect to AxonServer: {:?}", e);
@@ -52,17 +349,17 @@ async fn try_to_connect(url: &str, label: &str) -> Option<Channel> {
         .flatten()
 }
 
-async fn connect(url: &str, label: &str) -> Result<Option<Channel>> {
-    let conn = tonic::transport::Endpoint::from_shared(url.to_string())?
-        .connect()
-        .await
-        .map_err(|_| debug!(". Can't connect to AxonServer (yet)"))
-        .ok();
-    let conn = match conn {
+async fn connect(
+    settings: &ConnectionSettings,
+    host: &str,
+    port: u32,
+    label: &str,
+) -> Result<Option<Channel>> {
+    let conn = match open_channel(settings, host, port).await? {
         Some(conn) => conn,
         None => return Ok(None),
     };
-    let mut client = PlatformServiceClient::new(conn.clone());
+    let mut client = PlatformServiceClient::with_interceptor(conn.clone(), settings.interceptor());
     let mut client_identification = ClientIdentification::default();
     client_identification.component_name = format!("Rust client {}", &*label);
     let response = client
@@ -70,38 +367,146 @@ async fn connect(url: &str, label: &str) -> Result<Option<Channel>> {
         .await
         .map_err(|_| debug!(". AxonServer is not available (yet)"))
         .ok();
-    if response.is_none() {
-        return Ok(None);
+    let platform_info = match response.map(Response::into_inner) {
+        Some(platform_info) => platform_info,
+        None => return Ok(None),
+    };
+    debug!("Response: {:?}", Debuggable::from(&platform_info));
+    if platform_info.same_connection {
+        return Ok(Some(conn));
+    }
+    match platform_info.primary {
+        Some(primary) if !primary.host_name.is_empty() => {
+            let primary_port = primary.grpc_port as u32;
+            if primary.host_name == host && primary_port == port {
+                return Ok(Some(conn));
+            }
+            debug!(
+                "Redirect to primary AxonServer node: {:?}: {:?}: {:?}",
+                primary.node_name, primary.host_name, primary_port
+            );
+            open_channel(settings, &primary.host_name, primary_port).await
+        }
+        _ => Ok(Some(conn)),
+    }
+}
+
+async fn open_channel(
+    settings: &ConnectionSettings,
+    host: &str,
+    port: u32,
+) -> Result<Option<Channel>> {
+    let mut endpoint = Endpoint::from_shared(settings.url(host, port))?;
+    if let Some(tls) = settings.tls.as_ref() {
+        endpoint = endpoint.tls_config(tls.clone())?;
     }
-    let platform_info = response.map(Response::into_inner);
-    let platform_info = platform_info.as_ref();
-    debug!("Response: {:?}", platform_info.map(|p| Debuggable::from(p)));
-    return Ok(Some(conn));
+    if let Some(interval) = settings.keep_alive_interval {
+        endpoint = endpoint.http2_keep_alive_interval(interval);
+    }
+    if let Some(timeout) = settings.keep_alive_timeout {
+        endpoint = endpoint.keep_alive_timeout(timeout);
+    }
+    let conn = match settings.connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, endpoint.connect())
+            .await
+            .map_err(|_| debug!(". Timeout while connecting to AxonServer"))
+            .ok()
+            .and_then(|conn| {
+                conn.map_err(|_| debug!(". Can't connect to AxonServer (yet)"))
+                    .ok()
+            }),
+        None => endpoint
+            .connect()
+            .await
+            .map_err(|_| debug!(". Can't connect to AxonServer (yet)"))
+            .ok(),
+    };
+    Ok(conn)
 }
 
-/// Subscribes  to commands, verifies them against the command projection and sends emitted events to AxonServer.
+/// Keeps the control stream to AxonServer open and handles the instructions that AxonServer sends on it.
+///
+/// The platform worker answers heartbeats of AxonServer. When a heartbeat interval is configured, it also
+/// sends heartbeats to AxonServer and marks the connection unhealthy when AxonServer stops responding.
+/// Only a message with the instruction id of our heartbeat counts as its answer.
+/// When AxonServer requests a reconnect, the platform worker raises the reconnect signal and stops.
+///
+/// The platform worker periodically reports the status of the registered event processors to AxonServer
+/// and forwards instructions for event processors (pause, start, release, split, merge) to them.
 pub async fn platform_worker(axon_server_handle: AxonServerHandle, label: &str) -> Result<()> {
     debug!("Platform worker: start");
-    let conn = axon_server_handle.conn;
-
-    let mut client = PlatformServiceClient::new(conn.clone());
-    let mut client_identification = ClientIdentification::default();
-    client_identification.component_name = format!("Rust client {}", &*label);
-    let output = create_output_stream(label.to_string());
+    let mut client = axon_server_handle.platform_service_client();
+    let (tx, rx): (
+        Sender<PlatformInboundInstruction>,
+        Receiver<PlatformInboundInstruction>,
+    ) = channel(10);
+    let output = create_output_stream(label.to_string(), axon_server_handle.client_id.clone(), rx);
     let response = client.open_stream(Request::new(output)).await?;
     debug!("Stream response: {:?}", response);
 
     let mut inbound = response.into_inner();
+    let settings = &axon_server_handle.connection_settings;
+    let heartbeat_interval = settings.heartbeat_interval;
+    let heartbeat_deadline = heartbeat_interval
+        .map(|interval| interval + settings.heartbeat_timeout.unwrap_or(interval));
+    let mut heartbeat_ticker =
+        tokio::time::interval(heartbeat_interval.unwrap_or(Duration::from_secs(3600)));
+    let mut last_received = Instant::now();
+    let mut pending_heartbeat: Option<String> = None;
+    let mut event_processor_info_ticker = tokio::time::interval(EVENT_PROCESSOR_INFO_INTERVAL);
+    axon_server_handle.reconnect_signal.mark_healthy();
     loop {
-        match inbound.message().await {
+        let message = tokio::select! {
+            message = inbound.message() => message,
+            _ = event_processor_info_ticker.tick() => {
+                let event_processors = &axon_server_handle.event_processors;
+                for processor_name in event_processors.names() {
+                    send_event_processor_info(&tx, event_processors, &processor_name).await;
+                }
+                continue;
+            }
+            _ = heartbeat_ticker.tick(), if heartbeat_interval.is_some() => {
+                if matches!(heartbeat_deadline, Some(deadline) if last_received.elapsed() > deadline) {
+                    warn!("No heartbeat from AxonServer since: {:?}", last_received.elapsed());
+                    axon_server_handle.reconnect_signal.mark_unhealthy();
+                    return Err(anyhow!("Heartbeat timeout"));
+                }
+                pending_heartbeat = Some(send_heartbeat(&tx).await);
+                continue;
+            }
+        };
+        last_received = Instant::now();
+        match message {
             Ok(Some(message)) => {
                 debug!(
                     "Incoming (= 'outbound') platform instruction: {:?}",
                     Debuggable::from(&message)
                 );
+                match &message.request {
+                    Some(platform_outbound_instruction::Request::Ack(ack))
+                        if pending_heartbeat.as_ref() == Some(&ack.instruction_id) =>
+                    {
+                        pending_heartbeat = None;
+                        continue;
+                    }
+                    Some(platform_outbound_instruction::Request::Heartbeat(_)) => {
+                        if pending_heartbeat.as_ref() == Some(&message.instruction_id) {
+                            pending_heartbeat = None;
+                        } else if message.instruction_id.is_empty() {
+                            send_heartbeat(&tx).await;
+                        } else {
+                            send_ack(&tx, message.instruction_id).await;
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                if handle_platform_instruction(&axon_server_handle, message, &tx).await {
+                    return Err(anyhow!("AxonServer requested a reconnect"));
+                }
             }
             Ok(None) => {
-                debug!("None incoming");
+                return Err(anyhow!("Platform stream closed by AxonServer"));
             }
             Err(e) => {
                 error!("Error from AxonServer: {:?}", e);
@@ -111,10 +516,156 @@ pub async fn platform_worker(axon_server_handle: AxonServerHandle, label: &str)
     }
 }
 
-fn create_output_stream(label: String) -> impl Stream<Item = PlatformInboundInstruction> {
-    let interval = time::Duration::from_secs(300);
+/// Returns true if AxonServer requested a reconnect.
+async fn handle_platform_instruction(
+    axon_server_handle: &AxonServerHandle,
+    message: PlatformOutboundInstruction,
+    tx: &Sender<PlatformInboundInstruction>,
+) -> bool {
+    let instruction_id = message.instruction_id;
+    let (processor_name, instruction) = match message.request {
+        Some(platform_outbound_instruction::Request::RequestReconnect(_)) => {
+            if !instruction_id.is_empty() {
+                send_ack(tx, instruction_id).await;
+            }
+            axon_server_handle.reconnect_signal.request_reconnect();
+            return true;
+        }
+        Some(platform_outbound_instruction::Request::ReleaseSegment(reference)) => (
+            reference.processor_name,
+            ProcessorInstruction::Release(reference.segment_identifier),
+        ),
+        Some(platform_outbound_instruction::Request::SplitEventProcessorSegment(reference)) => (
+            reference.processor_name,
+            ProcessorInstruction::Split(reference.segment_identifier),
+        ),
+        Some(platform_outbound_instruction::Request::MergeEventProcessorSegment(reference)) => (
+            reference.processor_name,
+            ProcessorInstruction::Merge(reference.segment_identifier),
+        ),
+        Some(platform_outbound_instruction::Request::PauseEventProcessor(reference)) => {
+            (reference.processor_name, ProcessorInstruction::Pause)
+        }
+        Some(platform_outbound_instruction::Request::StartEventProcessor(reference)) => {
+            (reference.processor_name, ProcessorInstruction::Start)
+        }
+        Some(platform_outbound_instruction::Request::RequestEventProcessorInfo(reference)) => {
+            let event_processors = &axon_server_handle.event_processors;
+            send_event_processor_info(tx, event_processors, &reference.processor_name).await;
+            if !instruction_id.is_empty() {
+                send_ack(tx, instruction_id).await;
+            }
+            return false;
+        }
+        _ => {
+            if !instruction_id.is_empty() {
+                send_ack(tx, instruction_id).await;
+            }
+            return false;
+        }
+    };
+    let (result_tx, result_rx) = oneshot::channel();
+    let forwarded = match axon_server_handle.event_processors.get(&processor_name) {
+        Some(processor) => processor
+            .send(EventProcessorInstruction {
+                instruction,
+                result: result_tx,
+            })
+            .await
+            .is_ok(),
+        None => false,
+    };
+    if !forwarded {
+        warn!("Unknown event processor: {:?}", processor_name);
+    }
+    let tx = tx.clone();
+    let event_processors = axon_server_handle.event_processors.clone();
+    tokio::spawn(async move {
+        let result = result_rx
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Unknown event processor: {:?}", processor_name)));
+        if !instruction_id.is_empty() {
+            send_result(&tx, instruction_id, result).await;
+        }
+        send_event_processor_info(&tx, &event_processors, &processor_name).await;
+    });
+    false
+}
+
+async fn send_event_processor_info(
+    tx: &Sender<PlatformInboundInstruction>,
+    event_processors: &EventProcessorRegistry,
+    processor_name: &str,
+) {
+    if let Some(event_processor_info) = event_processors.event_processor_info(processor_name) {
+        let instruction = PlatformInboundInstruction {
+            instruction_id: "".to_string(),
+            request: Some(platform_inbound_instruction::Request::EventProcessorInfo(
+                event_processor_info,
+            )),
+        };
+        tx.send(instruction).await.ok();
+    }
+}
+
+/// Sends a heartbeat and returns its instruction id.
+async fn send_heartbeat(tx: &Sender<PlatformInboundInstruction>) -> String {
+    let instruction_id = format!("{}", Uuid::new_v4());
+    let instruction = PlatformInboundInstruction {
+        instruction_id: instruction_id.clone(),
+        request: Some(platform_inbound_instruction::Request::Heartbeat(
+            Heartbeat {},
+        )),
+    };
+    tx.send(instruction).await.ok();
+    instruction_id
+}
+
+async fn send_ack(tx: &Sender<PlatformInboundInstruction>, instruction_id: String) {
+    let ack = InstructionAck {
+        instruction_id,
+        success: true,
+        error: None,
+    };
+    let instruction = PlatformInboundInstruction {
+        instruction_id: "".to_string(),
+        request: Some(platform_inbound_instruction::Request::Ack(ack)),
+    };
+    tx.send(instruction).await.ok();
+}
+
+async fn send_result(
+    tx: &Sender<PlatformInboundInstruction>,
+    instruction_id: String,
+    result: Result<()>,
+) {
+    let instruction_result = InstructionResult {
+        instruction_id,
+        success: result.is_ok(),
+        error: result.err().map(|e| ErrorMessage {
+            message: e.to_string(),
+            location: "".to_string(),
+            details: Vec::new(),
+            error_code: "".to_string(),
+        }),
+    };
+    let instruction = PlatformInboundInstruction {
+        instruction_id: "".to_string(),
+        request: Some(platform_inbound_instruction::Request::Result(
+            instruction_result,
+        )),
+    };
+    tx.send(instruction).await.ok();
+}
+
+fn create_output_stream(
+    label: String,
+    client_id: String,
+    mut rx: Receiver<PlatformInboundInstruction>,
+) -> impl Stream<Item = PlatformInboundInstruction> {
     stream! {
         let mut client_identification = ClientIdentification::default();
+        client_identification.client_id = client_id;
         client_identification.component_name = format!("Rust client {}", &label);
         let instruction_id = Uuid::new_v4();
         let instruction = PlatformInboundInstruction {
@@ -123,9 +674,9 @@ fn create_output_stream(label: String) -> impl Stream<Item = PlatformInboundInst
         };
         yield instruction.to_owned();
 
-        loop {
-            sleep(interval).await;
-            debug!(".");
+        while let Some(instruction) = rx.recv().await {
+            debug!("Outgoing (= 'inbound') platform instruction: {:?}", Debuggable::from(&instruction));
+            yield instruction;
         }
     }
 }
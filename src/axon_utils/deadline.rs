@@ -0,0 +1,223 @@
+use super::error::AxonError;
+use super::event_processor::{event_processor, is_replaying, TokenStore};
+use super::event_scheduler::{schedule_serialized_event, ScheduleToken};
+use super::handler_registry::{empty_handler_registry, HandlerRegistry};
+use super::metadata::{get_text, text_value, MetaData};
+use super::routing::routing_key_instruction;
+use super::segment::Segment;
+use super::AxonServerHandle;
+use crate::axon_server::command::Command;
+use crate::axon_server::event::event_scheduler_client::EventSchedulerClient;
+use crate::axon_server::SerializedObject;
+use crate::intellij_work_around::Debuggable;
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use prost::Message;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+/// Type of the scheduled events that carry deadlines until they fire.
+///
+/// These events end up in the event store when they fire. Event processors skip them, unless they have a handler
+/// for this type, like the `deadline_dispatcher`.
+pub const DEADLINE_EVENT_TYPE: &str = "dendrite.Deadline";
+
+const DEADLINE_SEPARATOR: &str = ":deadline:";
+const DEADLINE_AGGREGATE_ID: &str = "deadline_aggregate_id";
+
+/// A deadline that was scheduled by a command handler.
+///
+/// Fields:
+/// * `token`: The token that can be used to cancel the deadline.
+/// * `deadline_name`: The name of the deadline handler that is invoked when the deadline fires.
+/// * `instant`: The moment when the deadline fires.
+/// * `payload`: The payload that is passed to the deadline handler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledDeadline {
+    pub token: ScheduleToken,
+    pub deadline_name: String,
+    pub instant: SystemTime,
+    pub payload: SerializedObject,
+}
+
+/// Returns the name of the command that triggers the deadline with the given name on aggregates of the given type.
+pub fn deadline_command_name(aggregate_name: &str, deadline_name: &str) -> String {
+    format!("{}{}{}", aggregate_name, DEADLINE_SEPARATOR, deadline_name)
+}
+
+/// Returns the name of the deadline if the command with the given name triggers a deadline on aggregates of
+/// the given type.
+pub(crate) fn deadline_name<'a>(aggregate_name: &str, command_name: &'a str) -> Option<&'a str> {
+    command_name
+        .strip_prefix(aggregate_name)
+        .and_then(|rest| rest.strip_prefix(DEADLINE_SEPARATOR))
+}
+
+/// Returns the identifier of the aggregate that a deadline command was scheduled for.
+pub(crate) fn deadline_aggregate_id(command: &Command) -> Result<String> {
+    get_text(&command.meta_data, DEADLINE_AGGREGATE_ID)
+        .map(|aggregate_id| aggregate_id.to_string())
+        .ok_or_else(|| anyhow!("Missing aggregate id for deadline: {:?}", command.name))
+}
+
+/// Creates the command that triggers a deadline on an aggregate.
+pub(crate) fn deadline_command(
+    aggregate_name: &str,
+    aggregate_id: &str,
+    deadline_name: &str,
+    payload: SerializedObject,
+    meta_data: &MetaData,
+    instant: SystemTime,
+) -> Result<Command> {
+    let mut meta_data = meta_data.clone();
+    meta_data.insert(DEADLINE_AGGREGATE_ID.to_string(), text_value(aggregate_id));
+    Ok(Command {
+        message_identifier: format!("{}", Uuid::new_v4()),
+        name: deadline_command_name(aggregate_name, deadline_name),
+        payload: Some(payload),
+        client_id: "".to_string(),
+        component_name: "".to_string(),
+        meta_data,
+        processing_instructions: vec![routing_key_instruction(aggregate_id)],
+        timestamp: instant.duration_since(UNIX_EPOCH)?.as_millis() as i64,
+    })
+}
+
+/// Schedules the command that triggers a deadline on an aggregate with the event scheduler of AxonServer.
+pub(crate) async fn schedule_deadline_command(
+    client: &mut EventSchedulerClient<Channel>,
+    aggregate_name: &str,
+    aggregate_id: &str,
+    deadline_name: &str,
+    payload: SerializedObject,
+    meta_data: &MetaData,
+    instant: SystemTime,
+) -> Result<ScheduleToken> {
+    let command = deadline_command(
+        aggregate_name,
+        aggregate_id,
+        deadline_name,
+        payload,
+        meta_data,
+        instant,
+    )?;
+    let meta_data = command.meta_data.clone();
+    let mut buf = Vec::new();
+    command.encode(&mut buf)?;
+    let event_payload = SerializedObject {
+        r#type: DEADLINE_EVENT_TYPE.to_string(),
+        revision: "".to_string(),
+        data: buf,
+    };
+    schedule_serialized_event(client, event_payload, meta_data, instant).await
+}
+
+/// Token store of the deadline dispatcher, together with the handle that it needs to send deadline commands.
+#[derive(Clone)]
+struct DeadlineDispatcher<Q> {
+    axon_server_handle: AxonServerHandle,
+    token_store: Q,
+}
+
+#[tonic::async_trait]
+impl<Q: TokenStore + Send + Sync> TokenStore for DeadlineDispatcher<Q> {
+    async fn store_token(&self, token: i64) {
+        self.token_store.store_token(token).await
+    }
+
+    async fn retrieve_token(&self) -> Result<i64> {
+        self.token_store.retrieve_token().await
+    }
+
+    async fn retrieve_segments(&self) -> Result<Vec<Segment>> {
+        self.token_store.retrieve_segments().await
+    }
+
+    async fn store_segment_token(&self, segment: &Segment, token: i64) {
+        self.token_store.store_segment_token(segment, token).await
+    }
+
+    async fn retrieve_segment_token(&self, segment: &Segment) -> Result<i64> {
+        self.token_store.retrieve_segment_token(segment).await
+    }
+
+    async fn claim_segment(&self, segment: &Segment, owner: &str) -> Result<bool> {
+        self.token_store.claim_segment(segment, owner).await
+    }
+
+    async fn release_segment(&self, segment: &Segment, owner: &str) -> Result<()> {
+        self.token_store.release_segment(segment, owner).await
+    }
+
+    async fn replace_segments(&self, old: &[Segment], new: &[(Segment, i64)]) -> Result<()> {
+        self.token_store.replace_segments(old, new).await
+    }
+
+    async fn reset(&self) -> Result<()> {
+        self.token_store.reset().await
+    }
+}
+
+/// Subscribes to deadlines when they fire and sends them as commands to the command workers of the aggregates
+/// that scheduled them.
+///
+/// Deadlines are scheduled by `AggregateContextTrait::schedule_deadline` and are handled by the deadline
+/// handlers of the `AggregateDefinition`. The token store remembers which deadlines were dispatched, so it has
+/// to be persistent. Deadlines are not dispatched again when the processor is replayed.
+///
+/// Each instance of the dispatcher claims the event stream in the token store before it dispatches deadlines.
+/// When several instances of the application run a dispatcher with the same `processor_name`, the token store
+/// has to be shared between them and has to implement `claim_segment` and `release_segment`; otherwise, only
+/// one instance may run the dispatcher, or each deadline fires once for each instance.
+///
+/// When a deadline cannot be delivered to a command worker, the dispatcher stops with an error, so that the
+/// deadline is dispatched again when it is restarted (e.g., by `supervise`). Deadline handlers that reject a
+/// deadline are not retried.
+pub async fn deadline_dispatcher<Q: TokenStore + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    processor_name: &str,
+    token_store: Q,
+) -> Result<()> {
+    let dispatcher = DeadlineDispatcher {
+        axon_server_handle: axon_server_handle.clone(),
+        token_store,
+    };
+    let mut registry = empty_handler_registry();
+    registry.insert(
+        DEADLINE_EVENT_TYPE,
+        &Command::decode,
+        &(|command, dispatcher| Box::pin(dispatch_deadline(command, dispatcher))),
+    )?;
+    event_processor(axon_server_handle, processor_name, dispatcher, registry).await
+}
+
+async fn dispatch_deadline<Q>(command: Command, dispatcher: DeadlineDispatcher<Q>) -> Result<()> {
+    if is_replaying() {
+        debug!("Skip deadline during replay: {:?}", command.name);
+        return Ok(());
+    }
+    debug!("Dispatch deadline: {:?}", Debuggable::from(&command));
+    let axon_server_handle = &dispatcher.axon_server_handle;
+    let command = Command {
+        message_identifier: format!("{}", Uuid::new_v4()),
+        client_id: axon_server_handle.client_id.clone(),
+        component_name: axon_server_handle.display_name.clone(),
+        ..command
+    };
+    let name = command.name.clone();
+    let mut client = axon_server_handle.command_service_client();
+    let response = client.dispatch(command).await.map_err(AxonError::from)?;
+    if let Some(error_message) = response.into_inner().error_message {
+        match AxonError::from_error_message(error_message) {
+            error @ AxonError::MissingHandler(_)
+            | error @ AxonError::Transport(_)
+            | error @ AxonError::Timeout(_) => {
+                warn!("Could not dispatch deadline: {:?}: {:?}", name, error);
+                return Err(error.into());
+            }
+            error => warn!("Deadline failed: {:?}: {:?}", name, error),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use super::error::{AxonError, ErrorDetails};
+use super::metadata::number_value;
+use super::routing::processing_instruction;
+use crate::axon_server::common::{ProcessingInstruction, ProcessingKey};
+use anyhow::Result;
+use futures_core::Future;
+use std::time::Duration;
+
+/// Options for sending a command or a query.
+///
+/// Options that are not set are taken from the defaults of the `AxonServerHandle`.
+/// Fields:
+/// * `timeout`: AxonServer gives up on the message after this duration, and so does the sender.
+/// * `priority`: Messages with a higher priority are handled first.
+/// * `nr_of_results`: The maximum number of results that the sender expects for a query. When it is not 1, the
+///   query is a scatter-gather query: the results that arrived before the timeout are returned instead of an error.
+/// * `response_type`: The response type that the sender expects for a query. It selects the query handler.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DispatchOptions {
+    pub timeout: Option<Duration>,
+    pub priority: Option<i64>,
+    pub nr_of_results: Option<i64>,
+    pub response_type: Option<String>,
+}
+
+/// Creates dispatch options without any settings.
+pub fn dispatch_options() -> DispatchOptions {
+    DispatchOptions::default()
+}
+
+impl DispatchOptions {
+    /// Gives up on the message after the given duration.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the relative priority of the message.
+    pub fn with_priority(mut self, priority: i64) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Sets the maximum number of results that are expected for a query.
+    pub fn with_nr_of_results(mut self, nr_of_results: i64) -> Self {
+        self.nr_of_results = Some(nr_of_results);
+        self
+    }
+
+    /// Sets the response type that is expected for a query.
+    pub fn with_response_type(mut self, response_type: &str) -> Self {
+        self.response_type = Some(response_type.to_string());
+        self
+    }
+
+    /// Returns these options, with the settings that are missing taken from the given defaults.
+    pub fn or(self, defaults: &DispatchOptions) -> Self {
+        DispatchOptions {
+            timeout: self.timeout.or(defaults.timeout),
+            priority: self.priority.or(defaults.priority),
+            nr_of_results: self.nr_of_results.or(defaults.nr_of_results),
+            response_type: self
+                .response_type
+                .or_else(|| defaults.response_type.clone()),
+        }
+    }
+
+    pub(crate) fn processing_instructions(&self) -> Vec<ProcessingInstruction> {
+        let mut result = Vec::new();
+        if let Some(timeout) = self.timeout {
+            result.push(processing_instruction(
+                ProcessingKey::Timeout,
+                number_value(timeout.as_millis() as i64),
+            ));
+        }
+        if let Some(priority) = self.priority {
+            result.push(processing_instruction(
+                ProcessingKey::Priority,
+                number_value(priority),
+            ));
+        }
+        if let Some(nr_of_results) = self.nr_of_results {
+            result.push(processing_instruction(
+                ProcessingKey::NrOfResults,
+                number_value(nr_of_results),
+            ));
+        }
+        result
+    }
+
+    /// Runs the given future, but gives up with an `AxonError::Timeout` when the timeout expires.
+    pub(crate) async fn enforce_timeout<T, F: Future<Output = Result<T>>>(
+        &self,
+        description: &str,
+        future: F,
+    ) -> Result<T> {
+        match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, future).await {
+                Ok(result) => result,
+                Err(_) => Err(AxonError::Timeout(ErrorDetails {
+                    message: format!("{}: no result within {:?}", description, timeout),
+                    ..ErrorDetails::default()
+                })
+                .into()),
+            },
+            None => future.await,
+        }
+    }
+}
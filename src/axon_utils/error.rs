@@ -0,0 +1,284 @@
+use super::command_worker::ConcurrencyError;
+use crate::axon_server::ErrorMessage;
+use std::fmt::{Display, Formatter};
+use tokio::time::error::Elapsed;
+use tonic::{Code, Status};
+
+const NO_HANDLER_FOR_COMMAND: &str = "AXONIQ-4000";
+const COMMAND_EXECUTION_ERROR: &str = "AXONIQ-4002";
+const COMMAND_DISPATCH_ERROR: &str = "AXONIQ-4003";
+const CONCURRENCY_EXCEPTION: &str = "AXONIQ-4004";
+const COMMAND_EXECUTION_NON_TRANSIENT_ERROR: &str = "AXONIQ-4005";
+const COMMAND_TIMEOUT: &str = "AXONIQ-4006";
+const NO_HANDLER_FOR_QUERY: &str = "AXONIQ-5000";
+const QUERY_EXECUTION_ERROR: &str = "AXONIQ-5001";
+const QUERY_DISPATCH_ERROR: &str = "AXONIQ-5002";
+const QUERY_EXECUTION_NON_TRANSIENT_ERROR: &str = "AXONIQ-5003";
+const QUERY_TIMEOUT: &str = "AXONIQ-5004";
+
+/// The kind of message that an error relates to. Commands and queries use different error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Command,
+    Query,
+}
+
+/// The description of an error as it is passed through AxonServer in an `ErrorMessage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorDetails {
+    pub message: String,
+    pub location: String,
+    pub details: Vec<String>,
+}
+
+/// Error that is passed between handlers and callers through AxonServer.
+///
+/// Each variant maps to an AxonServer error code, so that callers can tell, e.g., a failed validation
+/// from an outage. Handlers can return an `AxonError` (wrapped in an `anyhow::Error`) to control the
+/// error that is reported. Other errors are classified by their type; see `From<&anyhow::Error>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AxonError {
+    /// The handler rejected the message, e.g., because it did not pass validation.
+    Business(ErrorDetails),
+    /// No handler is registered for the message.
+    MissingHandler(ErrorDetails),
+    /// The aggregate was modified concurrently and retrying did not help.
+    Concurrency(ErrorDetails),
+    /// The message could not be delivered, e.g., because the connection to AxonServer failed.
+    Transport(ErrorDetails),
+    /// The payload of the message could not be decoded.
+    Decode(ErrorDetails),
+    /// No result was produced in time.
+    Timeout(ErrorDetails),
+}
+
+/// Creates an error that reports that a handler rejected a message, with additional details for the caller.
+pub fn business_error(message: &str, details: &[&str]) -> AxonError {
+    AxonError::Business(ErrorDetails {
+        message: message.to_string(),
+        location: "".to_string(),
+        details: details.iter().map(|d| d.to_string()).collect(),
+    })
+}
+
+impl AxonError {
+    pub(crate) fn missing_handler(message: String) -> Self {
+        AxonError::MissingHandler(ErrorDetails {
+            message,
+            ..ErrorDetails::default()
+        })
+    }
+
+    /// Returns the description of this error.
+    pub fn details(&self) -> &ErrorDetails {
+        match self {
+            AxonError::Business(details) => details,
+            AxonError::MissingHandler(details) => details,
+            AxonError::Concurrency(details) => details,
+            AxonError::Transport(details) => details,
+            AxonError::Decode(details) => details,
+            AxonError::Timeout(details) => details,
+        }
+    }
+
+    /// Returns the AxonServer error code for this error.
+    pub fn error_code(&self, kind: MessageKind) -> &'static str {
+        match (kind, self) {
+            (MessageKind::Command, AxonError::Business(_)) => COMMAND_EXECUTION_ERROR,
+            (MessageKind::Command, AxonError::MissingHandler(_)) => NO_HANDLER_FOR_COMMAND,
+            (MessageKind::Command, AxonError::Concurrency(_)) => CONCURRENCY_EXCEPTION,
+            (MessageKind::Command, AxonError::Transport(_)) => COMMAND_DISPATCH_ERROR,
+            (MessageKind::Command, AxonError::Decode(_)) => COMMAND_EXECUTION_NON_TRANSIENT_ERROR,
+            (MessageKind::Command, AxonError::Timeout(_)) => COMMAND_TIMEOUT,
+            (MessageKind::Query, AxonError::Business(_)) => QUERY_EXECUTION_ERROR,
+            (MessageKind::Query, AxonError::MissingHandler(_)) => NO_HANDLER_FOR_QUERY,
+            (MessageKind::Query, AxonError::Concurrency(_)) => QUERY_EXECUTION_ERROR,
+            (MessageKind::Query, AxonError::Transport(_)) => QUERY_DISPATCH_ERROR,
+            (MessageKind::Query, AxonError::Decode(_)) => QUERY_EXECUTION_NON_TRANSIENT_ERROR,
+            (MessageKind::Query, AxonError::Timeout(_)) => QUERY_TIMEOUT,
+        }
+    }
+
+    /// Converts this error to an `ErrorMessage` for AxonServer. An empty location is replaced by the given one.
+    pub fn to_error_message(&self, kind: MessageKind, location: &str) -> ErrorMessage {
+        let details = self.details();
+        let location = if details.location.is_empty() {
+            location
+        } else {
+            &details.location
+        };
+        ErrorMessage {
+            message: details.message.clone(),
+            location: location.to_string(),
+            details: details.details.clone(),
+            error_code: self.error_code(kind).to_string(),
+        }
+    }
+
+    /// Converts an `ErrorMessage` that was received from AxonServer to an `AxonError`.
+    ///
+    /// Unknown error codes are reported as business errors.
+    pub fn from_error_message(error_message: ErrorMessage) -> Self {
+        let details = ErrorDetails {
+            message: error_message.message,
+            location: error_message.location,
+            details: error_message.details,
+        };
+        match error_message.error_code.as_str() {
+            NO_HANDLER_FOR_COMMAND | NO_HANDLER_FOR_QUERY => AxonError::MissingHandler(details),
+            CONCURRENCY_EXCEPTION => AxonError::Concurrency(details),
+            COMMAND_DISPATCH_ERROR | QUERY_DISPATCH_ERROR => AxonError::Transport(details),
+            COMMAND_EXECUTION_NON_TRANSIENT_ERROR | QUERY_EXECUTION_NON_TRANSIENT_ERROR => {
+                AxonError::Decode(details)
+            }
+            COMMAND_TIMEOUT | QUERY_TIMEOUT => AxonError::Timeout(details),
+            _ => AxonError::Business(details),
+        }
+    }
+}
+
+impl Display for AxonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let details = self.details();
+        match self {
+            AxonError::Business(_) => f.write_str("Rejected")?,
+            AxonError::MissingHandler(_) => f.write_str("Missing handler")?,
+            AxonError::Concurrency(_) => f.write_str("Concurrency conflict")?,
+            AxonError::Transport(_) => f.write_str("Transport error")?,
+            AxonError::Decode(_) => f.write_str("Decode error")?,
+            AxonError::Timeout(_) => f.write_str("Timeout")?,
+        }
+        f.write_fmt(format_args!(": {}", details.message))?;
+        if !details.details.is_empty() {
+            f.write_fmt(format_args!(": {:?}", details.details))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AxonError {}
+
+impl From<Status> for AxonError {
+    fn from(status: Status) -> Self {
+        (&status).into()
+    }
+}
+
+impl From<&Status> for AxonError {
+    fn from(status: &Status) -> Self {
+        let details = ErrorDetails {
+            message: status.message().to_string(),
+            location: "".to_string(),
+            details: Vec::new(),
+        };
+        match status.code() {
+            Code::DeadlineExceeded => AxonError::Timeout(details),
+            _ => AxonError::Transport(details),
+        }
+    }
+}
+
+impl From<&anyhow::Error> for AxonError {
+    fn from(error: &anyhow::Error) -> Self {
+        if let Some(axon_error) = error.downcast_ref::<AxonError>() {
+            return axon_error.clone();
+        }
+        if let Some(status) = error.downcast_ref::<Status>() {
+            return status.into();
+        }
+        let details = ErrorDetails {
+            message: error.to_string(),
+            location: "".to_string(),
+            details: error.chain().skip(1).map(|e| e.to_string()).collect(),
+        };
+        if error.downcast_ref::<ConcurrencyError>().is_some() {
+            AxonError::Concurrency(details)
+        } else if error.downcast_ref::<prost::DecodeError>().is_some() {
+            AxonError::Decode(details)
+        } else if error.downcast_ref::<Elapsed>().is_some() {
+            AxonError::Timeout(details)
+        } else {
+            AxonError::Business(details)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_message(error_code: &str) -> ErrorMessage {
+        ErrorMessage {
+            message: "Failed".to_string(),
+            location: "test".to_string(),
+            details: Vec::new(),
+            error_code: error_code.to_string(),
+        }
+    }
+
+    fn kind_of(error_code: &str) -> AxonError {
+        AxonError::from_error_message(error_message(error_code))
+    }
+
+    #[test]
+    fn command_error_codes() {
+        assert!(matches!(
+            kind_of("AXONIQ-4000"),
+            AxonError::MissingHandler(_)
+        ));
+        assert!(matches!(kind_of("AXONIQ-4002"), AxonError::Business(_)));
+        assert!(matches!(kind_of("AXONIQ-4003"), AxonError::Transport(_)));
+        assert!(matches!(kind_of("AXONIQ-4004"), AxonError::Concurrency(_)));
+        assert!(matches!(kind_of("AXONIQ-4005"), AxonError::Decode(_)));
+        assert!(matches!(kind_of("AXONIQ-4006"), AxonError::Timeout(_)));
+    }
+
+    #[test]
+    fn query_error_codes() {
+        assert!(matches!(
+            kind_of("AXONIQ-5000"),
+            AxonError::MissingHandler(_)
+        ));
+        assert!(matches!(kind_of("AXONIQ-5001"), AxonError::Business(_)));
+        assert!(matches!(kind_of("AXONIQ-5002"), AxonError::Transport(_)));
+        assert!(matches!(kind_of("AXONIQ-5003"), AxonError::Decode(_)));
+        assert!(matches!(kind_of("AXONIQ-5004"), AxonError::Timeout(_)));
+    }
+
+    #[test]
+    fn unknown_error_code_is_business_error() {
+        assert!(matches!(kind_of("AXONIQ-9999"), AxonError::Business(_)));
+        assert!(matches!(kind_of(""), AxonError::Business(_)));
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        let details = ErrorDetails::default();
+        let errors = vec![
+            AxonError::Business(details.clone()),
+            AxonError::MissingHandler(details.clone()),
+            AxonError::Transport(details.clone()),
+            AxonError::Decode(details.clone()),
+            AxonError::Timeout(details.clone()),
+        ];
+        for kind in &[MessageKind::Command, MessageKind::Query] {
+            for error in &errors {
+                let error_message = error.to_error_message(*kind, "test");
+                assert_eq!(
+                    AxonError::from_error_message(error_message.clone()).error_code(*kind),
+                    error_message.error_code
+                );
+                assert_eq!(
+                    std::mem::discriminant(&AxonError::from_error_message(error_message)),
+                    std::mem::discriminant(error)
+                );
+            }
+        }
+        let concurrency = AxonError::Concurrency(details);
+        let error_message = concurrency.to_error_message(MessageKind::Command, "test");
+        assert!(matches!(
+            AxonError::from_error_message(error_message),
+            AxonError::Concurrency(_)
+        ));
+    }
+}
@@ -1,13 +1,49 @@
+use super::deadline::DEADLINE_EVENT_TYPE;
 use super::handler_registry::TheHandlerRegistry;
+use super::metadata::{caused_by, MetaData};
+use super::segment::Segment;
 use super::AxonServerHandle;
-use crate::axon_server::event::event_store_client::EventStoreClient;
-use crate::axon_server::event::{Event, EventWithToken, GetEventsRequest};
+use crate::axon_server::control::event_processor_info::SegmentStatus;
+use crate::axon_server::control::EventProcessorInfo;
+use crate::axon_server::event::{Event, EventWithToken, GetEventsRequest, PayloadDescription};
+use crate::axon_server::event::{GetFirstTokenRequest, GetLastTokenRequest, GetTokenAtRequest};
+use crate::axon_server::SerializedObject;
 use crate::intellij_work_around::Debuggable;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_stream::stream;
 use futures_core::stream::Stream;
-use log::debug;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Interval between attempts to claim unclaimed segments.
+const CLAIM_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Period during which a released segment is not claimed again by the same processor.
+const RELEASE_PERIOD: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    static REPLAYING: bool;
+    static CAUSED_BY_EVENT: MetaData;
+}
+
+/// Returns true if the event that is currently being handled is replayed after a reset of the event processor.
+///
+/// Event handlers can use this to suppress side effects (like sending e-mail) during a replay.
+pub fn is_replaying() -> bool {
+    REPLAYING.try_with(|replaying| *replaying).unwrap_or(false)
+}
+
+/// Returns the metadata for messages that are caused by the event that is currently being handled.
+pub(crate) fn caused_by_event() -> MetaData {
+    CAUSED_BY_EVENT
+        .try_with(|meta_data| meta_data.clone())
+        .unwrap_or_default()
+}
 
 #[derive(Debug)]
 struct AxonEventProcessed {
@@ -17,28 +53,770 @@ struct AxonEventProcessed {
 /// Describes a token store.
 ///
 /// A token store can be used to persist markers that indicate the last processed event for each event processor.
+///
+/// A token store that supports segmentation also keeps track of the segments of the event stream and of the
+/// processor instance that claimed each segment. The default implementations of the segment related methods
+/// only know the root segment and delegate to `store_token` and `retrieve_token`.
 #[tonic::async_trait]
 pub trait TokenStore {
     async fn store_token(&self, token: i64);
     async fn retrieve_token(&self) -> Result<i64>;
+
+    /// Lists all segments of the event stream for this processor.
+    async fn retrieve_segments(&self) -> Result<Vec<Segment>> {
+        Ok(vec![Segment::ROOT])
+    }
+
+    /// Stores the token for a segment. Implementations may use this to extend the claim on the segment.
+    async fn store_segment_token(&self, segment: &Segment, token: i64) {
+        if segment.is_root() {
+            self.store_token(token).await
+        } else {
+            warn!("Token store does not support segments: {:?}", segment);
+        }
+    }
+
+    /// Retrieves the token for a segment.
+    async fn retrieve_segment_token(&self, segment: &Segment) -> Result<i64> {
+        if segment.is_root() {
+            self.retrieve_token().await
+        } else {
+            Err(anyhow!(
+                "Token store does not support segments: {:?}",
+                segment
+            ))
+        }
+    }
+
+    /// Tries to claim a segment for the given owner. Returns false if the segment is claimed by another owner.
+    async fn claim_segment(&self, _segment: &Segment, _owner: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Releases the claim of the given owner on a segment.
+    async fn release_segment(&self, _segment: &Segment, _owner: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Replaces segments with new segments and their tokens, as a result of a split or a merge.
+    /// The new segments are not claimed.
+    async fn replace_segments(&self, old: &[Segment], _new: &[(Segment, i64)]) -> Result<()> {
+        Err(anyhow!(
+            "Token store does not support splitting or merging segments: {:?}",
+            old
+        ))
+    }
+
+    /// Called when the event processor is reset, before the tokens are moved. Query models can use this
+    /// to clear the records that are going to be rebuilt.
+    async fn reset(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The position in the event stream to which an event processor is reset.
+#[derive(Debug, Clone, Copy)]
+pub enum ResetPosition {
+    /// Process all events from the start of the event stream.
+    First,
+    /// Skip all events that are currently in the event stream.
+    Last,
+    /// Process all events that were stored at or after the given time (milliseconds since the epoch).
+    Timestamp(i64),
+    /// Process all events after the event with the given token.
+    Token(i64),
+}
+
+/// An instruction for an event processor that was received from AxonServer.
+#[derive(Debug, Clone)]
+pub enum ProcessorInstruction {
+    /// Stop processing the segment with the given identifier and release the claim on it.
+    Release(i32),
+    /// Split the segment with the given identifier in two halves.
+    Split(i32),
+    /// Merge the segment with the given identifier with the segment it can be merged with.
+    Merge(i32),
+    /// Stop processing all segments and release the claims on them until the processor is started again.
+    Pause,
+    /// Resume processing after a pause.
+    Start,
+    /// Move the tokens of all segments back (or forward) to the given position.
+    Reset(ResetPosition),
+}
+
+/// Carries a `ProcessorInstruction` to an event processor, together with a channel for the result.
+#[derive(Debug)]
+pub struct EventProcessorInstruction {
+    pub instruction: ProcessorInstruction,
+    pub result: oneshot::Sender<Result<()>>,
+}
+
+/// The status of an event processor, as it is reported to AxonServer.
+#[derive(Debug, Default)]
+struct ProcessorStatus {
+    max_segments: usize,
+    running: bool,
+    error: bool,
+    segments: HashMap<i32, SegmentStatus>,
+}
+
+type SharedStatus = Arc<Mutex<ProcessorStatus>>;
+
+fn update_status(status: &SharedStatus, update: impl FnOnce(&mut ProcessorStatus)) {
+    if let Ok(mut status) = status.lock() {
+        update(&mut status);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RegisteredProcessor {
+    sender: Sender<EventProcessorInstruction>,
+    status: SharedStatus,
+}
+
+/// Keeps track of the event processors that use an `AxonServerHandle`, so that the platform worker can
+/// forward instructions from AxonServer to them and report their status.
+#[derive(Debug, Clone, Default)]
+pub struct EventProcessorRegistry {
+    processors: Arc<Mutex<HashMap<String, RegisteredProcessor>>>,
+}
+
+impl EventProcessorRegistry {
+    fn register(
+        &self,
+        name: &str,
+        sender: Sender<EventProcessorInstruction>,
+        status: SharedStatus,
+    ) -> Result<()> {
+        let mut processors = self.processors.lock().map_err(|e| anyhow!(e.to_string()))?;
+        if processors.contains_key(name) {
+            return Err(anyhow!("Event processor already registered: {:?}", name));
+        }
+        processors.insert(name.to_string(), RegisteredProcessor { sender, status });
+        Ok(())
+    }
+
+    fn unregister(&self, name: &str) {
+        if let Ok(mut processors) = self.processors.lock() {
+            processors.remove(name);
+        }
+    }
+
+    /// Returns the channel for instructions to the event processor with the given name.
+    pub fn get(&self, name: &str) -> Option<Sender<EventProcessorInstruction>> {
+        self.processors
+            .lock()
+            .ok()
+            .and_then(|processors| processors.get(name).map(|p| p.sender.clone()))
+    }
+
+    /// Returns the names of all registered event processors.
+    pub fn names(&self) -> Vec<String> {
+        self.processors
+            .lock()
+            .map(|processors| processors.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Describes the current status of the event processor with the given name.
+    pub fn event_processor_info(&self, name: &str) -> Option<EventProcessorInfo> {
+        let status = self.processors.lock().ok()?.get(name)?.status.clone();
+        let status = status.lock().ok()?;
+        let mut segment_status: Vec<SegmentStatus> = status.segments.values().cloned().collect();
+        segment_status.sort_by_key(|s| s.segment_id);
+        let active_threads = segment_status.len() as i32;
+        Some(EventProcessorInfo {
+            processor_name: name.to_string(),
+            mode: "Tracking".to_string(),
+            active_threads,
+            running: status.running,
+            error: status.error,
+            segment_status,
+            available_threads: (status.max_segments as i32 - active_threads).max(0),
+            token_store_identifier: "".to_string(),
+        })
+    }
+}
+
+/// Resets the event processor with the given name, that is running with the given handle, to the given position.
+///
+/// The processor stops processing, claims all segments, calls `reset` on the query model, stores the new tokens
+/// and resumes processing. Events up to the position that the processor had reached before the reset are
+/// marked as replaying (see `is_replaying`). This position is not persisted, so events that are processed
+/// again after a restart of the application are not marked as replaying.
+pub async fn reset_event_processor(
+    axon_server_handle: &AxonServerHandle,
+    processor_name: &str,
+    position: ResetPosition,
+) -> Result<()> {
+    let processor = axon_server_handle
+        .event_processors
+        .get(processor_name)
+        .ok_or_else(|| anyhow!("Unknown event processor: {:?}", processor_name))?;
+    let (result_tx, result_rx) = oneshot::channel();
+    processor
+        .send(EventProcessorInstruction {
+            instruction: ProcessorInstruction::Reset(position),
+            result: result_tx,
+        })
+        .await
+        .map_err(|_| anyhow!("Event processor stopped: {:?}", processor_name))?;
+    result_rx
+        .await
+        .map_err(|_| anyhow!("Event processor stopped: {:?}", processor_name))?
 }
 
 /// Subscribes to events and builds a query model from them.
 ///
-/// There are likely to be multiple query models for a single application.
-pub async fn event_processor<Q: TokenStore + Send + Sync + Clone>(
+/// There are likely to be multiple query models for a single application. The processor registers itself
+/// under `processor_name`, so that it shows up in AxonServer and can be paused and started from there.
+pub async fn event_processor<Q: TokenStore + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    processor_name: &str,
+    query_model: Q,
+    event_handler_registry: TheHandlerRegistry<Q, Option<Q>>,
+) -> Result<()> {
+    segmented_event_processor(
+        axon_server_handle,
+        processor_name,
+        1,
+        query_model,
+        event_handler_registry,
+    )
+    .await
+}
+
+/// Subscribes to events and builds a query model from them, processing at most `max_segments` segments of
+/// the event stream at the same time.
+///
+/// Other instances of the same processor can claim the remaining segments. The processor registers itself
+/// under `processor_name`, so that the platform worker can forward instructions to release, split or merge
+/// segments. After a merge, events of the segment that was ahead are processed again from the token of
+/// the segment that was behind.
+pub async fn segmented_event_processor<Q: TokenStore + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    processor_name: &str,
+    max_segments: usize,
+    query_model: Q,
+    event_handler_registry: TheHandlerRegistry<Q, Option<Q>>,
+) -> Result<()> {
+    let registry = axon_server_handle.event_processors.clone();
+    let (tx, instructions) = channel(10);
+    let status = Arc::new(Mutex::new(ProcessorStatus {
+        max_segments,
+        running: true,
+        ..ProcessorStatus::default()
+    }));
+    registry.register(processor_name, tx, status.clone())?;
+    let _registration = Registration {
+        registry,
+        processor_name: processor_name.to_string(),
+    };
+    run_event_processor(
+        axon_server_handle,
+        processor_name,
+        max_segments,
+        query_model,
+        event_handler_registry,
+        instructions,
+        status,
+    )
+    .await
+}
+
+/// Unregisters an event processor when it stops, also when its future is dropped, e.g., by a supervisor
+/// that reconnects.
+struct Registration {
+    registry: EventProcessorRegistry,
+    processor_name: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.processor_name);
+    }
+}
+
+async fn run_event_processor<Q: TokenStore + Send + Sync + Clone + 'static>(
     axon_server_handle: AxonServerHandle,
+    processor_name: &str,
+    max_segments: usize,
     query_model: Q,
     event_handler_registry: TheHandlerRegistry<Q, Option<Q>>,
+    mut instructions: Receiver<EventProcessorInstruction>,
+    status: SharedStatus,
+) -> Result<()> {
+    let (done_tx, mut done_rx) = unbounded_channel();
+    let mut coordinator = SegmentCoordinator {
+        axon_server_handle,
+        processor_name: processor_name.to_string(),
+        max_segments,
+        query_model,
+        event_handler_registry: Arc::new(event_handler_registry),
+        workers: HashMap::new(),
+        released: HashMap::new(),
+        done_tx,
+        status,
+        paused: false,
+        replay_until: None,
+    };
+    let mut claim_interval = tokio::time::interval(CLAIM_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = claim_interval.tick() => {
+                coordinator.claim_segments().await;
+            }
+            Some((segment, result)) = done_rx.recv() => {
+                if let Err(e) = coordinator.worker_done(segment, result).await {
+                    error!("Error while processing segment: {:?}: {:?}", segment, e);
+                    coordinator.stop_all().await;
+                    return Err(e);
+                }
+            }
+            Some(instruction) = instructions.recv() => {
+                debug!("Event processor instruction: {:?}", instruction.instruction);
+                let result = coordinator.handle_instruction(instruction.instruction).await;
+                if let Err(e) = result.as_ref() {
+                    warn!("Error while handling event processor instruction: {:?}", e);
+                }
+                instruction.result.send(result).ok();
+            }
+        }
+    }
+}
+
+struct SegmentWorker {
+    segment: Segment,
+    stop: Sender<()>,
+    join_handle: JoinHandle<()>,
+}
+
+struct SegmentCoordinator<Q: TokenStore + Send + Sync + Clone + 'static> {
+    axon_server_handle: AxonServerHandle,
+    processor_name: String,
+    max_segments: usize,
+    query_model: Q,
+    event_handler_registry: Arc<TheHandlerRegistry<Q, Option<Q>>>,
+    workers: HashMap<i32, SegmentWorker>,
+    released: HashMap<i32, Instant>,
+    done_tx: UnboundedSender<(Segment, Result<()>)>,
+    status: SharedStatus,
+    paused: bool,
+    replay_until: Option<i64>,
+}
+
+impl<Q: TokenStore + Send + Sync + Clone + 'static> SegmentCoordinator<Q> {
+    fn owner(&self) -> String {
+        self.axon_server_handle.client_id.clone()
+    }
+
+    async fn claim_segments(&mut self) {
+        self.released
+            .retain(|_, released_at| released_at.elapsed() < RELEASE_PERIOD);
+        if self.paused || self.workers.len() >= self.max_segments {
+            return;
+        }
+        let segments = match self.query_model.retrieve_segments().await {
+            Ok(segments) if segments.is_empty() => vec![Segment::ROOT],
+            Ok(segments) => segments,
+            Err(e) => {
+                warn!("Could not retrieve segments: {:?}", e);
+                return;
+            }
+        };
+        for segment in segments {
+            if self.workers.len() >= self.max_segments {
+                break;
+            }
+            if self.workers.contains_key(&segment.segment_id)
+                || self.released.contains_key(&segment.segment_id)
+            {
+                continue;
+            }
+            match self
+                .query_model
+                .claim_segment(&segment, &self.owner())
+                .await
+            {
+                Ok(true) => self.start_worker(segment),
+                Ok(false) => debug!("Segment claimed by another processor: {:?}", segment),
+                Err(e) => warn!("Could not claim segment: {:?}: {:?}", segment, e),
+            }
+        }
+    }
+
+    fn start_worker(&mut self, segment: Segment) {
+        debug!("Start processing segment: {:?}", segment);
+        let (stop, stop_rx) = channel(1);
+        let segment_task = SegmentTask {
+            axon_server_handle: self.axon_server_handle.clone(),
+            processor_name: self.processor_name.clone(),
+            segment,
+            query_model: self.query_model.clone(),
+            event_handler_registry: self.event_handler_registry.clone(),
+            status: self.status.clone(),
+            replay_until: self.replay_until,
+        };
+        let done_tx = self.done_tx.clone();
+        let join_handle = tokio::spawn(async move {
+            let result = process_segment(segment_task, stop_rx).await;
+            done_tx.send((segment, result)).ok();
+        });
+        let worker = SegmentWorker {
+            segment,
+            stop,
+            join_handle,
+        };
+        self.workers.insert(segment.segment_id, worker);
+    }
+
+    async fn stop_worker(&mut self, segment_id: i32) -> Option<Segment> {
+        let worker = self.workers.remove(&segment_id)?;
+        debug!("Stop processing segment: {:?}", worker.segment);
+        worker.stop.send(()).await.ok();
+        worker.join_handle.await.ok();
+        update_status(&self.status, |status| {
+            status.segments.remove(&segment_id);
+        });
+        Some(worker.segment)
+    }
+
+    async fn stop_all(&mut self) {
+        let segment_ids: Vec<i32> = self.workers.keys().cloned().collect();
+        for segment_id in segment_ids {
+            if let Some(segment) = self.stop_worker(segment_id).await {
+                self.release(&segment).await;
+            }
+        }
+    }
+
+    async fn release(&mut self, segment: &Segment) {
+        if let Err(e) = self
+            .query_model
+            .release_segment(segment, &self.owner())
+            .await
+        {
+            warn!("Could not release segment: {:?}: {:?}", segment, e);
+        }
+    }
+
+    async fn worker_done(&mut self, segment: Segment, result: Result<()>) -> Result<()> {
+        match self.workers.get(&segment.segment_id) {
+            Some(worker) if worker.segment == segment => {
+                self.workers.remove(&segment.segment_id);
+                self.release(&segment).await;
+                update_status(&self.status, |status| match result.as_ref() {
+                    Ok(()) => {
+                        status.segments.remove(&segment.segment_id);
+                    }
+                    Err(e) => {
+                        status.error = true;
+                        if let Some(segment_status) = status.segments.get_mut(&segment.segment_id) {
+                            segment_status.error_state = e.to_string();
+                        }
+                    }
+                });
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_instruction(&mut self, instruction: ProcessorInstruction) -> Result<()> {
+        match instruction {
+            ProcessorInstruction::Release(segment_id) => {
+                self.released.insert(segment_id, Instant::now());
+                if let Some(segment) = self.stop_worker(segment_id).await {
+                    self.release(&segment).await;
+                }
+                Ok(())
+            }
+            ProcessorInstruction::Split(segment_id) => self.split(segment_id).await,
+            ProcessorInstruction::Merge(segment_id) => self.merge(segment_id).await,
+            ProcessorInstruction::Pause => {
+                debug!("Pause event processor: {:?}", self.processor_name);
+                self.paused = true;
+                self.stop_all().await;
+                update_status(&self.status, |status| status.running = false);
+                Ok(())
+            }
+            ProcessorInstruction::Start => {
+                debug!("Start event processor: {:?}", self.processor_name);
+                self.paused = false;
+                update_status(&self.status, |status| status.running = true);
+                self.claim_segments().await;
+                Ok(())
+            }
+            ProcessorInstruction::Reset(position) => {
+                let result = self.reset(position).await;
+                self.claim_segments().await;
+                result
+            }
+        }
+    }
+
+    async fn reset(&mut self, position: ResetPosition) -> Result<()> {
+        let mut client = self.axon_server_handle.event_store_client();
+        let token = match position {
+            ResetPosition::First => {
+                client
+                    .get_first_token(GetFirstTokenRequest {})
+                    .await?
+                    .into_inner()
+                    .token
+                    - 1
+            }
+            ResetPosition::Last => {
+                client
+                    .get_last_token(GetLastTokenRequest {})
+                    .await?
+                    .into_inner()
+                    .token
+            }
+            ResetPosition::Timestamp(instant) => {
+                client
+                    .get_token_at(GetTokenAtRequest { instant })
+                    .await?
+                    .into_inner()
+                    .token
+                    - 1
+            }
+            ResetPosition::Token(token) => token,
+        };
+        debug!(
+            "Reset event processor: {:?}: {:?}: {:?}",
+            self.processor_name, position, token
+        );
+        let segments = match self.query_model.retrieve_segments().await? {
+            segments if segments.is_empty() => vec![Segment::ROOT],
+            segments => segments,
+        };
+        self.stop_all().await;
+        let mut claimed = Vec::new();
+        for segment in &segments {
+            match self.query_model.claim_segment(segment, &self.owner()).await {
+                Ok(true) => claimed.push(*segment),
+                Ok(false) => {
+                    self.release_all(&claimed).await;
+                    return Err(anyhow!(
+                        "Segment claimed by another processor: {:?}",
+                        segment
+                    ));
+                }
+                Err(e) => {
+                    self.release_all(&claimed).await;
+                    return Err(e);
+                }
+            }
+        }
+        let mut replay_until = -1;
+        for segment in &segments {
+            replay_until = replay_until.max(self.retrieve_token(segment).await);
+        }
+        if let Err(e) = self.query_model.reset().await {
+            self.release_all(&claimed).await;
+            return Err(e);
+        }
+        for segment in &segments {
+            self.query_model.store_segment_token(segment, token).await;
+        }
+        self.release_all(&claimed).await;
+        self.replay_until = if replay_until > token {
+            Some(replay_until)
+        } else {
+            None
+        };
+        self.released.clear();
+        Ok(())
+    }
+
+    async fn release_all(&mut self, segments: &[Segment]) {
+        for segment in segments {
+            self.release(segment).await;
+        }
+    }
+
+    async fn split(&mut self, segment_id: i32) -> Result<()> {
+        let segment = self
+            .stop_worker(segment_id)
+            .await
+            .ok_or_else(|| anyhow!("Segment not claimed: {:?}", segment_id))?;
+        let token = self.retrieve_token(&segment).await;
+        let (first, second) = segment.split();
+        let new_segments = [(first, token), (second, token)];
+        if let Err(e) = self
+            .query_model
+            .replace_segments(&[segment], &new_segments)
+            .await
+        {
+            self.start_worker(segment);
+            return Err(e);
+        }
+        debug!("Split segment: {:?}: {:?}: {:?}", segment, first, second);
+        self.claim_and_start(first).await;
+        Ok(())
+    }
+
+    async fn merge(&mut self, segment_id: i32) -> Result<()> {
+        let segment = self
+            .workers
+            .get(&segment_id)
+            .map(|worker| worker.segment)
+            .ok_or_else(|| anyhow!("Segment not claimed: {:?}", segment_id))?;
+        let other = Segment {
+            segment_id: segment.mergeable_segment_id(),
+            mask: segment.mask,
+        };
+        if !self.query_model.retrieve_segments().await?.contains(&other) {
+            return Err(anyhow!("No segment to merge with: {:?}", segment));
+        }
+        let other_was_claimed = self.stop_worker(other.segment_id).await.is_some();
+        if !other_was_claimed
+            && !self
+                .query_model
+                .claim_segment(&other, &self.owner())
+                .await?
+        {
+            return Err(anyhow!("Could not claim segment: {:?}", other));
+        }
+        self.stop_worker(segment_id).await;
+        let token = self
+            .retrieve_token(&segment)
+            .await
+            .min(self.retrieve_token(&other).await);
+        let merged = segment.merged();
+        if let Err(e) = self
+            .query_model
+            .replace_segments(&[segment, other], &[(merged, token)])
+            .await
+        {
+            self.start_worker(segment);
+            if other_was_claimed {
+                self.start_worker(other);
+            } else {
+                self.release(&other).await;
+            }
+            return Err(e);
+        }
+        debug!("Merged segments: {:?}: {:?}: {:?}", segment, other, merged);
+        self.claim_and_start(merged).await;
+        Ok(())
+    }
+
+    async fn retrieve_token(&self, segment: &Segment) -> i64 {
+        self.query_model
+            .retrieve_segment_token(segment)
+            .await
+            .unwrap_or(-1)
+    }
+
+    async fn claim_and_start(&mut self, segment: Segment) {
+        match self
+            .query_model
+            .claim_segment(&segment, &self.owner())
+            .await
+        {
+            Ok(true) => self.start_worker(segment),
+            Ok(false) => debug!("Segment claimed by another processor: {:?}", segment),
+            Err(e) => warn!("Could not claim segment: {:?}: {:?}", segment, e),
+        }
+    }
+}
+
+/// Stops the segment workers and releases their claims when the event processor is dropped without
+/// stopping them, e.g., by a supervisor that reconnects.
+impl<Q: TokenStore + Send + Sync + Clone + 'static> Drop for SegmentCoordinator<Q> {
+    fn drop(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        let mut segments = Vec::new();
+        for (_, worker) in self.workers.drain() {
+            worker.join_handle.abort();
+            segments.push(worker.segment);
+        }
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => {
+                warn!("Could not release segments: {:?}", segments);
+                return;
+            }
+        };
+        let query_model = self.query_model.clone();
+        let owner = self.owner();
+        runtime.spawn(async move {
+            for segment in segments {
+                debug!("Release segment of dropped event processor: {:?}", segment);
+                if let Err(e) = query_model.release_segment(&segment, &owner).await {
+                    warn!("Could not release segment: {:?}: {:?}", segment, e);
+                }
+            }
+        });
+    }
+}
+
+/// Everything a worker needs to process a segment of the event stream.
+struct SegmentTask<Q: TokenStore + Send + Sync + Clone + 'static> {
+    axon_server_handle: AxonServerHandle,
+    processor_name: String,
+    segment: Segment,
+    query_model: Q,
+    event_handler_registry: Arc<TheHandlerRegistry<Q, Option<Q>>>,
+    status: SharedStatus,
+    replay_until: Option<i64>,
+}
+
+async fn process_segment<Q: TokenStore + Send + Sync + Clone>(
+    segment_task: SegmentTask<Q>,
+    mut stop: Receiver<()>,
 ) -> Result<()> {
-    let conn = axon_server_handle.conn.clone();
-    let mut client = EventStoreClient::new(conn);
+    let SegmentTask {
+        axon_server_handle,
+        processor_name,
+        segment,
+        query_model,
+        event_handler_registry,
+        status,
+        replay_until,
+    } = segment_task;
+    let mut client = axon_server_handle.event_store_client();
 
     let (tx, rx): (Sender<AxonEventProcessed>, Receiver<AxonEventProcessed>) = channel(10);
 
-    let initial_token = query_model.retrieve_token().await.unwrap_or(-1) + 1;
-    debug!("Initial token: {:?}", initial_token);
-    let outbound = create_output_stream(axon_server_handle, initial_token, rx);
+    let initial_token = query_model
+        .retrieve_segment_token(&segment)
+        .await
+        .unwrap_or(-1)
+        + 1;
+    debug!("Initial token: {:?}: {:?}", segment, initial_token);
+    let head_token = client
+        .get_last_token(GetLastTokenRequest {})
+        .await?
+        .into_inner()
+        .token;
+    let mut caught_up = initial_token > head_token;
+    update_status(&status, |status| {
+        status.segments.insert(
+            segment.segment_id,
+            SegmentStatus {
+                segment_id: segment.segment_id,
+                caught_up,
+                replaying: matches!(replay_until, Some(until) if initial_token <= until),
+                one_part_of: segment.one_part_of(),
+                token_position: initial_token - 1,
+                error_state: "".to_string(),
+            },
+        );
+    });
+    let upcasters = axon_server_handle.upcasters.clone();
+    let blacklist = blacklist(&event_handler_registry);
+    let outbound = create_output_stream(
+        axon_server_handle,
+        processor_name,
+        initial_token,
+        blacklist,
+        rx,
+    );
 
     debug!("Event Processor: calling open_stream");
     let response = client.list_events(outbound).await?;
@@ -46,46 +824,120 @@ pub async fn event_processor<Q: TokenStore + Send + Sync + Clone>(
 
     let mut events = response.into_inner();
     loop {
-        let event_with_token = events.message().await?;
+        let event_with_token = tokio::select! {
+            _ = stop.recv() => {
+                debug!("Event Processor: stop: {:?}", segment);
+                return Ok(());
+            }
+            message = events.message() => message?,
+        };
         debug!(
             "Event with token: {:?}",
             event_with_token.as_ref().map(|e| Debuggable::from(e))
         );
 
-        if let Some(EventWithToken {
-            event: Some(event),
-            token,
-            ..
-        }) = event_with_token
-        {
-            if let Event {
-                payload: Some(serialized_object),
+        match event_with_token {
+            Some(EventWithToken {
+                event: Some(event),
+                token,
                 ..
-            } = event
-            {
-                if let Some(event_handler) = event_handler_registry
-                    .handlers
-                    .get(&serialized_object.r#type)
-                {
-                    (event_handler)
-                        .handle(serialized_object.data, query_model.clone())
+            }) => {
+                let replaying = matches!(replay_until, Some(until) if token <= until);
+                if segment.matches(sequencing_key(&event)) {
+                    let meta_data = caused_by(&event.message_identifier, &event.meta_data);
+                    if let Event {
+                        payload: Some(serialized_object),
+                        ..
+                    } = event
+                    {
+                        let serialized_object = upcasters.upcast(serialized_object)?;
+                        handle_event(
+                            serialized_object,
+                            meta_data,
+                            &event_handler_registry,
+                            query_model.clone(),
+                            replaying,
+                        )
                         .await?;
+                    }
                 }
-            }
 
-            query_model.store_token(token).await;
+                query_model.store_segment_token(&segment, token).await;
+                caught_up = caught_up || token >= head_token;
+                update_status(&status, |status| {
+                    if let Some(segment_status) = status.segments.get_mut(&segment.segment_id) {
+                        segment_status.token_position = token;
+                        segment_status.caught_up = caught_up;
+                        segment_status.replaying = replaying;
+                    }
+                });
 
-            tx.send(AxonEventProcessed {
-                message_identifier: event.message_identifier,
-            })
-            .await?;
+                tx.send(AxonEventProcessed {
+                    message_identifier: event.message_identifier,
+                })
+                .await?;
+            }
+            Some(_) => {}
+            None => {
+                debug!("Event Processor: end of stream: {:?}", segment);
+                return Ok(());
+            }
         }
     }
 }
 
+/// Applies an event to the query model with the handler that is registered for its type, if any.
+///
+/// Commands that a saga sends while it handles the event get `caused_by_meta_data` (see `caused_by_event`).
+pub(crate) async fn handle_event<Q: Send + Clone>(
+    serialized_object: SerializedObject,
+    caused_by_meta_data: MetaData,
+    event_handler_registry: &TheHandlerRegistry<Q, Option<Q>>,
+    query_model: Q,
+    replaying: bool,
+) -> Result<()> {
+    if let Some(event_handler) = event_handler_registry
+        .handlers
+        .get(&serialized_object.r#type)
+    {
+        let handled = CAUSED_BY_EVENT.scope(
+            caused_by_meta_data,
+            event_handler.handle(serialized_object.data, query_model),
+        );
+        REPLAYING.scope(replaying, handled).await?;
+    }
+    Ok(())
+}
+
+fn sequencing_key(event: &Event) -> &str {
+    if event.aggregate_identifier.is_empty() {
+        &event.message_identifier
+    } else {
+        &event.aggregate_identifier
+    }
+}
+
+/// Asks AxonServer not to send deadlines to event processors that do not dispatch them.
+fn blacklist<Q: Send + Clone>(
+    event_handler_registry: &TheHandlerRegistry<Q, Option<Q>>,
+) -> Vec<PayloadDescription> {
+    if event_handler_registry
+        .handlers
+        .contains_key(DEADLINE_EVENT_TYPE)
+    {
+        return Vec::new();
+    }
+    vec![PayloadDescription {
+        r#type: DEADLINE_EVENT_TYPE.to_string(),
+        revision: "".to_string(),
+    }]
+}
+
 fn create_output_stream(
     axon_server_handle: AxonServerHandle,
+    processor_name: String,
     initial_token: i64,
+    blacklist: Vec<PayloadDescription>,
     mut rx: Receiver<AxonEventProcessed>,
 ) -> impl Stream<Item = GetEventsRequest> {
     stream! {
@@ -99,8 +951,8 @@ fn create_output_stream(
             number_of_permits: permits,
             client_id: axon_server_handle.client_id.clone(),
             component_name: axon_server_handle.display_name.clone(),
-            processor: "Event Processor".to_string(),
-            blacklist: Vec::new(),
+            processor: processor_name,
+            blacklist,
             force_read_from_leader: false,
         };
         yield request.clone();
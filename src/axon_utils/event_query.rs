@@ -1,29 +1,88 @@
 use super::AxonServerHandle;
 use crate::axon_server::event::event_store_client::EventStoreClient;
-use crate::axon_server::event::{Event, GetAggregateEventsRequest};
-use anyhow::Result;
+use crate::axon_server::event::{query_events_response, query_value};
+use crate::axon_server::event::{
+    Event, GetAggregateEventsRequest, QueryEventsRequest, QueryEventsResponse, QueryValue,
+    RowResponse,
+};
+use anyhow::{anyhow, Result};
+use async_stream::stream;
+use futures_core::stream::Stream;
+use log::debug;
+use std::pin::Pin;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tonic::transport::Channel;
+use tonic::Request;
 
-/// Fetch all events for a given aggregate.
+/// A value in the result of an ad-hoc event store query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventStoreValue {
+    Text(String),
+    Number(i64),
+    Boolean(bool),
+    Double(f64),
+    Null,
+}
+
+/// A row in the result of an ad-hoc event store query.
+///
+/// Fields:
+/// * `columns`: The values of the row in the order of the columns of the result. Columns without a value are `Null`.
+/// * `id_values`: The values that identify the row. A row with the same identifying values replaces an earlier one.
+/// * `sort_values`: The values that determine the position of the row in the result.
+/// * `live`: Whether the row was produced after all stored events were processed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventStoreRow {
+    pub columns: Vec<(String, EventStoreValue)>,
+    pub id_values: Vec<EventStoreValue>,
+    pub sort_values: Vec<EventStoreValue>,
+    pub live: bool,
+}
+
+impl EventStoreRow {
+    /// Returns the value of the column with the given name, or `None` if the result has no such column.
+    pub fn get(&self, column: &str) -> Option<&EventStoreValue> {
+        self.columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The stream of rows that results from an ad-hoc event store query.
+pub type EventStoreRows = Pin<Box<dyn Stream<Item = Result<EventStoreRow>> + Send>>;
+
+/// Fetch all events for a given aggregate, with their payloads upcasted to the latest revision.
 pub async fn query_events(
     axon_server_handle: &AxonServerHandle,
     aggregate_identifier: &str,
 ) -> Result<Vec<Event>> {
-    let axon_server_handle = axon_server_handle.clone();
-    let conn = axon_server_handle.conn;
-    let mut client = EventStoreClient::new(conn);
-    query_events_from_client(&mut client, aggregate_identifier).await
+    let mut client = axon_server_handle.event_store_client();
+    let events = query_events_from_client(&mut client, aggregate_identifier).await?;
+    events
+        .into_iter()
+        .map(|event| axon_server_handle.upcasters.upcast_event(event))
+        .collect()
 }
 
 /// Fetch all events for a given aggregate.
 pub async fn query_events_from_client(
     client: &mut EventStoreClient<Channel>,
     aggregate_identifier: &str,
+) -> Result<Vec<Event>> {
+    query_events_from_sequence(client, aggregate_identifier, 0).await
+}
+
+/// Fetch the events for a given aggregate, starting at a given sequence number.
+pub async fn query_events_from_sequence(
+    client: &mut EventStoreClient<Channel>,
+    aggregate_identifier: &str,
+    initial_sequence: i64,
 ) -> Result<Vec<Event>> {
     let request = GetAggregateEventsRequest {
         aggregate_id: aggregate_identifier.to_string(),
         allow_snapshots: false,
-        initial_sequence: 0,
+        initial_sequence,
         max_sequence: std::i64::MAX,
         min_token: 0,
     };
@@ -34,3 +93,202 @@ pub async fn query_events_from_client(
     }
     Ok(result)
 }
+
+/// Runs an ad-hoc query against the event store, in the query language of the AxonServer UI.
+///
+/// When `live` is false, the stream ends after all stored events were processed. Otherwise, it keeps
+/// producing rows for new events until it is dropped.
+pub async fn query_event_store(
+    axon_server_handle: &AxonServerHandle,
+    query: &str,
+    live: bool,
+) -> Result<EventStoreRows> {
+    let mut client = axon_server_handle.event_store_client();
+    query_event_store_from_client(&mut client, query, live).await
+}
+
+/// Runs an ad-hoc query against the event store using the given client.
+pub async fn query_event_store_from_client(
+    client: &mut EventStoreClient<Channel>,
+    query: &str,
+    live: bool,
+) -> Result<EventStoreRows> {
+    let permits_batch_size: i64 = 100;
+    let request = QueryEventsRequest {
+        query: query.to_string(),
+        number_of_permits: permits_batch_size * 2,
+        live_events: live,
+        force_read_from_leader: false,
+    };
+    let (tx, rx): (Sender<i64>, Receiver<i64>) = channel(10);
+    let outbound = create_query_events_output_stream(request, rx);
+    let response = client.query_events(Request::new(outbound)).await?;
+    let mut inbound = response.into_inner();
+
+    let rows = stream! {
+        let mut permits = permits_batch_size * 2;
+        let mut columns: Vec<String> = Vec::new();
+        let mut files_completed = false;
+        loop {
+            if permits <= permits_batch_size {
+                debug!("Query events: send more flow-control permits: amount: {:?}", permits_batch_size);
+                if tx.send(permits_batch_size).await.is_err() {
+                    break;
+                }
+                permits += permits_batch_size;
+            }
+            match inbound.message().await {
+                Ok(Some(QueryEventsResponse { data: Some(data) })) => match data {
+                    query_events_response::Data::Columns(columns_response) => {
+                        debug!("Query events: columns: {:?}", columns_response.column);
+                        columns = columns_response.column;
+                    }
+                    query_events_response::Data::Row(row) => {
+                        // Only rows consume flow-control permits.
+                        permits -= 1;
+                        yield Ok(convert_row(&columns, row, files_completed));
+                    }
+                    query_events_response::Data::FilesCompleted(_) => {
+                        debug!("Query events: all stored events processed");
+                        if !live {
+                            break;
+                        }
+                        files_completed = true;
+                    }
+                },
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    yield Err(anyhow!(e));
+                    break;
+                }
+            }
+        }
+    };
+    Ok(Box::pin(rows))
+}
+
+fn create_query_events_output_stream(
+    request: QueryEventsRequest,
+    mut rx: Receiver<i64>,
+) -> impl Stream<Item = QueryEventsRequest> {
+    stream! {
+        yield request.clone();
+        while let Some(permits) = rx.recv().await {
+            yield QueryEventsRequest {
+                number_of_permits: permits,
+                ..request.clone()
+            };
+        }
+    }
+}
+
+fn convert_row(columns: &[String], mut row: RowResponse, live: bool) -> EventStoreRow {
+    let columns = columns
+        .iter()
+        .map(|column| {
+            let value = row.values.remove(column);
+            (column.clone(), convert_optional_value(value))
+        })
+        .collect();
+    EventStoreRow {
+        columns,
+        id_values: row.id_values.into_iter().map(convert_value).collect(),
+        sort_values: row.sort_values.into_iter().map(convert_value).collect(),
+        live,
+    }
+}
+
+fn convert_optional_value(value: Option<QueryValue>) -> EventStoreValue {
+    value.map(convert_value).unwrap_or(EventStoreValue::Null)
+}
+
+fn convert_value(value: QueryValue) -> EventStoreValue {
+    match value.data {
+        Some(query_value::Data::TextValue(text)) => EventStoreValue::Text(text),
+        Some(query_value::Data::NumberValue(number)) => EventStoreValue::Number(number),
+        Some(query_value::Data::BooleanValue(boolean)) => EventStoreValue::Boolean(boolean),
+        Some(query_value::Data::DoubleValue(double)) => EventStoreValue::Double(double),
+        None => EventStoreValue::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn text(text: &str) -> QueryValue {
+        QueryValue {
+            data: Some(query_value::Data::TextValue(text.to_string())),
+        }
+    }
+
+    fn number(number: i64) -> QueryValue {
+        QueryValue {
+            data: Some(query_value::Data::NumberValue(number)),
+        }
+    }
+
+    #[test]
+    fn values_are_converted() {
+        assert_eq!(
+            convert_value(text("a")),
+            EventStoreValue::Text("a".to_string())
+        );
+        assert_eq!(convert_value(number(42)), EventStoreValue::Number(42));
+        let boolean = QueryValue {
+            data: Some(query_value::Data::BooleanValue(true)),
+        };
+        assert_eq!(convert_value(boolean), EventStoreValue::Boolean(true));
+        let double = QueryValue {
+            data: Some(query_value::Data::DoubleValue(0.5)),
+        };
+        assert_eq!(convert_value(double), EventStoreValue::Double(0.5));
+        assert_eq!(
+            convert_value(QueryValue { data: None }),
+            EventStoreValue::Null
+        );
+        assert_eq!(convert_optional_value(None), EventStoreValue::Null);
+    }
+
+    #[test]
+    fn row_follows_the_order_of_the_columns() {
+        let columns = vec![
+            "aggregateId".to_string(),
+            "count".to_string(),
+            "missing".to_string(),
+        ];
+        let mut values = HashMap::new();
+        values.insert("count".to_string(), number(3));
+        values.insert("aggregateId".to_string(), text("greeter-1"));
+        values.insert("ignored".to_string(), text("not a column"));
+        let row = RowResponse {
+            id_values: vec![text("greeter-1")],
+            sort_values: vec![number(7)],
+            values,
+        };
+
+        let row = convert_row(&columns, row, true);
+
+        assert_eq!(
+            row,
+            EventStoreRow {
+                columns: vec![
+                    (
+                        "aggregateId".to_string(),
+                        EventStoreValue::Text("greeter-1".to_string())
+                    ),
+                    ("count".to_string(), EventStoreValue::Number(3)),
+                    ("missing".to_string(), EventStoreValue::Null),
+                ],
+                id_values: vec![EventStoreValue::Text("greeter-1".to_string())],
+                sort_values: vec![EventStoreValue::Number(7)],
+                live: true,
+            }
+        );
+        assert_eq!(row.get("count"), Some(&EventStoreValue::Number(3)));
+        assert_eq!(row.get("missing"), Some(&EventStoreValue::Null));
+        assert_eq!(row.get("ignored"), None);
+    }
+}
@@ -0,0 +1,164 @@
+use super::error::AxonError;
+use super::metadata::{empty_meta_data, MetaData};
+use super::{AxonServerHandle, VecU8Message};
+use crate::axon_server::event::event_scheduler_client::EventSchedulerClient;
+use crate::axon_server::event::{
+    CancelScheduledEventRequest, Event, RescheduleEventRequest, ScheduleEventRequest,
+};
+use crate::axon_server::SerializedObject;
+use anyhow::Result;
+use log::debug;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+/// Token that identifies an event that was scheduled for publication.
+///
+/// The token can be stored (e.g., in a query model) to reschedule or cancel the event later.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScheduleToken {
+    pub token: String,
+}
+
+impl Display for ScheduleToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.token)
+    }
+}
+
+impl From<String> for ScheduleToken {
+    fn from(token: String) -> Self {
+        ScheduleToken { token }
+    }
+}
+
+/// Schedules an event for publication at the given instant.
+pub async fn schedule_event(
+    axon_server_handle: &AxonServerHandle,
+    event_type: &str,
+    event: &(dyn VecU8Message + Sync),
+    instant: SystemTime,
+) -> Result<ScheduleToken> {
+    let payload = encode_payload(event_type, event)?;
+    let mut client = axon_server_handle.event_scheduler_client();
+    schedule_serialized_event(&mut client, payload, empty_meta_data(), instant).await
+}
+
+/// Schedules an event for publication after the given delay.
+pub async fn schedule_event_after(
+    axon_server_handle: &AxonServerHandle,
+    event_type: &str,
+    event: &(dyn VecU8Message + Sync),
+    delay: Duration,
+) -> Result<ScheduleToken> {
+    schedule_event(
+        axon_server_handle,
+        event_type,
+        event,
+        SystemTime::now() + delay,
+    )
+    .await
+}
+
+/// Cancels a scheduled event and schedules the given event at the given instant in its place.
+///
+/// Returns the token of the newly scheduled event.
+pub async fn reschedule_event(
+    axon_server_handle: &AxonServerHandle,
+    token: &ScheduleToken,
+    event_type: &str,
+    event: &(dyn VecU8Message + Sync),
+    instant: SystemTime,
+) -> Result<ScheduleToken> {
+    let request = RescheduleEventRequest {
+        token: token.token.clone(),
+        instant: to_millis(instant)?,
+        event: Some(create_event(
+            encode_payload(event_type, event)?,
+            empty_meta_data(),
+            instant,
+        )?),
+    };
+    debug!(
+        "Reschedule event: {:?}: {:?}: {:?}",
+        token, event_type, instant
+    );
+    let mut client = axon_server_handle.event_scheduler_client();
+    let response = client.reschedule_event(request).await?;
+    Ok(response.into_inner().token.into())
+}
+
+/// Cancels the publication of a scheduled event. Does nothing if the event was already published.
+pub async fn cancel_scheduled_event(
+    axon_server_handle: &AxonServerHandle,
+    token: &ScheduleToken,
+) -> Result<()> {
+    let mut client = axon_server_handle.event_scheduler_client();
+    cancel_scheduled_event_with_client(&mut client, token).await
+}
+
+/// Schedules an event with the given payload and metadata for publication at the given instant.
+pub(crate) async fn schedule_serialized_event(
+    client: &mut EventSchedulerClient<Channel>,
+    payload: SerializedObject,
+    meta_data: MetaData,
+    instant: SystemTime,
+) -> Result<ScheduleToken> {
+    debug!("Schedule event: {:?}: {:?}", payload.r#type, instant);
+    let request = ScheduleEventRequest {
+        instant: to_millis(instant)?,
+        event: Some(create_event(payload, meta_data, instant)?),
+    };
+    let response = client.schedule_event(request).await?;
+    Ok(response.into_inner().token.into())
+}
+
+pub(crate) async fn cancel_scheduled_event_with_client(
+    client: &mut EventSchedulerClient<Channel>,
+    token: &ScheduleToken,
+) -> Result<()> {
+    let request = CancelScheduledEventRequest {
+        token: token.token.clone(),
+    };
+    debug!("Cancel scheduled event: {:?}", token);
+    let ack = client.cancel_scheduled_event(request).await?.into_inner();
+    if let Some(error_message) = ack.error {
+        return Err(AxonError::from_error_message(error_message).into());
+    }
+    Ok(())
+}
+
+pub(crate) fn encode_payload(
+    payload_type: &str,
+    payload: &(dyn VecU8Message + Sync),
+) -> Result<SerializedObject> {
+    let mut buf = Vec::new();
+    payload.encode_u8(&mut buf)?;
+    Ok(SerializedObject {
+        r#type: payload_type.to_string(),
+        revision: "".to_string(),
+        data: buf,
+    })
+}
+
+fn create_event(
+    payload: SerializedObject,
+    meta_data: MetaData,
+    instant: SystemTime,
+) -> Result<Event> {
+    Ok(Event {
+        message_identifier: format!("{}", Uuid::new_v4()),
+        timestamp: to_millis(instant)?,
+        aggregate_identifier: "".to_string(),
+        aggregate_sequence_number: 0,
+        aggregate_type: "".to_string(),
+        payload: Some(payload),
+        meta_data,
+        snapshot: false,
+    })
+}
+
+pub(crate) fn to_millis(instant: SystemTime) -> Result<i64> {
+    Ok(instant.duration_since(UNIX_EPOCH)?.as_millis() as i64)
+}
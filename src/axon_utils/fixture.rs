@@ -0,0 +1,43 @@
+use super::error::AxonError;
+use super::event_scheduler::encode_payload;
+use super::VecU8Message;
+use crate::axon_server::SerializedObject;
+
+/// Panics unless the actual payloads have exactly the expected types and encoded data, in order.
+///
+/// `what` names the payloads in the panic message, e.g., "events".
+pub(crate) fn assert_payloads(
+    actual: &[SerializedObject],
+    expected: &[(&str, &(dyn VecU8Message + Sync))],
+    what: &str,
+) {
+    let expected: Vec<(String, Vec<u8>)> = expected
+        .iter()
+        .map(|(payload_type, payload)| {
+            let payload = encode_payload(payload_type, *payload)
+                .unwrap_or_else(|e| panic!("Could not encode expected {}: {:?}", what, e));
+            (payload.r#type, payload.data)
+        })
+        .collect();
+    let actual: Vec<(String, Vec<u8>)> = actual
+        .iter()
+        .map(|payload| (payload.r#type.clone(), payload.data.clone()))
+        .collect();
+    assert_eq!(actual, expected, "Unexpected {}", what);
+}
+
+/// Panics if handling the message with the given name (e.g., "command") failed.
+pub(crate) fn assert_success(error: &Option<anyhow::Error>, message: &str) {
+    if let Some(error) = error {
+        panic!("Expected success, but the {} failed: {:?}", message, error);
+    }
+}
+
+/// Panics unless handling the message with the given name (e.g., "command") failed. Returns the error,
+/// classified as it would be reported to the sender of the message.
+pub(crate) fn assert_error(error: &Option<anyhow::Error>, message: &str) -> AxonError {
+    match error {
+        Some(error) => AxonError::from(error),
+        None => panic!("Expected an error, but the {} succeeded", message),
+    }
+}
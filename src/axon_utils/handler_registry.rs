@@ -60,6 +60,18 @@ pub struct TheHandlerRegistry<P: Send, W: Clone> {
     pub handlers: HashMap<String, Box<dyn SubscriptionHandle<P, W>>>,
 }
 
+impl<P: Send, W: Clone> Clone for TheHandlerRegistry<P, W> {
+    fn clone(&self) -> Self {
+        TheHandlerRegistry {
+            handlers: self
+                .handlers
+                .iter()
+                .map(|(name, handle)| (name.clone(), handle.box_clone()))
+                .collect(),
+        }
+    }
+}
+
 impl<P: Send + Clone, W: Clone + 'static> HandlerRegistry<P, W> for TheHandlerRegistry<P, W> {
     fn register(&mut self, applicator: &'static (dyn Fn(&mut Self) -> Result<()>)) -> Result<()> {
         applicator(self)
@@ -0,0 +1,65 @@
+use crate::axon_server::common::meta_data_value::Data;
+use crate::axon_server::common::MetaDataValue;
+use std::collections::HashMap;
+
+/// The metadata of a command, event or query.
+pub type MetaData = HashMap<String, MetaDataValue>;
+
+/// Key of the metadata entry that identifies the request that started a chain of messages.
+pub const CORRELATION_ID: &str = "correlation_id";
+
+/// Key of the metadata entry that identifies the message that directly caused a message.
+pub const CAUSATION_ID: &str = "causation_id";
+
+/// Creates an empty metadata map.
+pub fn empty_meta_data() -> MetaData {
+    HashMap::new()
+}
+
+/// Creates a metadata value that holds a text.
+pub fn text_value(text: &str) -> MetaDataValue {
+    MetaDataValue {
+        data: Some(Data::TextValue(text.to_string())),
+    }
+}
+
+/// Creates a metadata value that holds a number.
+pub fn number_value(number: i64) -> MetaDataValue {
+    MetaDataValue {
+        data: Some(Data::NumberValue(number)),
+    }
+}
+
+/// Returns the text of a metadata entry, or `None` if it is missing or does not hold a text.
+pub fn get_text<'a>(meta_data: &'a MetaData, key: &str) -> Option<&'a str> {
+    match meta_data.get(key) {
+        Some(MetaDataValue {
+            data: Some(Data::TextValue(text)),
+        }) => Some(text),
+        _ => None,
+    }
+}
+
+/// Returns the number of a metadata entry, or `None` if it is missing or does not hold a number.
+pub fn get_number(meta_data: &MetaData, key: &str) -> Option<i64> {
+    match meta_data.get(key) {
+        Some(MetaDataValue {
+            data: Some(Data::NumberValue(number)),
+        }) => Some(*number),
+        _ => None,
+    }
+}
+
+/// Derives the metadata for messages that are caused by the message with the given identifier and metadata.
+///
+/// All entries are inherited, except that the correlation id is set to the one of the causing message (the
+/// first message in a chain is its own correlation id) and the causation id refers to the causing message.
+pub(crate) fn caused_by(message_identifier: &str, meta_data: &MetaData) -> MetaData {
+    let correlation_id = get_text(meta_data, CORRELATION_ID)
+        .unwrap_or(message_identifier)
+        .to_string();
+    let mut result = meta_data.clone();
+    result.insert(CORRELATION_ID.to_string(), text_value(&correlation_id));
+    result.insert(CAUSATION_ID.to_string(), text_value(message_identifier));
+    result
+}
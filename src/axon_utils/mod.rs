@@ -16,39 +16,102 @@ use crate::intellij_work_around::Debuggable;
 use anyhow::{anyhow, Result};
 use log::debug;
 use prost::Message;
+use std::time::Duration;
 use tonic::transport::Channel;
 
+mod aggregate_fixture;
 mod command_submit;
 mod command_worker;
 mod connection;
+mod deadline;
+mod dispatch;
+mod error;
 mod event_processor;
 mod event_query;
+mod event_scheduler;
+mod fixture;
 mod handler_registry;
+mod metadata;
+mod projection_fixture;
 mod query_processor;
 mod query_submit;
+mod query_update;
+mod response_type;
+mod routing;
+mod saga;
+mod segment;
+mod snapshot;
+mod supervisor;
+mod upcaster;
 
 pub use crate::axon_server::SerializedObject;
+pub use aggregate_fixture::{aggregate_test_fixture, AggregateTestFixture, AggregateTestResult};
 pub use command_submit::init as init_command_sender;
-pub use command_worker::command_worker;
 pub use command_worker::{
-    create_aggregate_definition, emit, emit_events, emit_events_and_response,
+    command_worker, command_worker_settings, command_worker_with_settings, CommandWorkerSettings,
+};
+pub use command_worker::{
+    create_aggregate_definition, emit, emit_events, emit_events_and_response, emit_with_revision,
     empty_aggregate_registry, AggregateContext, AggregateContextTrait, AggregateDefinition,
-    AggregateRegistry, EmitApplicableEventsAndResponse, TheAggregateRegistry,
+    AggregateRegistry, ConcurrencyError, EmitApplicableEventsAndResponse, TheAggregateRegistry,
 };
 pub use connection::platform_worker;
-pub use connection::wait_for_server;
-pub use event_processor::{event_processor, TokenStore};
-pub use event_query::query_events;
+pub use connection::{
+    axon_server_handle_builder, wait_for_cluster, wait_for_server, AxonServerHandleBuilder,
+    ConnectionSettings,
+};
+pub use deadline::{
+    deadline_command_name, deadline_dispatcher, ScheduledDeadline, DEADLINE_EVENT_TYPE,
+};
+pub use dispatch::{dispatch_options, DispatchOptions};
+pub use error::{business_error, AxonError, ErrorDetails, MessageKind};
+pub use event_processor::{
+    event_processor, is_replaying, reset_event_processor, segmented_event_processor,
+    EventProcessorRegistry, ResetPosition, TokenStore,
+};
+pub use event_query::{
+    query_event_store, query_events, EventStoreRow, EventStoreRows, EventStoreValue,
+};
+pub use event_scheduler::{
+    cancel_scheduled_event, reschedule_event, schedule_event, schedule_event_after, ScheduleToken,
+};
 pub use handler_registry::empty_handler_registry;
 pub use handler_registry::{HandlerRegistry, TheHandlerRegistry};
-pub use query_processor::{query_processor, QueryContext, QueryResult};
+pub use metadata::{
+    empty_meta_data, get_number, get_text, number_value, text_value, MetaData, CAUSATION_ID,
+    CORRELATION_ID,
+};
+pub use projection_fixture::{projection_test_fixture, ProjectionTestFixture, QueryTestResult};
+pub use query_processor::{
+    query_processor, query_processor_settings, query_processor_with_settings,
+    query_result_with_payloads, QueryContext, QueryProcessorSettings, QueryResult,
+};
+pub use query_submit::SubscriptionQueryResult;
+pub use query_update::QueryUpdateEmitter;
+pub use response_type::query_handler_name;
+pub use routing::{empty_routing_key_registry, RoutingKeyRegistry};
+pub use saga::{
+    create_saga_definition, saga_processor, AssociationValue, SagaContext, SagaDefinition,
+    SagaEventHandlerRegistry, SagaInstance, SagaRepository,
+};
+pub use segment::Segment;
+pub use snapshot::{snapshot_every_n_events, snapshot_when, SnapshotPolicy, SnapshotTrigger};
+pub use supervisor::{supervise, supervised_command_worker, Backoff, ReconnectSignal};
+pub use upcaster::{empty_upcaster_registry, Upcaster, UpcasterRegistry};
 
 /// A handle for AxonServer.
 #[derive(Debug, Clone)]
 pub struct AxonServerHandle {
     pub display_name: String,
     pub client_id: String,
+    pub connection_settings: ConnectionSettings,
     pub conn: Channel,
+    pub event_processors: EventProcessorRegistry,
+    pub query_update_emitter: QueryUpdateEmitter,
+    pub reconnect_signal: ReconnectSignal,
+    pub upcasters: UpcasterRegistry,
+    pub routing_keys: RoutingKeyRegistry,
+    pub dispatch_options: DispatchOptions,
 }
 
 /// Describes a message that can be serialized to a mutable `Vec<u8>`.
@@ -72,23 +135,114 @@ where
 }
 
 /// Trait that is implemented by an object that can be used to send commands to AxonServer.
+///
+/// Implementations provide `send_command_with_options`; the other methods delegate to it.
 #[tonic::async_trait]
 pub trait CommandSink {
+    #[allow(clippy::redundant_allocation)]
     async fn send_command(
         &self,
         command_type: &str,
         command: Box<&(dyn VecU8Message + Sync)>,
+    ) -> Result<Option<SerializedObject>> {
+        self.send_command_with_meta_data(command_type, *command, empty_meta_data())
+            .await
+    }
+
+    /// Sends a command with the given metadata, e.g., a correlation id.
+    async fn send_command_with_meta_data(
+        &self,
+        command_type: &str,
+        command: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+    ) -> Result<Option<SerializedObject>> {
+        self.send_command_with_options(command_type, command, meta_data, dispatch_options())
+            .await
+    }
+
+    /// Sends a command with the given metadata and options, e.g., a timeout.
+    async fn send_command_with_options(
+        &self,
+        command_type: &str,
+        command: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
     ) -> Result<Option<SerializedObject>>;
 }
 
 /// Trait that is implemented by an object that can be used to send queries to AxonServer.
+///
+/// Implementations provide `send_query_with_options` and `subscribe_query_with_options`; the other methods
+/// delegate to them.
 #[tonic::async_trait]
 pub trait QuerySink {
+    #[allow(clippy::redundant_allocation)]
     async fn send_query<'a>(
         &self,
         query_type: &str,
         query: Box<&(dyn VecU8Message + Sync)>,
+    ) -> Result<Vec<SerializedObject>> {
+        self.send_query_with_meta_data(query_type, *query, empty_meta_data())
+            .await
+    }
+
+    /// Sends a query with the given metadata.
+    async fn send_query_with_meta_data(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+    ) -> Result<Vec<SerializedObject>> {
+        self.send_query_with_options(query_type, query, meta_data, dispatch_options())
+            .await
+    }
+
+    /// Sends a query with the given metadata and options, e.g., a timeout or the number of expected results.
+    async fn send_query_with_options(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
     ) -> Result<Vec<SerializedObject>>;
+
+    /// Sends a query to all query processors that can handle it and collects their results.
+    ///
+    /// Returns the results that arrived within `timeout`, but no more than `expected_results`
+    /// (use -1 for unlimited). Error responses of individual query processors are skipped.
+    async fn scatter_gather(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        expected_results: i64,
+        timeout: Duration,
+    ) -> Result<Vec<SerializedObject>> {
+        let options = dispatch_options()
+            .with_nr_of_results(expected_results)
+            .with_timeout(timeout);
+        self.send_query_with_options(query_type, query, empty_meta_data(), options)
+            .await
+    }
+
+    /// Sends a subscription query and returns the initial result together with a stream of updates.
+    async fn subscribe_query(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+    ) -> Result<SubscriptionQueryResult> {
+        self.subscribe_query_with_options(query_type, query, empty_meta_data(), dispatch_options())
+            .await
+    }
+
+    /// Sends a subscription query with the given metadata and options, e.g., the response type of the
+    /// initial result, or a timeout for the initial result.
+    async fn subscribe_query_with_options(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
+    ) -> Result<SubscriptionQueryResult>;
 }
 
 /// Converts a `prost::Message` to an Axon `SerializedObject`.
@@ -0,0 +1,482 @@
+use super::error::AxonError;
+use super::event_processor::{handle_event, TokenStore};
+use super::event_scheduler::encode_payload;
+use super::fixture::{assert_error, assert_payloads, assert_success};
+use super::handler_registry::{empty_handler_registry, TheHandlerRegistry};
+use super::metadata::{empty_meta_data, MetaData};
+use super::query_processor::{handle_query, QueryContext, QueryResult};
+use super::response_type::encode_response_type;
+use super::segment::Segment;
+use super::upcaster::{empty_upcaster_registry, UpcasterRegistry};
+use super::VecU8Message;
+use crate::axon_server::query::QueryRequest;
+use crate::axon_server::SerializedObject;
+use anyhow::Result;
+use prost::Message;
+use uuid::Uuid;
+
+/// Given-when-then test fixture for the event handlers and query handlers of a query model.
+///
+/// The fixture applies a list of given events to the query model with the event handlers, the way an event
+/// processor would, and then handles a query with the query handlers, without connecting to AxonServer.
+/// Events get consecutive tokens, starting right after the token that the query model retrieves:
+///
+/// ```ignore
+/// let result = projection_test_fixture(query_model, event_handler_registry)
+///     .with_query_handlers(query_handler_registry)
+///     .given("GreetedEvent", &GreetedEvent { message: Some(greeting) })
+///     .await?
+///     .expect_stored_tokens(&[0])
+///     .when_query("SearchQuery", &SearchQuery { query: "hello".to_string() })
+///     .await;
+/// let greetings: Vec<Greeting> = result.payloads();
+/// ```
+pub struct ProjectionTestFixture<Q: TokenStore + QueryContext + Send + Sync + Clone + 'static> {
+    query_model: Q,
+    event_handler_registry: TheHandlerRegistry<Q, Option<Q>>,
+    query_handler_registry: TheHandlerRegistry<Q, QueryResult>,
+    upcasters: UpcasterRegistry,
+    meta_data: MetaData,
+    replaying: bool,
+    next_token: Option<i64>,
+    stored_tokens: Vec<i64>,
+}
+
+/// Creates a test fixture for the given query model and its event handlers.
+pub fn projection_test_fixture<Q: TokenStore + QueryContext + Send + Sync + Clone + 'static>(
+    query_model: Q,
+    event_handler_registry: TheHandlerRegistry<Q, Option<Q>>,
+) -> ProjectionTestFixture<Q> {
+    ProjectionTestFixture {
+        query_model,
+        event_handler_registry,
+        query_handler_registry: empty_handler_registry(),
+        upcasters: empty_upcaster_registry(),
+        meta_data: empty_meta_data(),
+        replaying: false,
+        next_token: None,
+        stored_tokens: Vec::new(),
+    }
+}
+
+impl<Q: TokenStore + QueryContext + Send + Sync + Clone + 'static> ProjectionTestFixture<Q> {
+    /// Sets the query handlers that are used by `when_query`.
+    pub fn with_query_handlers(
+        mut self,
+        query_handler_registry: TheHandlerRegistry<Q, QueryResult>,
+    ) -> Self {
+        self.query_handler_registry = query_handler_registry;
+        self
+    }
+
+    /// Upcasts the given events with the given registry before they are applied to the query model.
+    pub fn with_upcasters(mut self, upcasters: UpcasterRegistry) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// Sets the metadata of the queries that are handled by this fixture.
+    pub fn with_meta_data(mut self, meta_data: MetaData) -> Self {
+        self.meta_data = meta_data;
+        self
+    }
+
+    /// Applies the events that are given after this call as if they were replayed after a reset of the
+    /// event processor (see `is_replaying`).
+    pub fn with_replaying(mut self, replaying: bool) -> Self {
+        self.replaying = replaying;
+        self
+    }
+
+    /// Applies an event to the query model and stores its token.
+    pub async fn given(self, event_type: &str, event: &(dyn VecU8Message + Sync)) -> Result<Self> {
+        self.given_serialized(encode_payload(event_type, event)?)
+            .await
+    }
+
+    /// Applies an event that is already serialized, e.g., with an older revision, to the query model and
+    /// stores its token.
+    pub async fn given_serialized(mut self, event: SerializedObject) -> Result<Self> {
+        let token = match self.next_token {
+            Some(token) => token,
+            None => self.query_model.retrieve_token().await.unwrap_or(-1) + 1,
+        };
+        let event = self.upcasters.upcast(event)?;
+        handle_event(
+            event,
+            empty_meta_data(),
+            &self.event_handler_registry,
+            self.query_model.clone(),
+            self.replaying,
+        )
+        .await?;
+        self.query_model
+            .store_segment_token(&Segment::ROOT, token)
+            .await;
+        self.stored_tokens.push(token);
+        self.next_token = Some(token + 1);
+        Ok(self)
+    }
+
+    /// Returns the query model, e.g., to inspect its state.
+    pub fn query_model(&self) -> &Q {
+        &self.query_model
+    }
+
+    /// Returns the tokens that were stored in the token store of the query model, in order.
+    pub fn stored_tokens(&self) -> &[i64] {
+        &self.stored_tokens
+    }
+
+    /// Panics unless exactly the given tokens were stored in the token store of the query model, in order.
+    pub fn expect_stored_tokens(self, tokens: &[i64]) -> Self {
+        assert_eq!(self.stored_tokens, tokens, "Unexpected stored tokens");
+        self
+    }
+
+    /// Handles a query against the query model.
+    pub async fn when_query(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+    ) -> QueryTestResult {
+        self.handle(query_type, query, None).await
+    }
+
+    /// Handles a query against the query model with the handler for the given response type.
+    pub async fn when_query_for_response_type(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        response_type: &str,
+    ) -> QueryTestResult {
+        self.handle(query_type, query, Some(response_type)).await
+    }
+
+    async fn handle(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        response_type: Option<&str>,
+    ) -> QueryTestResult {
+        match self.try_handle(query_type, query, response_type).await {
+            Ok(query_result) => QueryTestResult {
+                payloads: query_result
+                    .map(QueryResult::into_payloads)
+                    .unwrap_or_default(),
+                error: None,
+            },
+            Err(error) => QueryTestResult {
+                payloads: Vec::new(),
+                error: Some(error),
+            },
+        }
+    }
+
+    async fn try_handle(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        response_type: Option<&str>,
+    ) -> Result<Option<QueryResult>> {
+        let query = QueryRequest {
+            message_identifier: format!("{}", Uuid::new_v4()),
+            query: query_type.to_string(),
+            timestamp: 0,
+            payload: Some(encode_payload(query_type, query)?),
+            meta_data: self.meta_data.clone(),
+            response_type: response_type.map(encode_response_type),
+            processing_instructions: Vec::new(),
+            client_id: "".to_string(),
+            component_name: "".to_string(),
+        };
+        handle_query(
+            &query,
+            &self.query_handler_registry,
+            self.query_model.clone(),
+        )
+        .await
+    }
+}
+
+/// The outcome of handling a query with a `ProjectionTestFixture`.
+///
+/// Fields:
+/// * `payloads`: The payloads of the result of the query handler, in order.
+/// * `error`: The error of the query handler. If it is set, there are no payloads.
+#[derive(Debug)]
+pub struct QueryTestResult {
+    pub payloads: Vec<SerializedObject>,
+    pub error: Option<anyhow::Error>,
+}
+
+impl QueryTestResult {
+    /// Panics unless the query succeeded with exactly the given payloads, in order.
+    ///
+    /// Payloads are compared by type and encoded data.
+    pub fn expect_payloads(&self, expected: &[(&str, &(dyn VecU8Message + Sync))]) -> &Self {
+        assert_success(&self.error, "query");
+        assert_payloads(&self.payloads, expected, "payloads");
+        self
+    }
+
+    /// Panics unless the query succeeded without a result.
+    pub fn expect_no_payload(&self) -> &Self {
+        self.expect_payloads(&[])
+    }
+
+    /// Panics unless the query succeeded with exactly one payload. Returns the decoded payload.
+    pub fn payload<T: Message + Default>(&self) -> T {
+        assert_success(&self.error, "query");
+        match self.payloads.as_slice() {
+            [payload] => decode(payload),
+            payloads => panic!("Expected a single payload, but got: {:?}", payloads.len()),
+        }
+    }
+
+    /// Panics unless the query succeeded. Returns the decoded payloads, in order.
+    pub fn payloads<T: Message + Default>(&self) -> Vec<T> {
+        assert_success(&self.error, "query");
+        self.payloads.iter().map(decode).collect()
+    }
+
+    /// Panics unless the query failed. Returns the error that the sender of the query would get.
+    pub fn expect_error(&self) -> AxonError {
+        assert_error(&self.error, "query")
+    }
+}
+
+fn decode<T: Message + Default>(payload: &SerializedObject) -> T {
+    T::decode(payload.data.as_slice())
+        .unwrap_or_else(|e| panic!("Could not decode payload: {:?}: {:?}", payload.r#type, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axon_utils::{
+        axon_serialize, business_error, get_text, is_replaying, query_result_with_payloads,
+        text_value, HandlerRegistry,
+    };
+    use anyhow::anyhow;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, PartialEq, Message)]
+    struct GreetedEvent {
+        #[prost(string, tag = "1")]
+        message: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct SearchQuery {
+        #[prost(string, tag = "1")]
+        keyword: String,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Greeting {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(bool, tag = "2")]
+        replayed: bool,
+    }
+
+    /// Greetings that were handled, and every token that was stored for them.
+    #[derive(Default)]
+    struct GreetingsState {
+        greetings: Vec<Greeting>,
+        tokens: Vec<i64>,
+    }
+
+    /// Query model whose clones share one state, so that tests can inspect what the handlers did.
+    #[derive(Clone, Default)]
+    struct Greetings(Arc<Mutex<GreetingsState>>);
+
+    impl Greetings {
+        /// Creates a query model that already processed the event with the given token.
+        fn processed_up_to(token: i64) -> Self {
+            let greetings = Greetings::default();
+            greetings.0.lock().unwrap().tokens.push(token);
+            greetings
+        }
+    }
+
+    #[tonic::async_trait]
+    impl TokenStore for Greetings {
+        async fn store_token(&self, token: i64) {
+            self.0.lock().unwrap().tokens.push(token);
+        }
+
+        async fn retrieve_token(&self) -> Result<i64> {
+            self.0
+                .lock()
+                .unwrap()
+                .tokens
+                .last()
+                .copied()
+                .ok_or_else(|| anyhow!("No events processed yet"))
+        }
+    }
+
+    impl QueryContext for Greetings {}
+
+    async fn handle_greeted(event: GreetedEvent, greetings: Greetings) -> Result<()> {
+        greetings.0.lock().unwrap().greetings.push(Greeting {
+            message: event.message,
+            replayed: is_replaying(),
+        });
+        Ok(())
+    }
+
+    /// Returns the greetings that contain the keyword, and the greeting of the user in the metadata, if any.
+    async fn handle_search(
+        query: SearchQuery,
+        greetings: Greetings,
+    ) -> Result<Option<QueryResult>> {
+        if query.keyword.is_empty() {
+            return Err(business_error("Missing keyword", &[]).into());
+        }
+        let mut payloads = Vec::new();
+        if let Some(user) = get_text(&greetings.get_meta_data(), "user") {
+            let greeting = Greeting {
+                message: format!("Hello, {}!", user),
+                replayed: false,
+            };
+            payloads.push(axon_serialize("Greeting", &greeting)?);
+        }
+        for greeting in greetings.0.lock().unwrap().greetings.iter() {
+            if greeting.message.contains(&query.keyword) {
+                payloads.push(axon_serialize("Greeting", greeting)?);
+            }
+        }
+        Ok(Some(query_result_with_payloads(payloads)))
+    }
+
+    fn greetings_fixture(greetings: Greetings) -> ProjectionTestFixture<Greetings> {
+        let mut event_handlers: TheHandlerRegistry<Greetings, Option<Greetings>> =
+            empty_handler_registry();
+        event_handlers
+            .insert("GreetedEvent", &GreetedEvent::decode, &|c, p| {
+                Box::pin(handle_greeted(c, p))
+            })
+            .unwrap();
+        let mut query_handlers: TheHandlerRegistry<Greetings, QueryResult> =
+            empty_handler_registry();
+        query_handlers
+            .insert_with_output("SearchQuery", &SearchQuery::decode, &|c, p| {
+                Box::pin(handle_search(c, p))
+            })
+            .unwrap();
+        projection_test_fixture(greetings, event_handlers).with_query_handlers(query_handlers)
+    }
+
+    fn greeted(message: &str) -> GreetedEvent {
+        GreetedEvent {
+            message: message.to_string(),
+        }
+    }
+
+    fn search(keyword: &str) -> SearchQuery {
+        SearchQuery {
+            keyword: keyword.to_string(),
+        }
+    }
+
+    fn greeting(message: &str, replayed: bool) -> Greeting {
+        Greeting {
+            message: message.to_string(),
+            replayed,
+        }
+    }
+
+    #[tokio::test]
+    async fn query_sees_given_events() -> Result<()> {
+        greetings_fixture(Greetings::default())
+            .given("GreetedEvent", &greeted("Hello, World!"))
+            .await?
+            .given("GreetedEvent", &greeted("Goodbye, World!"))
+            .await?
+            .given("GreetedEvent", &greeted("Hello again!"))
+            .await?
+            .expect_stored_tokens(&[0, 1, 2])
+            .when_query("SearchQuery", &search("Hello"))
+            .await
+            .expect_payloads(&[
+                ("Greeting", &greeting("Hello, World!", false)),
+                ("Greeting", &greeting("Hello again!", false)),
+            ]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tokens_continue_after_retrieved_token() -> Result<()> {
+        let fixture = greetings_fixture(Greetings::processed_up_to(4))
+            .given("GreetedEvent", &greeted("Hello, World!"))
+            .await?
+            .expect_stored_tokens(&[5]);
+        assert_eq!(fixture.query_model().0.lock().unwrap().tokens, vec![4, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayed_events_are_marked() -> Result<()> {
+        let result = greetings_fixture(Greetings::default())
+            .given("GreetedEvent", &greeted("Hello, World!"))
+            .await?
+            .with_replaying(true)
+            .given("GreetedEvent", &greeted("Hello again!"))
+            .await?
+            .when_query("SearchQuery", &search("Hello"))
+            .await;
+        assert_eq!(
+            result.payloads::<Greeting>(),
+            vec![
+                greeting("Hello, World!", false),
+                greeting("Hello again!", true)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_handler_sees_meta_data() -> Result<()> {
+        let mut meta_data = empty_meta_data();
+        meta_data.insert("user".to_string(), text_value("Alice"));
+        let result = greetings_fixture(Greetings::default())
+            .with_meta_data(meta_data)
+            .when_query("SearchQuery", &search("Goodbye"))
+            .await;
+        assert_eq!(
+            result.payload::<Greeting>(),
+            greeting("Hello, Alice!", false)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_without_result_has_no_payload() {
+        greetings_fixture(Greetings::default())
+            .when_query("SearchQuery", &search("Hello"))
+            .await
+            .expect_no_payload();
+    }
+
+    #[tokio::test]
+    async fn rejected_query_reports_business_error() {
+        let error = greetings_fixture(Greetings::default())
+            .when_query("SearchQuery", &search(""))
+            .await
+            .expect_error();
+        assert!(matches!(error, AxonError::Business(_)));
+        assert_eq!(error.details().message, "Missing keyword");
+    }
+
+    #[tokio::test]
+    async fn unknown_query_reports_missing_handler() {
+        let error = greetings_fixture(Greetings::default())
+            .when_query("CountQuery", &search("Hello"))
+            .await
+            .expect_error();
+        assert!(matches!(error, AxonError::MissingHandler(_)));
+    }
+}
@@ -1,8 +1,14 @@
+use super::error::{AxonError, MessageKind};
 use super::handler_registry::TheHandlerRegistry;
-use crate::axon_server::query::query_service_client::QueryServiceClient;
+use super::metadata::{number_value, MetaData};
+use super::response_type::{decode_response_type, query_handler_name, split_query_handler_name};
 use crate::axon_server::query::{
     query_provider_inbound, query_provider_outbound, QueryProviderOutbound,
 };
+use crate::axon_server::query::{
+    subscription_query_request, subscription_query_response, SubscriptionQuery,
+    SubscriptionQueryRequest, SubscriptionQueryResponse,
+};
 use crate::axon_server::query::{QueryComplete, QueryRequest, QueryResponse, QuerySubscription};
 use crate::axon_server::{FlowControl, SerializedObject};
 use crate::axon_utils::AxonServerHandle;
@@ -12,35 +18,141 @@ use async_stream::stream;
 use futures_core::stream::Stream;
 use log::{debug, error, warn};
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Semaphore;
 use tonic::Request;
 use uuid::Uuid;
 
-/// Marker trait that describes the context for a query handler.
-pub trait QueryContext {}
+/// Key of the metadata entry of an initial result of a subscription query that holds the number of initial
+/// results, if there are more than one.
+pub(crate) const INITIAL_RESULT_COUNT: &str = "initial_result_count";
+
+tokio::task_local! {
+    static QUERY_META_DATA: MetaData;
+}
+
+/// Trait that describes the context for a query handler.
+pub trait QueryContext {
+    /// Returns the metadata of the query that is being handled.
+    fn get_meta_data(&self) -> MetaData {
+        QUERY_META_DATA
+            .try_with(|meta_data| meta_data.clone())
+            .unwrap_or_default()
+    }
+}
 
 /// Carries the result of a query from handler to processor.
-#[derive(Debug, Clone)]
+///
+/// A result with more than one payload is created with `query_result_with_payloads`. Each payload is sent to
+/// the caller as a separate `QueryResponse` (or as a separate initial result of a subscription query).
+/// A result with at most one payload is created with `QueryResult::new(payload)` or with
+/// `QueryResult { payload, ..Default::default() }`.
+#[derive(Debug, Clone, Default)]
 pub struct QueryResult {
     pub payload: Option<SerializedObject>,
+    pub additional_payloads: Vec<SerializedObject>,
+}
+
+/// Creates a query result that consists of multiple payloads.
+pub fn query_result_with_payloads(payloads: Vec<SerializedObject>) -> QueryResult {
+    let mut payloads = payloads.into_iter();
+    QueryResult {
+        payload: payloads.next(),
+        additional_payloads: payloads.collect(),
+    }
+}
+
+impl QueryResult {
+    /// Creates a query result with at most one payload.
+    pub fn new(payload: Option<SerializedObject>) -> Self {
+        QueryResult {
+            payload,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn into_payloads(self) -> Vec<SerializedObject> {
+        self.payload
+            .into_iter()
+            .chain(self.additional_payloads)
+            .collect()
+    }
+}
+
+/// Settings for a query processor.
+///
+/// Fields:
+/// * `concurrency`: The maximum number of queries that are handled at the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryProcessorSettings {
+    pub concurrency: usize,
+}
+
+impl Default for QueryProcessorSettings {
+    fn default() -> Self {
+        QueryProcessorSettings { concurrency: 4 }
+    }
+}
+
+/// Creates the default settings for a query processor.
+pub fn query_processor_settings() -> QueryProcessorSettings {
+    QueryProcessorSettings::default()
+}
+
+impl QueryProcessorSettings {
+    /// Sets the maximum number of queries that are handled at the same time.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
 }
 
 /// Carries the result of a query from the query processor th the output stream.
 #[derive(Debug)]
-struct AxonQueryResult {
+pub struct AxonQueryResult {
     message_identifier: String,
-    result: Option<SerializedObject>,
+    result: Result<Vec<SerializedObject>, AxonError>,
+}
+
+/// Carries output from the query processor (and from the `QueryUpdateEmitter`) to the output stream.
+#[derive(Debug)]
+pub enum AxonQueryOutput {
+    /// The result of a query. Consumes a flow-control permit.
+    Result(AxonQueryResult),
+    /// A response for a subscription query: an initial result, an update or a completion.
+    SubscriptionResponse(Box<SubscriptionQueryResponse>),
+    /// An incoming message that does not need a response was processed. Consumes a flow-control permit.
+    Processed,
 }
 
 /// Subscribes to queries, executes them against a query model and pass back the results.
-pub async fn query_processor<Q: QueryContext + Send + Sync + Clone>(
+pub async fn query_processor<Q: QueryContext + Send + Sync + Clone + 'static>(
+    axon_server_handle: AxonServerHandle,
+    query_context: Q,
+    query_handler_registry: TheHandlerRegistry<Q, QueryResult>,
+) -> Result<()> {
+    query_processor_with_settings(
+        axon_server_handle,
+        query_context,
+        query_handler_registry,
+        QueryProcessorSettings::default(),
+    )
+    .await
+}
+
+/// Like `query_processor`, but with the given settings for concurrency.
+pub async fn query_processor_with_settings<Q: QueryContext + Send + Sync + Clone + 'static>(
     axon_server_handle: AxonServerHandle,
     query_context: Q,
     query_handler_registry: TheHandlerRegistry<Q, QueryResult>,
+    settings: QueryProcessorSettings,
 ) -> Result<()> {
-    debug!("Query processor: start");
+    debug!("Query processor: start: {:?}", settings);
+    let concurrency = settings.concurrency.max(1);
+    let query_handler_registry = Arc::new(query_handler_registry);
 
-    let mut client = QueryServiceClient::new(axon_server_handle.conn.clone());
+    let mut client = axon_server_handle.query_service_client();
 
     let mut query_vec: Vec<String> = vec![];
     for (query_name, _) in &query_handler_registry.handlers {
@@ -48,52 +160,89 @@ pub async fn query_processor<Q: QueryContext + Send + Sync + Clone>(
     }
     let query_box = Box::new(query_vec);
 
-    let (tx, rx): (Sender<AxonQueryResult>, Receiver<AxonQueryResult>) = channel(10);
+    let (tx, rx): (Sender<AxonQueryOutput>, Receiver<AxonQueryOutput>) = channel(10 + concurrency);
 
-    let outbound = create_output_stream(axon_server_handle, query_box, rx);
+    let outbound = create_output_stream(axon_server_handle.clone(), query_box, concurrency, rx);
 
     debug!("Query processor: calling open_stream");
     let response = client.open_stream(Request::new(outbound)).await?;
     debug!("Stream response: {:?}", response);
 
+    let semaphore = Arc::new(Semaphore::new(concurrency));
     let mut inbound = response.into_inner();
     loop {
         match inbound.message().await {
             Ok(Some(inbound)) => {
                 debug!("Inbound message: {:?}", Debuggable::from(&inbound));
-                if let Some(query_provider_inbound::Request::Query(query)) = inbound.request {
-                    let query_name = query.query.clone();
-                    let mut result = Err(anyhow!("Could not find aggregate handler"));
-                    if let Some(query_handle) = query_handler_registry.handlers.get(&query_name) {
-                        if let QueryRequest {
-                            payload: Some(serialized_object),
-                            ..
-                        } = query
-                        {
-                            result = query_handle
-                                .handle(serialized_object.data, query_context.clone())
+                match inbound.request {
+                    Some(query_provider_inbound::Request::Query(query)) => {
+                        let permit = semaphore.clone().acquire_owned().await?;
+                        let query_handler_registry = query_handler_registry.clone();
+                        let query_context = query_context.clone();
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            let result =
+                                handle_query(&query, &query_handler_registry, query_context).await;
+
+                            let axon_query_result = AxonQueryResult {
+                                message_identifier: query.message_identifier,
+                                result: result
+                                    .map(|result| {
+                                        result.map(QueryResult::into_payloads).unwrap_or_default()
+                                    })
+                                    .map_err(|e| AxonError::from(&e)),
+                            };
+                            drop(permit);
+                            if tx
+                                .send(AxonQueryOutput::Result(axon_query_result))
                                 .await
-                        }
+                                .is_err()
+                            {
+                                debug!(
+                                    "Query processor: stream closed before result could be sent"
+                                );
+                            }
+                        });
                     }
-
-                    match result.as_ref() {
-                        Err(e) => warn!("Error while handling query: {:?}", e),
-                        Ok(Some(result)) => debug!("Result from query handler: {:?}", result),
-                        Ok(None) => debug!("Result from query handler: None"),
+                    Some(query_provider_inbound::Request::SubscriptionQueryRequest(
+                        SubscriptionQueryRequest {
+                            request:
+                                Some(subscription_query_request::Request::GetInitialResult(
+                                    subscription_query,
+                                )),
+                        },
+                    )) => {
+                        let permit = semaphore.clone().acquire_owned().await?;
+                        let display_name = axon_server_handle.display_name.clone();
+                        let query_handler_registry = query_handler_registry.clone();
+                        let query_context = query_context.clone();
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            let sent = send_initial_result(
+                                subscription_query,
+                                &display_name,
+                                &query_handler_registry,
+                                query_context,
+                                &tx,
+                            )
+                            .await;
+                            drop(permit);
+                            if sent.is_err() {
+                                debug!(
+                                    "Query processor: stream closed before initial result could be sent"
+                                );
+                            }
+                        });
                     }
-
-                    let axon_query_result = AxonQueryResult {
-                        message_identifier: query.message_identifier,
-                        result: result
-                            .unwrap_or(None)
-                            .map(|query_result| query_result.payload)
-                            .flatten(),
-                    };
-                    tx.send(axon_query_result).await.unwrap();
+                    Some(query_provider_inbound::Request::SubscriptionQueryRequest(request)) => {
+                        handle_subscription_query_request(request, &axon_server_handle, &tx)
+                            .await?;
+                    }
+                    _ => {}
                 }
             }
             Ok(None) => {
-                debug!("None incoming");
+                return Err(anyhow!("Query stream closed by AxonServer"));
             }
             Err(e) => {
                 error!("Error from AxonServer: {:?}", e);
@@ -103,21 +252,174 @@ pub async fn query_processor<Q: QueryContext + Send + Sync + Clone>(
     }
 }
 
+pub(crate) async fn handle_query<Q: QueryContext + Send + Sync + Clone>(
+    query: &QueryRequest,
+    query_handler_registry: &TheHandlerRegistry<Q, QueryResult>,
+    query_context: Q,
+) -> Result<Option<QueryResult>> {
+    let mut result = Err(AxonError::missing_handler(format!(
+        "Could not find query handler: {:?}",
+        query.query
+    ))
+    .into());
+    let response_type = query.response_type.as_ref().and_then(decode_response_type);
+    let query_handle = response_type
+        .and_then(|response_type| {
+            query_handler_registry
+                .handlers
+                .get(&query_handler_name(&query.query, &response_type))
+        })
+        .or_else(|| query_handler_registry.handlers.get(&query.query));
+    if let Some(query_handle) = query_handle {
+        if let QueryRequest {
+            payload: Some(serialized_object),
+            ..
+        } = query
+        {
+            result = QUERY_META_DATA
+                .scope(
+                    query.meta_data.clone(),
+                    query_handle.handle(serialized_object.data.clone(), query_context),
+                )
+                .await
+        }
+    }
+
+    match result.as_ref() {
+        Err(e) => warn!("Error while handling query: {:?}", e),
+        Ok(Some(result)) => debug!("Result from query handler: {:?}", result),
+        Ok(None) => debug!("Result from query handler: None"),
+    }
+    result
+}
+
+/// Registers or unregisters a subscription query. Initial results are handled by `send_initial_result`.
+async fn handle_subscription_query_request(
+    request: SubscriptionQueryRequest,
+    axon_server_handle: &AxonServerHandle,
+    tx: &Sender<AxonQueryOutput>,
+) -> Result<()> {
+    let emitter = &axon_server_handle.query_update_emitter;
+    match request.request {
+        Some(subscription_query_request::Request::Subscribe(subscription_query)) => {
+            if let Some(query) = subscription_query.query_request {
+                emitter.register(
+                    &subscription_query.subscription_identifier,
+                    query.query,
+                    query.payload,
+                    axon_server_handle.client_id.clone(),
+                    axon_server_handle.display_name.clone(),
+                    tx.clone(),
+                )?;
+            }
+        }
+        Some(subscription_query_request::Request::Unsubscribe(subscription_query)) => {
+            emitter.unregister(&subscription_query.subscription_identifier);
+        }
+        _ => {}
+    }
+    tx.send(AxonQueryOutput::Processed).await?;
+    Ok(())
+}
+
+/// Handles the query of a subscription query and sends its result as the initial result.
+async fn send_initial_result<Q: QueryContext + Send + Sync + Clone>(
+    subscription_query: SubscriptionQuery,
+    display_name: &str,
+    query_handler_registry: &TheHandlerRegistry<Q, QueryResult>,
+    query_context: Q,
+    tx: &Sender<AxonQueryOutput>,
+) -> Result<()> {
+    if let Some(query) = subscription_query.query_request {
+        let result = handle_query(&query, query_handler_registry, query_context).await;
+        for query_response in initial_responses(&query, result, display_name) {
+            let message_id = Uuid::new_v4();
+            let response = SubscriptionQueryResponse {
+                message_identifier: format!("{}", message_id),
+                subscription_identifier: subscription_query.subscription_identifier.clone(),
+                response: Some(subscription_query_response::Response::InitialResult(
+                    query_response,
+                )),
+            };
+            tx.send(AxonQueryOutput::SubscriptionResponse(Box::new(response)))
+                .await?;
+        }
+    }
+    tx.send(AxonQueryOutput::Processed).await?;
+    Ok(())
+}
+
+/// Converts the result of the query of a subscription query to initial results, one per payload.
+///
+/// When there is more than one payload, each initial result carries their number in its metadata (see
+/// `INITIAL_RESULT_COUNT`), so that the subscriber knows when it has received all of them.
+pub(crate) fn initial_responses(
+    query: &QueryRequest,
+    result: Result<Option<QueryResult>>,
+    display_name: &str,
+) -> Vec<QueryResponse> {
+    let payloads = match result {
+        Ok(result) => result.map(QueryResult::into_payloads).unwrap_or_default(),
+        Err(e) => {
+            let error_message =
+                AxonError::from(&e).to_error_message(MessageKind::Query, display_name);
+            return vec![QueryResponse {
+                error_code: error_message.error_code.clone(),
+                error_message: Some(error_message),
+                ..initial_response(query)
+            }];
+        }
+    };
+    if payloads.len() <= 1 {
+        return vec![QueryResponse {
+            payload: payloads.into_iter().next(),
+            ..initial_response(query)
+        }];
+    }
+    let mut meta_data = HashMap::new();
+    meta_data.insert(
+        INITIAL_RESULT_COUNT.to_string(),
+        number_value(payloads.len() as i64),
+    );
+    payloads
+        .into_iter()
+        .map(|payload| QueryResponse {
+            payload: Some(payload),
+            meta_data: meta_data.clone(),
+            ..initial_response(query)
+        })
+        .collect()
+}
+
+fn initial_response(query: &QueryRequest) -> QueryResponse {
+    QueryResponse {
+        message_identifier: format!("{}", Uuid::new_v4()),
+        error_code: "".to_string(),
+        error_message: None,
+        payload: None,
+        meta_data: HashMap::new(),
+        processing_instructions: Vec::new(),
+        request_identifier: query.message_identifier.clone(),
+    }
+}
+
 fn create_output_stream(
     axon_server_handle: AxonServerHandle,
     query_box: Box<Vec<String>>,
-    mut rx: Receiver<AxonQueryResult>,
+    concurrency: usize,
+    mut rx: Receiver<AxonQueryOutput>,
 ) -> impl Stream<Item = QueryProviderOutbound> {
     stream! {
         let client_id = axon_server_handle.client_id.clone();
         debug!("Query processor: stream: start: {:?}", rx);
-        for query_name in query_box.iter() {
-            debug!("Query processor: stream: subscribe to query type: {:?}", query_name);
+        for handler_name in query_box.iter() {
+            let (query_name, result_name) = split_query_handler_name(handler_name);
+            debug!("Query processor: stream: subscribe to query type: {:?}: {:?}", query_name, result_name);
             let subscription_id = Uuid::new_v4();
             let subscription = QuerySubscription {
                 message_id: format!("{}", subscription_id),
                 query: query_name.to_string().clone(),
-                result_name: "*".to_string(),
+                result_name: result_name.to_string(),
                 client_id: client_id.clone(),
                 component_name: axon_server_handle.display_name.clone(),
             };
@@ -131,7 +433,7 @@ fn create_output_stream(
             yield instruction.to_owned();
         }
 
-        let permits_batch_size: i64 = 3;
+        let permits_batch_size: i64 = concurrency.max(3) as i64;
         let mut permits = permits_batch_size * 2;
         debug!("Query processor: stream: send initial flow-control permits: amount: {:?}", permits);
         let flow_control = FlowControl {
@@ -145,38 +447,68 @@ fn create_output_stream(
         };
         yield instruction.to_owned();
 
-        while let Some(axon_query_result) = rx.recv().await {
-            debug!("Send query response: {:?}", axon_query_result);
-            let response_id = Uuid::new_v4();
-            let response = QueryResponse {
-                message_identifier: format!("{}", response_id),
-                error_code: "".to_string(),
-                error_message: None,
-                payload: axon_query_result.result.clone(),
-                meta_data: HashMap::new(),
-                processing_instructions: Vec::new(),
-                request_identifier: axon_query_result.message_identifier.clone(),
-            };
-            let instruction_id = Uuid::new_v4();
-            let instruction = QueryProviderOutbound {
-                instruction_id: format!("{}", instruction_id),
-                request: Some(query_provider_outbound::Request::QueryResponse(response)),
+        while let Some(axon_query_output) = rx.recv().await {
+            let axon_query_result = match axon_query_output {
+                AxonQueryOutput::Result(axon_query_result) => Some(axon_query_result),
+                AxonQueryOutput::SubscriptionResponse(response) => {
+                    debug!("Send subscription query response: {:?}", response);
+                    let instruction_id = Uuid::new_v4();
+                    let instruction = QueryProviderOutbound {
+                        instruction_id: format!("{}", instruction_id),
+                        request: Some(query_provider_outbound::Request::SubscriptionQueryResponse(*response)),
+                    };
+                    yield instruction.to_owned();
+                    continue;
+                }
+                AxonQueryOutput::Processed => None,
             };
-            debug!("QueryResponse instruction: {:?}", instruction);
-            yield instruction.to_owned();
+            if let Some(axon_query_result) = axon_query_result {
+                debug!("Send query response: {:?}", axon_query_result);
+                let payloads = match &axon_query_result.result {
+                    Ok(payloads) if payloads.is_empty() => vec![Ok(None)],
+                    Ok(payloads) => payloads.iter().map(|payload| Ok(Some(payload.clone()))).collect(),
+                    Err(e) => vec![Err(e.to_error_message(MessageKind::Query, &axon_server_handle.display_name))],
+                };
+                for payload in payloads {
+                    let response_id = Uuid::new_v4();
+                    let mut response = QueryResponse {
+                        message_identifier: format!("{}", response_id),
+                        error_code: "".to_string(),
+                        error_message: None,
+                        payload: None,
+                        meta_data: HashMap::new(),
+                        processing_instructions: Vec::new(),
+                        request_identifier: axon_query_result.message_identifier.clone(),
+                    };
+                    match payload {
+                        Ok(payload) => response.payload = payload,
+                        Err(error_message) => {
+                            response.error_code = error_message.error_code.clone();
+                            response.error_message = Some(error_message);
+                        }
+                    }
+                    let instruction_id = Uuid::new_v4();
+                    let instruction = QueryProviderOutbound {
+                        instruction_id: format!("{}", instruction_id),
+                        request: Some(query_provider_outbound::Request::QueryResponse(response)),
+                    };
+                    debug!("QueryResponse instruction: {:?}", instruction);
+                    yield instruction.to_owned();
+                }
 
-            let complete_id = Uuid::new_v4();
-            let complete = QueryComplete {
-                message_id: format!("{}", complete_id),
-                request_id: axon_query_result.message_identifier.clone(),
-            };
-            let complete_instruction_id = Uuid::new_v4();
-            let complete_instruction = QueryProviderOutbound {
-                instruction_id: format!("{}", complete_instruction_id),
-                request: Some(query_provider_outbound::Request::QueryComplete(complete)),
-            };
-            debug!("Complete instruction: {:?}", complete_instruction);
-            yield complete_instruction.to_owned();
+                let complete_id = Uuid::new_v4();
+                let complete = QueryComplete {
+                    message_id: format!("{}", complete_id),
+                    request_id: axon_query_result.message_identifier.clone(),
+                };
+                let complete_instruction_id = Uuid::new_v4();
+                let complete_instruction = QueryProviderOutbound {
+                    instruction_id: format!("{}", complete_instruction_id),
+                    request: Some(query_provider_outbound::Request::QueryComplete(complete)),
+                };
+                debug!("Complete instruction: {:?}", complete_instruction);
+                yield complete_instruction.to_owned();
+            }
 
             permits -= 1;
             if permits <= permits_batch_size {
@@ -1,20 +1,56 @@
-use super::{AxonServerHandle, QuerySink, VecU8Message};
+use super::error::AxonError;
+use super::metadata::get_number;
+use super::query_processor::INITIAL_RESULT_COUNT;
+use super::response_type::encode_response_type;
+use super::{AxonServerHandle, DispatchOptions, MetaData, QuerySink, VecU8Message};
 use crate::axon_server::query::query_service_client::QueryServiceClient;
-use crate::axon_server::query::{QueryRequest, QueryResponse};
-use crate::axon_server::SerializedObject;
+use crate::axon_server::query::{subscription_query_request, subscription_query_response};
+use crate::axon_server::query::{
+    QueryRequest, QueryResponse, QueryUpdate, QueryUpdateCompleteExceptionally, SubscriptionQuery,
+    SubscriptionQueryRequest, SubscriptionQueryResponse,
+};
+use crate::axon_server::{ErrorMessage, SerializedObject};
 use crate::intellij_work_around::Debuggable;
-use anyhow::Result;
-use log::debug;
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use async_stream::stream;
+use futures_core::stream::Stream;
+use log::{debug, warn};
+use std::fmt::{Debug, Formatter};
+use std::pin::Pin;
 use std::vec::Vec;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tonic::transport::Channel;
+use tonic::Request;
 use uuid::Uuid;
 
+/// The result of a subscription query: the payloads of the initial result and a stream of updates.
+///
+/// The subscription is cancelled when the stream of updates is dropped.
+pub struct SubscriptionQueryResult {
+    pub initial_result: Vec<SerializedObject>,
+    pub updates: Pin<Box<dyn Stream<Item = Result<SerializedObject>> + Send>>,
+}
+
+impl Debug for SubscriptionQueryResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "[SubscriptionQueryResult:{:?}]",
+            self.initial_result
+                .iter()
+                .map(|o| Debuggable::from(o))
+                .collect::<Vec<_>>()
+        ))
+    }
+}
+
 #[tonic::async_trait]
 impl QuerySink for AxonServerHandle {
-    async fn send_query<'a>(
+    async fn send_query_with_options(
         &self,
         query_type: &str,
-        query: Box<&(dyn VecU8Message + Sync)>,
+        query: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
     ) -> Result<Vec<SerializedObject>> {
         debug!("Sending query: {:?}: {:?}", query_type, self.display_name);
         let mut buf = Vec::new();
@@ -26,50 +62,399 @@ impl QuerySink for AxonServerHandle {
             revision: "1".to_string(),
             data: buf,
         };
-        submit_query(self, &serialized_command).await
+        submit_query(self, &serialized_command, meta_data, options).await
+    }
+
+    async fn subscribe_query_with_options(
+        &self,
+        query_type: &str,
+        query: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
+    ) -> Result<SubscriptionQueryResult> {
+        debug!(
+            "Sending subscription query: {:?}: {:?}",
+            query_type, self.display_name
+        );
+        let mut buf = Vec::new();
+        query.encode_u8(&mut buf)?;
+        let serialized_query = SerializedObject {
+            r#type: query_type.to_string(),
+            revision: "1".to_string(),
+            data: buf,
+        };
+        submit_subscription_query(self, &serialized_query, meta_data, options).await
     }
 }
 
 async fn submit_query<'a>(
     this: &AxonServerHandle,
     message: &SerializedObject,
+    meta_data: MetaData,
+    options: DispatchOptions,
 ) -> Result<Vec<SerializedObject>> {
     debug!("Message: {:?}", Debuggable::from(message));
-    let this = this.clone();
-    let conn = this.conn;
-    let mut client = QueryServiceClient::new(conn);
+    let options = options.or(&this.dispatch_options);
+    let client = this.query_service_client();
     debug!("Query Service Client: {:?}", client);
     let uuid = Uuid::new_v4();
     let query_request = QueryRequest {
         message_identifier: format!("{}", uuid),
         query: message.r#type.clone(),
-        response_type: None,
+        response_type: options.response_type.as_deref().map(encode_response_type),
         payload: Some(message.clone()),
         client_id: this.client_id.clone(),
         component_name: this.display_name.clone(),
-        meta_data: HashMap::new(),
-        processing_instructions: Vec::new(),
+        meta_data,
+        processing_instructions: options.processing_instructions(),
         timestamp: 0,
     };
-    let response = client.query(query_request).await?;
+    let scatter_gather = matches!(options.nr_of_results, Some(n) if n != 1);
+    if !scatter_gather {
+        return options
+            .enforce_timeout(&message.r#type, async {
+                let mut result = Vec::new();
+                collect_query_responses(client, query_request, &options, &mut result).await?;
+                Ok(result)
+            })
+            .await;
+    }
+    let mut result = Vec::new();
+    let collected = collect_query_responses(client, query_request, &options, &mut result);
+    match options.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, collected).await {
+            Ok(collected) => collected?,
+            Err(_) => debug!("Scatter-gather query: timeout: {:?}", message.r#type),
+        },
+        None => collected.await?,
+    }
+    debug!(
+        "Scatter-gather query: {:?}: number of results: {:?}",
+        message.r#type,
+        result.len()
+    );
+    Ok(result)
+}
+
+/// Adds the payloads of the responses to `result`. For a scatter-gather query, error responses of individual
+/// query processors are skipped and collecting stops when the expected number of results arrived.
+async fn collect_query_responses(
+    mut client: QueryServiceClient<Channel>,
+    query_request: QueryRequest,
+    options: &DispatchOptions,
+    result: &mut Vec<SerializedObject>,
+) -> Result<()> {
+    let scatter_gather = matches!(options.nr_of_results, Some(n) if n != 1);
+    let expected_results = options.nr_of_results.filter(|n| *n > 0).map(|n| n as usize);
+    let response = client.query(query_request).await.map_err(AxonError::from)?;
     debug!("Response: {:?}", response);
     let mut response = response.into_inner();
 
-    let mut result = Vec::new();
-    loop {
-        let query_response = response.message().await?;
-
-        if let Some(QueryResponse {
-            payload: Some(payload),
-            ..
-        }) = query_response
-        {
-            let payload = payload.clone();
+    while let Some(query_response) = response.message().await.map_err(AxonError::from)? {
+        if let Some(error_message) = query_response.error_message {
+            let error = AxonError::from_error_message(error_message);
+            if !scatter_gather {
+                return Err(error.into());
+            }
+            warn!("Scatter-gather query: error response: {:?}", error);
+            continue;
+        }
+        if let Some(payload) = query_response.payload {
             debug!("Query response: payload: {:?}", Debuggable::from(&payload));
             result.push(payload);
-        } else {
+        }
+        if matches!(expected_results, Some(n) if result.len() >= n) {
             break;
         }
     }
-    Ok(result)
+    Ok(())
+}
+
+async fn submit_subscription_query(
+    this: &AxonServerHandle,
+    message: &SerializedObject,
+    meta_data: MetaData,
+    options: DispatchOptions,
+) -> Result<SubscriptionQueryResult> {
+    debug!("Message: {:?}", Debuggable::from(message));
+    let options = options.or(&this.dispatch_options);
+    let mut client = this.query_service_client();
+    let query_request = QueryRequest {
+        message_identifier: format!("{}", Uuid::new_v4()),
+        query: message.r#type.clone(),
+        response_type: options.response_type.as_deref().map(encode_response_type),
+        payload: Some(message.clone()),
+        client_id: this.client_id.clone(),
+        component_name: this.display_name.clone(),
+        meta_data,
+        processing_instructions: options.processing_instructions(),
+        timestamp: 0,
+    };
+    let permits_batch_size: i64 = 3;
+    let subscription_query = SubscriptionQuery {
+        subscription_identifier: format!("{}", Uuid::new_v4()),
+        number_of_permits: permits_batch_size * 2,
+        query_request: Some(query_request),
+        update_response_type: None,
+    };
+    let (tx, rx): (Sender<i64>, Receiver<i64>) = channel(10);
+    let outbound = create_subscription_output_stream(subscription_query, rx);
+    let response = client.subscription(Request::new(outbound)).await?;
+    debug!("Response: {:?}", response);
+    let mut inbound = response.into_inner();
+
+    let mut early_updates = Vec::new();
+    let mut initial_results = InitialResults::default();
+    options
+        .enforce_timeout(&message.r#type, async {
+            loop {
+                match inbound.message().await? {
+                    Some(SubscriptionQueryResponse {
+                        response: Some(response),
+                        ..
+                    }) => match response {
+                        subscription_query_response::Response::InitialResult(query_response) => {
+                            if initial_results.add(query_response)? {
+                                return Ok(());
+                            }
+                        }
+                        subscription_query_response::Response::Update(update) => {
+                            early_updates.push(update);
+                        }
+                        subscription_query_response::Response::Complete(_) => {
+                            return Err(anyhow!(
+                                "Subscription query completed before initial result"
+                            ));
+                        }
+                        subscription_query_response::Response::CompleteExceptionally(complete) => {
+                            return Err(complete_exceptionally_error(complete).into());
+                        }
+                    },
+                    Some(_) => {}
+                    None => return Err(anyhow!("Subscription query closed before initial result")),
+                }
+            }
+        })
+        .await?;
+    let initial_result = initial_results.payloads;
+    debug!(
+        "Initial result: {:?}",
+        initial_result
+            .iter()
+            .map(|o| Debuggable::from(o))
+            .collect::<Vec<_>>()
+    );
+
+    let updates = stream! {
+        let mut permits = permits_batch_size * 2;
+        for update in early_updates {
+            permits -= 1;
+            if let Some(update) = convert_update(update) {
+                yield update;
+            }
+        }
+        loop {
+            if permits <= permits_batch_size {
+                debug!("Subscription query: send more flow-control permits: amount: {:?}", permits_batch_size);
+                if tx.send(permits_batch_size).await.is_err() {
+                    break;
+                }
+                permits += permits_batch_size;
+            }
+            match inbound.message().await {
+                Ok(Some(SubscriptionQueryResponse { response: Some(response), .. })) => match response {
+                    subscription_query_response::Response::Update(update) => {
+                        permits -= 1;
+                        if let Some(update) = convert_update(update) {
+                            yield update;
+                        }
+                    }
+                    subscription_query_response::Response::Complete(_) => break,
+                    subscription_query_response::Response::CompleteExceptionally(complete) => {
+                        yield Err(complete_exceptionally_error(complete).into());
+                        break;
+                    }
+                    subscription_query_response::Response::InitialResult(_) => {}
+                },
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    yield Err(anyhow!(e));
+                    break;
+                }
+            }
+        }
+    };
+    Ok(SubscriptionQueryResult {
+        initial_result,
+        updates: Box::pin(updates),
+    })
+}
+
+/// Collects the initial result of a subscription query, which may consist of several responses (see
+/// `INITIAL_RESULT_COUNT`).
+#[derive(Default)]
+struct InitialResults {
+    payloads: Vec<SerializedObject>,
+    received: i64,
+    expected: i64,
+}
+
+impl InitialResults {
+    /// Adds a response. Returns true when all responses of the initial result have arrived.
+    fn add(&mut self, query_response: QueryResponse) -> Result<bool> {
+        if let Some(error_message) = query_response.error_message {
+            return Err(AxonError::from_error_message(error_message).into());
+        }
+        if self.received == 0 {
+            self.expected =
+                get_number(&query_response.meta_data, INITIAL_RESULT_COUNT).unwrap_or(1);
+        }
+        self.received += 1;
+        self.payloads.extend(query_response.payload);
+        Ok(self.received >= self.expected)
+    }
+}
+
+fn convert_update(update: QueryUpdate) -> Option<Result<SerializedObject>> {
+    debug!("Query update: {:?}", Debuggable::from(&update));
+    if let Some(error_message) = update.error_message {
+        return Some(Err(AxonError::from_error_message(error_message).into()));
+    }
+    update.payload.map(Ok)
+}
+
+fn complete_exceptionally_error(complete: QueryUpdateCompleteExceptionally) -> AxonError {
+    AxonError::from_error_message(complete.error_message.unwrap_or(ErrorMessage {
+        message: complete.error_code.clone(),
+        location: complete.component_name,
+        details: Vec::new(),
+        error_code: complete.error_code,
+    }))
+}
+
+fn create_subscription_output_stream(
+    subscription_query: SubscriptionQuery,
+    mut rx: Receiver<i64>,
+) -> impl Stream<Item = SubscriptionQueryRequest> {
+    stream! {
+        yield SubscriptionQueryRequest {
+            request: Some(subscription_query_request::Request::Subscribe(subscription_query.clone())),
+        };
+        yield SubscriptionQueryRequest {
+            request: Some(subscription_query_request::Request::GetInitialResult(subscription_query.clone())),
+        };
+        while let Some(permits) = rx.recv().await {
+            let flow_control = SubscriptionQuery {
+                subscription_identifier: subscription_query.subscription_identifier.clone(),
+                number_of_permits: permits,
+                query_request: None,
+                update_response_type: None,
+            };
+            yield SubscriptionQueryRequest {
+                request: Some(subscription_query_request::Request::FlowControl(flow_control)),
+            };
+        }
+        debug!("Subscription query: unsubscribe: {:?}", subscription_query.subscription_identifier);
+        yield SubscriptionQueryRequest {
+            request: Some(subscription_query_request::Request::Unsubscribe(subscription_query)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axon_utils::query_processor::initial_responses;
+    use crate::axon_utils::{business_error, query_result_with_payloads, QueryResult};
+
+    fn query() -> QueryRequest {
+        QueryRequest {
+            message_identifier: "query-1".to_string(),
+            query: "SearchQuery".to_string(),
+            ..QueryRequest::default()
+        }
+    }
+
+    fn payload(data: u8) -> SerializedObject {
+        SerializedObject {
+            r#type: "Greeting".to_string(),
+            revision: "".to_string(),
+            data: vec![data],
+        }
+    }
+
+    /// Feeds the responses to `InitialResults` and returns the payloads and the number of responses that
+    /// were needed to complete the initial result.
+    fn collect(responses: Vec<QueryResponse>) -> Result<(Vec<SerializedObject>, usize)> {
+        let mut initial_results = InitialResults::default();
+        for (i, response) in responses.into_iter().enumerate() {
+            if initial_results.add(response)? {
+                return Ok((initial_results.payloads, i + 1));
+            }
+        }
+        Err(anyhow!("Initial result incomplete"))
+    }
+
+    #[test]
+    fn initial_result_with_one_payload() -> Result<()> {
+        let responses = initial_responses(
+            &query(),
+            Ok(Some(QueryResult::new(Some(payload(1))))),
+            "test",
+        );
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].request_identifier, "query-1");
+        assert_eq!(collect(responses)?, (vec![payload(1)], 1));
+        Ok(())
+    }
+
+    #[test]
+    fn initial_result_without_payload() -> Result<()> {
+        let responses = initial_responses(&query(), Ok(None), "test");
+        assert_eq!(collect(responses)?, (Vec::new(), 1));
+        Ok(())
+    }
+
+    #[test]
+    fn initial_result_with_multiple_payloads() -> Result<()> {
+        let payloads = vec![payload(1), payload(2), payload(3)];
+        let result = query_result_with_payloads(payloads.clone());
+        let mut responses = initial_responses(&query(), Ok(Some(result)), "test");
+        assert_eq!(responses.len(), 3);
+        // An update that arrives after the initial result is not part of it.
+        responses.push(QueryResponse {
+            payload: Some(payload(4)),
+            ..responses[0].clone()
+        });
+        assert_eq!(collect(responses)?, (payloads, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn initial_result_from_other_processor_is_complete() -> Result<()> {
+        let responses = vec![
+            QueryResponse {
+                payload: Some(payload(1)),
+                ..QueryResponse::default()
+            },
+            QueryResponse {
+                payload: Some(payload(2)),
+                ..QueryResponse::default()
+            },
+        ];
+        assert_eq!(collect(responses)?, (vec![payload(1)], 1));
+        Ok(())
+    }
+
+    #[test]
+    fn initial_result_with_error() {
+        let error = business_error("Rejected", &[]).into();
+        let responses = initial_responses(&query(), Err(error), "test");
+        let error = collect(responses).unwrap_err();
+        assert!(matches!(
+            AxonError::from(&error),
+            AxonError::Business(details) if details.message == "Rejected"
+        ));
+    }
 }
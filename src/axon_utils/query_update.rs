@@ -0,0 +1,174 @@
+use super::query_processor::AxonQueryOutput;
+use super::VecU8Message;
+use crate::axon_server::query::subscription_query_response;
+use crate::axon_server::query::{QueryUpdate, QueryUpdateComplete, SubscriptionQueryResponse};
+use crate::axon_server::SerializedObject;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+/// An active subscription query, as registered by a query processor.
+#[derive(Debug, Clone)]
+struct ActiveSubscription {
+    query: String,
+    payload: Option<SerializedObject>,
+    client_id: String,
+    component_name: String,
+    sender: Sender<AxonQueryOutput>,
+}
+
+/// Emits updates to the subscription queries that are active in the query processors that use an `AxonServerHandle`.
+///
+/// Event handlers can use the emitter to push changes of the query model to subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct QueryUpdateEmitter {
+    subscriptions: Arc<Mutex<HashMap<String, ActiveSubscription>>>,
+}
+
+impl QueryUpdateEmitter {
+    pub(crate) fn register(
+        &self,
+        subscription_identifier: &str,
+        query: String,
+        payload: Option<SerializedObject>,
+        client_id: String,
+        component_name: String,
+        sender: Sender<AxonQueryOutput>,
+    ) -> Result<()> {
+        let subscription = ActiveSubscription {
+            query,
+            payload,
+            client_id,
+            component_name,
+            sender,
+        };
+        debug!(
+            "Register subscription query: {:?}: {:?}",
+            subscription_identifier, subscription.query
+        );
+        self.subscriptions
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?
+            .insert(subscription_identifier.to_string(), subscription);
+        Ok(())
+    }
+
+    pub(crate) fn unregister(&self, subscription_identifier: &str) {
+        debug!(
+            "Unregister subscription query: {:?}",
+            subscription_identifier
+        );
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.remove(subscription_identifier);
+        }
+    }
+
+    /// Sends an update to all active subscription queries of type `query_type` for which `filter` holds.
+    pub async fn emit<T>(
+        &self,
+        query_type: &str,
+        deserializer: &(dyn Fn(Bytes) -> Result<T, prost::DecodeError> + Sync),
+        filter: &(dyn Fn(&T) -> bool + Sync),
+        update_type: &str,
+        update: &(dyn VecU8Message + Sync),
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        update.encode_u8(&mut buf)?;
+        let payload = SerializedObject {
+            r#type: update_type.to_string(),
+            revision: "".to_string(),
+            data: buf,
+        };
+        let subscriptions = self.matching_subscriptions(query_type, deserializer, filter)?;
+        for (subscription_identifier, subscription) in subscriptions {
+            let update = QueryUpdate {
+                message_identifier: format!("{}", Uuid::new_v4()),
+                payload: Some(payload.clone()),
+                meta_data: HashMap::new(),
+                client_id: subscription.client_id.clone(),
+                component_name: subscription.component_name.clone(),
+                error_code: "".to_string(),
+                error_message: None,
+            };
+            let response = subscription_query_response::Response::Update(update);
+            self.send(&subscription_identifier, &subscription, response)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Completes all active subscription queries of type `query_type` for which `filter` holds.
+    ///
+    /// No more updates are sent to these subscriptions.
+    pub async fn complete<T>(
+        &self,
+        query_type: &str,
+        deserializer: &(dyn Fn(Bytes) -> Result<T, prost::DecodeError> + Sync),
+        filter: &(dyn Fn(&T) -> bool + Sync),
+    ) -> Result<()> {
+        let subscriptions = self.matching_subscriptions(query_type, deserializer, filter)?;
+        for (subscription_identifier, subscription) in subscriptions {
+            let complete = QueryUpdateComplete {
+                client_id: subscription.client_id.clone(),
+                component_name: subscription.component_name.clone(),
+            };
+            let response = subscription_query_response::Response::Complete(complete);
+            self.send(&subscription_identifier, &subscription, response)
+                .await;
+            self.unregister(&subscription_identifier);
+        }
+        Ok(())
+    }
+
+    fn matching_subscriptions<T>(
+        &self,
+        query_type: &str,
+        deserializer: &(dyn Fn(Bytes) -> Result<T, prost::DecodeError> + Sync),
+        filter: &(dyn Fn(&T) -> bool + Sync),
+    ) -> Result<Vec<(String, ActiveSubscription)>> {
+        let subscriptions = self
+            .subscriptions
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        let mut result = Vec::new();
+        for (subscription_identifier, subscription) in subscriptions.iter() {
+            if subscription.query != query_type {
+                continue;
+            }
+            let data = subscription
+                .payload
+                .as_ref()
+                .map(|p| p.data.clone())
+                .unwrap_or_default();
+            if filter(&deserializer(Bytes::from(data))?) {
+                result.push((subscription_identifier.clone(), subscription.clone()));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn send(
+        &self,
+        subscription_identifier: &str,
+        subscription: &ActiveSubscription,
+        response: subscription_query_response::Response,
+    ) {
+        let response = SubscriptionQueryResponse {
+            message_identifier: format!("{}", Uuid::new_v4()),
+            subscription_identifier: subscription_identifier.to_string(),
+            response: Some(response),
+        };
+        let output = AxonQueryOutput::SubscriptionResponse(Box::new(response));
+        if subscription.sender.send(output).await.is_err() {
+            debug!(
+                "Query processor stopped: drop subscription query: {:?}",
+                subscription_identifier
+            );
+            self.unregister(subscription_identifier);
+        }
+    }
+}
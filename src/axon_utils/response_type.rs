@@ -0,0 +1,57 @@
+use crate::axon_server::SerializedObject;
+
+const INSTANCE_RESPONSE_TYPE: &str =
+    "org.axonframework.messaging.responsetypes.InstanceResponseType";
+const EXPECTED_RESPONSE_TYPE: &str = "expectedResponseType";
+const SEPARATOR: char = '#';
+
+/// Returns the name under which a query handler for the given query and response type is registered in
+/// the query handler registry.
+///
+/// Handlers that are registered under the plain query name handle queries with any response type.
+pub fn query_handler_name(query_name: &str, response_type: &str) -> String {
+    format!("{}{}{}", query_name, SEPARATOR, response_type)
+}
+
+/// Splits the name of a query handler into the query name and the response type (`"*"` if any).
+pub(crate) fn split_query_handler_name(handler_name: &str) -> (&str, &str) {
+    match handler_name.find(SEPARATOR) {
+        Some(index) => (&handler_name[..index], &handler_name[index + 1..]),
+        None => (handler_name, "*"),
+    }
+}
+
+/// Encodes the expected response type of a query the way Axon Framework does with its default (XStream) serializer.
+pub(crate) fn encode_response_type(response_type: &str) -> SerializedObject {
+    let data = format!(
+        "<{}><{}>{}</{}></{}>",
+        INSTANCE_RESPONSE_TYPE,
+        EXPECTED_RESPONSE_TYPE,
+        response_type,
+        EXPECTED_RESPONSE_TYPE,
+        INSTANCE_RESPONSE_TYPE
+    );
+    SerializedObject {
+        r#type: INSTANCE_RESPONSE_TYPE.to_string(),
+        revision: "".to_string(),
+        data: data.into_bytes(),
+    }
+}
+
+/// Extracts the expected response type from a response type that was serialized by Axon Framework, either
+/// with the XStream serializer or with the Jackson serializer.
+pub(crate) fn decode_response_type(response_type: &SerializedObject) -> Option<String> {
+    let data = std::str::from_utf8(&response_type.data).ok()?;
+    let xml_start = format!("<{}>", EXPECTED_RESPONSE_TYPE);
+    if let Some(start) = data.find(&xml_start) {
+        let rest = &data[start + xml_start.len()..];
+        let end = rest.find('<')?;
+        return Some(rest[..end].trim().to_string());
+    }
+    let json_key = format!("\"{}\"", EXPECTED_RESPONSE_TYPE);
+    let start = data.find(&json_key)?;
+    let rest = &data[start + json_key.len()..];
+    let rest = &rest[rest.find('"')? + 1..];
+    let end = rest.find('"')?;
+    Some(rest[..end].to_string())
+}
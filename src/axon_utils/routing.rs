@@ -0,0 +1,88 @@
+use super::metadata::text_value;
+use crate::axon_server::common::{MetaDataValue, ProcessingInstruction, ProcessingKey};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+type RoutingKeyExtractor = Arc<dyn Fn(&[u8]) -> Result<String> + Send + Sync>;
+
+/// Registry that knows, for each command type, how to extract the routing key from a command.
+///
+/// AxonServer uses consistent hashing on the routing key to route commands. When the routing key is the
+/// aggregate id, all commands for an aggregate are handled by the same command worker, so that its cache
+/// of projections stays effective.
+#[derive(Clone, Default)]
+pub struct RoutingKeyRegistry {
+    extractors: HashMap<String, RoutingKeyExtractor>,
+}
+
+impl Debug for RoutingKeyRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "[RoutingKeyRegistry:{:?}]",
+            self.extractors.keys().collect::<Vec<_>>()
+        ))
+    }
+}
+
+/// Creates an empty routing key registry that can be populated with routing key extractors.
+pub fn empty_routing_key_registry() -> RoutingKeyRegistry {
+    RoutingKeyRegistry::default()
+}
+
+impl RoutingKeyRegistry {
+    /// Registers how the routing key is extracted from commands of the given type.
+    pub fn insert<T: 'static>(
+        &mut self,
+        command_name: &str,
+        deserializer: &'static (dyn Fn(Bytes) -> Result<T, prost::DecodeError> + Sync),
+        extractor: &'static (dyn Fn(&T) -> String + Sync),
+    ) -> Result<()> {
+        if self.extractors.contains_key(command_name) {
+            return Err(anyhow!(
+                "Routing key extractor already registered: {:?}",
+                command_name
+            ));
+        }
+        let extractor: RoutingKeyExtractor = Arc::new(move |data: &[u8]| {
+            let command = deserializer(Bytes::copy_from_slice(data))?;
+            Ok(extractor(&command))
+        });
+        self.extractors.insert(command_name.to_string(), extractor);
+        Ok(())
+    }
+
+    /// Adds all routing key extractors of the given registry to this registry.
+    pub fn extend(&mut self, other: &RoutingKeyRegistry) {
+        for (command_name, extractor) in &other.extractors {
+            self.extractors
+                .insert(command_name.clone(), extractor.clone());
+        }
+    }
+
+    /// Extracts the routing key from an encoded command of the given type.
+    ///
+    /// Returns `None` if no routing key extractor was registered for the command type.
+    pub fn routing_key(&self, command_name: &str, data: &[u8]) -> Result<Option<String>> {
+        match self.extractors.get(command_name) {
+            Some(extractor) => Ok(Some(extractor(data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+pub(crate) fn processing_instruction(
+    key: ProcessingKey,
+    value: MetaDataValue,
+) -> ProcessingInstruction {
+    ProcessingInstruction {
+        key: key as i32,
+        value: Some(value),
+    }
+}
+
+pub(crate) fn routing_key_instruction(routing_key: &str) -> ProcessingInstruction {
+    processing_instruction(ProcessingKey::RoutingKey, text_value(routing_key))
+}
@@ -0,0 +1,378 @@
+use super::event_processor::{
+    caused_by_event, is_replaying, segmented_event_processor, TokenStore,
+};
+use super::handler_registry::{
+    empty_handler_registry, HandlerRegistry, SubscriptionHandle, TheHandlerRegistry,
+};
+use super::segment::Segment;
+use super::{AxonServerHandle, CommandSink, DispatchOptions, MetaData, VecU8Message};
+use crate::axon_server::SerializedObject;
+use anyhow::Result;
+use log::debug;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A key / value pair that associates events with saga instances.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AssociationValue {
+    pub key: String,
+    pub value: String,
+}
+
+/// A saga instance as it is stored in a `SagaRepository`.
+#[derive(Debug, Clone)]
+pub struct SagaInstance<S> {
+    pub saga_id: String,
+    pub association_values: Vec<AssociationValue>,
+    pub state: S,
+}
+
+/// Describes a repository for saga instances.
+///
+/// The repository finds saga instances by association value and stores their state.
+#[tonic::async_trait]
+pub trait SagaRepository<S> {
+    async fn find_sagas(
+        &self,
+        saga_type: &str,
+        association_value: &AssociationValue,
+    ) -> Result<Vec<String>>;
+    async fn load_saga(&self, saga_type: &str, saga_id: &str) -> Result<Option<SagaInstance<S>>>;
+    async fn store_saga(&self, saga_type: &str, saga: &SagaInstance<S>) -> Result<()>;
+    async fn delete_saga(&self, saga_type: &str, saga_id: &str) -> Result<()>;
+}
+
+/// The context of a saga event handler.
+///
+/// Gives access to the state of the saga instance, manages its association values and can be used to
+/// send commands to AxonServer. Commands inherit the metadata of the event that is being handled, with
+/// the event as their cause (see `CORRELATION_ID` and `CAUSATION_ID`). While events are replayed after a
+/// reset of the event processor (see `is_replaying`), commands are not sent: they were already sent when
+/// the events were first handled.
+pub struct SagaContext<S> {
+    pub saga_id: String,
+    pub state: S,
+    association_values: Vec<AssociationValue>,
+    ended: bool,
+    axon_server_handle: AxonServerHandle,
+}
+
+impl<S> SagaContext<S> {
+    /// Associates this saga instance with events that resolve to the given key / value pair.
+    pub fn associate_with(&mut self, key: &str, value: &str) {
+        let association_value = AssociationValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        if !self.association_values.contains(&association_value) {
+            self.association_values.push(association_value);
+        }
+    }
+
+    /// Removes the association of this saga instance with the given key / value pair.
+    pub fn remove_association_with(&mut self, key: &str, value: &str) {
+        self.association_values
+            .retain(|a| a.key != key || a.value != value);
+    }
+
+    /// Ends this saga instance. It is removed from the repository after the handler is done.
+    pub fn end(&mut self) {
+        self.ended = true;
+    }
+}
+
+impl<S> Debug for SagaContext<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("[SagaContext:{:?}]", self.saga_id))
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Send + Sync> CommandSink for SagaContext<S> {
+    async fn send_command_with_options(
+        &self,
+        command_type: &str,
+        command: &(dyn VecU8Message + Sync),
+        meta_data: MetaData,
+        options: DispatchOptions,
+    ) -> Result<Option<SerializedObject>> {
+        if is_replaying() {
+            debug!(
+                "Skip command during replay: {:?}: {:?}",
+                self.saga_id, command_type
+            );
+            return Ok(None);
+        }
+        let mut command_meta_data = caused_by_event();
+        command_meta_data.extend(meta_data);
+        self.axon_server_handle
+            .send_command_with_options(command_type, command, command_meta_data, options)
+            .await
+    }
+}
+
+/// Registry for the event handlers of a saga.
+pub type SagaEventHandlerRegistry<S> =
+    TheHandlerRegistry<Arc<async_lock::Mutex<SagaContext<S>>>, ()>;
+
+/// The complete definition of a saga.
+///
+/// Fields:
+/// * `saga_name`: The name of the saga type. It is also used as the name of the event processor.
+/// * `empty_state`: Factory method for the state of a new saga instance.
+/// * `association_resolvers`: Registry that assigns, for each event type, a handler that extracts the association value.
+/// * `start_events`: The event types that start a new saga instance when no instance is associated with the event.
+/// * `event_handler_registry`: Registry that assigns a handler for each event type.
+pub struct SagaDefinition<S: Send + 'static> {
+    pub saga_name: String,
+    empty_state: Box<fn() -> S>,
+    association_resolvers: TheHandlerRegistry<(), AssociationValue>,
+    start_events: Vec<String>,
+    event_handler_registry: SagaEventHandlerRegistry<S>,
+}
+
+impl<S: Send + 'static> Debug for SagaDefinition<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("[SagaDefinition:{:?}]", self.saga_name))
+    }
+}
+
+/// Creates a new saga definition as needed by function `saga_processor`.
+pub fn create_saga_definition<S: Send + Sync + Clone + 'static>(
+    saga_name: String,
+    empty_state: Box<fn() -> S>,
+    association_resolvers: TheHandlerRegistry<(), AssociationValue>,
+    start_events: &[&str],
+    event_handler_registry: SagaEventHandlerRegistry<S>,
+) -> SagaDefinition<S> {
+    SagaDefinition {
+        saga_name,
+        empty_state,
+        association_resolvers,
+        start_events: start_events.iter().map(|e| e.to_string()).collect(),
+        event_handler_registry,
+    }
+}
+
+/// Subscribes to events and lets them be handled by the associated saga instances.
+///
+/// The progress of the saga processor is tracked in `token_store`. The saga instances are stored in `repository`.
+pub async fn saga_processor<S, R, T>(
+    axon_server_handle: AxonServerHandle,
+    saga_definition: SagaDefinition<S>,
+    repository: R,
+    token_store: T,
+) -> Result<()>
+where
+    S: Send + Sync + Clone + 'static,
+    R: SagaRepository<S> + Send + Sync + Clone + 'static,
+    T: TokenStore + Send + Sync + Clone + 'static,
+{
+    let saga_name = saga_definition.saga_name.clone();
+    let mut event_handler_registry = empty_handler_registry();
+    for event_type in saga_definition.event_handler_registry.handlers.keys() {
+        let handle: SagaManagerHandle<S, R, T> = Box::new(SagaEventHandle {
+            event_type: event_type.clone(),
+            phantom: PhantomData,
+        });
+        event_handler_registry
+            .handlers
+            .insert(event_type.clone(), handle);
+    }
+    let saga_manager = SagaManager {
+        axon_server_handle: axon_server_handle.clone(),
+        saga_definition: Arc::new(saga_definition),
+        repository,
+        token_store,
+    };
+    segmented_event_processor(
+        axon_server_handle,
+        &saga_name,
+        1,
+        saga_manager,
+        event_handler_registry,
+    )
+    .await
+}
+
+/// Serves as the query model of the event processor for a saga.
+#[derive(Clone)]
+struct SagaManager<S: Send + 'static, R, T> {
+    axon_server_handle: AxonServerHandle,
+    saga_definition: Arc<SagaDefinition<S>>,
+    repository: R,
+    token_store: T,
+}
+
+impl<S, R, T> SagaManager<S, R, T>
+where
+    S: Send + Sync + Clone + 'static,
+    R: SagaRepository<S> + Send + Sync + Clone + 'static,
+    T: TokenStore + Send + Sync + Clone + 'static,
+{
+    async fn handle_event(&self, event_type: &str, buf: Vec<u8>) -> Result<()> {
+        let saga_definition = self.saga_definition.deref();
+        let saga_name = &saga_definition.saga_name;
+        let association_value = match saga_definition.association_resolvers.get(event_type) {
+            Some(resolver) => resolver.handle(buf.clone(), ()).await?,
+            None => None,
+        };
+        let association_value = match association_value {
+            Some(association_value) => association_value,
+            None => {
+                debug!("No association value for event: {:?}", event_type);
+                return Ok(());
+            }
+        };
+        let event_handler = match saga_definition.event_handler_registry.get(event_type) {
+            Some(event_handler) => event_handler,
+            None => return Ok(()),
+        };
+
+        let mut instances = Vec::new();
+        for saga_id in self
+            .repository
+            .find_sagas(saga_name, &association_value)
+            .await?
+        {
+            if let Some(instance) = self.repository.load_saga(saga_name, &saga_id).await? {
+                instances.push(instance);
+            }
+        }
+        if instances.is_empty() && saga_definition.start_events.iter().any(|e| e == event_type) {
+            let saga_id = format!("{}", Uuid::new_v4());
+            debug!("Start saga: {:?}: {:?}", saga_name, saga_id);
+            instances.push(SagaInstance {
+                saga_id,
+                association_values: vec![association_value.clone()],
+                state: (saga_definition.empty_state)(),
+            });
+        }
+
+        let mut saga_contexts = Vec::new();
+        for instance in instances {
+            let saga_context = Arc::new(async_lock::Mutex::new(SagaContext {
+                saga_id: instance.saga_id,
+                state: instance.state,
+                association_values: instance.association_values,
+                ended: false,
+                axon_server_handle: self.axon_server_handle.clone(),
+            }));
+            event_handler
+                .handle(buf.clone(), saga_context.clone())
+                .await?;
+            saga_contexts.push(saga_context);
+        }
+
+        // Only store the instances after the event was handled by all of them, so that a failure leaves all
+        // of them as they were. Commands that were already sent are sent again when the event is retried.
+        for saga_context in saga_contexts {
+            let saga_context = saga_context.lock().await;
+            if saga_context.ended {
+                debug!("End saga: {:?}: {:?}", saga_name, saga_context.saga_id);
+                self.repository
+                    .delete_saga(saga_name, &saga_context.saga_id)
+                    .await?;
+            } else {
+                let instance = SagaInstance {
+                    saga_id: saga_context.saga_id.clone(),
+                    association_values: saga_context.association_values.clone(),
+                    state: saga_context.state.clone(),
+                };
+                self.repository.store_saga(saga_name, &instance).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl<S, R, T> TokenStore for SagaManager<S, R, T>
+where
+    S: Send + Sync + 'static,
+    R: Send + Sync,
+    T: TokenStore + Send + Sync,
+{
+    async fn store_token(&self, token: i64) {
+        self.token_store.store_token(token).await
+    }
+
+    async fn retrieve_token(&self) -> Result<i64> {
+        self.token_store.retrieve_token().await
+    }
+
+    async fn retrieve_segments(&self) -> Result<Vec<Segment>> {
+        self.token_store.retrieve_segments().await
+    }
+
+    async fn store_segment_token(&self, segment: &Segment, token: i64) {
+        self.token_store.store_segment_token(segment, token).await
+    }
+
+    async fn retrieve_segment_token(&self, segment: &Segment) -> Result<i64> {
+        self.token_store.retrieve_segment_token(segment).await
+    }
+
+    async fn claim_segment(&self, segment: &Segment, owner: &str) -> Result<bool> {
+        self.token_store.claim_segment(segment, owner).await
+    }
+
+    async fn release_segment(&self, segment: &Segment, owner: &str) -> Result<()> {
+        self.token_store.release_segment(segment, owner).await
+    }
+
+    async fn replace_segments(&self, old: &[Segment], new: &[(Segment, i64)]) -> Result<()> {
+        self.token_store.replace_segments(old, new).await
+    }
+
+    async fn reset(&self) -> Result<()> {
+        self.token_store.reset().await
+    }
+}
+
+type SagaManagerHandle<S, R, T> =
+    Box<dyn SubscriptionHandle<SagaManager<S, R, T>, Option<SagaManager<S, R, T>>>>;
+
+/// Routes events of a particular type from the event processor to the saga manager.
+struct SagaEventHandle<S: Send + 'static, R, T> {
+    event_type: String,
+    phantom: PhantomData<SagaManager<S, R, T>>,
+}
+
+impl<S: Send + 'static, R, T> Clone for SagaEventHandle<S, R, T> {
+    fn clone(&self) -> Self {
+        SagaEventHandle {
+            event_type: self.event_type.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S, R, T> SubscriptionHandle<SagaManager<S, R, T>, Option<SagaManager<S, R, T>>>
+    for SagaEventHandle<S, R, T>
+where
+    S: Send + Sync + Clone + 'static,
+    R: SagaRepository<S> + Send + Sync + Clone + 'static,
+    T: TokenStore + Send + Sync + Clone + 'static,
+{
+    fn name(&self) -> String {
+        self.event_type.clone()
+    }
+
+    async fn handle(
+        &self,
+        buf: Vec<u8>,
+        saga_manager: SagaManager<S, R, T>,
+    ) -> Result<Option<Option<SagaManager<S, R, T>>>> {
+        saga_manager.handle_event(&self.event_type, buf).await?;
+        Ok(None)
+    }
+
+    fn box_clone(&self) -> SagaManagerHandle<S, R, T> {
+        Box::from(self.clone())
+    }
+}
@@ -0,0 +1,142 @@
+/// A segment of the event stream.
+///
+/// An event belongs to a segment if the hash of its sequencing key (the aggregate identifier, or the
+/// message identifier for events that do not belong to an aggregate) masked with `mask` equals `segment_id`.
+/// The hash is the same as Java's `String.hashCode()`, so that segments are compatible with those of Axon Framework.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Segment {
+    pub segment_id: i32,
+    pub mask: i32,
+}
+
+impl Segment {
+    /// The segment that contains all events.
+    pub const ROOT: Segment = Segment {
+        segment_id: 0,
+        mask: 0,
+    };
+
+    /// Returns true if this is the segment that contains all events.
+    pub fn is_root(&self) -> bool {
+        self.mask == 0
+    }
+
+    /// The size of this segment as a fraction: 2 means 1/2, 4 means 1/4, etc.
+    pub fn one_part_of(&self) -> i32 {
+        self.mask + 1
+    }
+
+    /// Returns true if the given sequencing key belongs to this segment.
+    pub fn matches(&self, key: &str) -> bool {
+        self.mask == 0 || (java_string_hash(key) & self.mask) == self.segment_id
+    }
+
+    /// Splits this segment in two halves.
+    pub fn split(&self) -> (Segment, Segment) {
+        let mask = (self.mask << 1) + 1;
+        let first = Segment {
+            segment_id: self.segment_id,
+            mask,
+        };
+        let second = Segment {
+            segment_id: self.segment_id + self.mask + 1,
+            mask,
+        };
+        (first, second)
+    }
+
+    /// Returns the identifier of the segment that this segment can be merged with.
+    pub fn mergeable_segment_id(&self) -> i32 {
+        let parent_mask = ((self.mask as u32) >> 1) as i32;
+        let first_segment_id = self.segment_id & parent_mask;
+        if first_segment_id == self.segment_id {
+            first_segment_id + parent_mask + 1
+        } else {
+            first_segment_id
+        }
+    }
+
+    /// Returns the segment that is the union of this segment and the segment that it can be merged with.
+    pub fn merged(&self) -> Segment {
+        Segment {
+            segment_id: self.segment_id.min(self.mergeable_segment_id()),
+            mask: ((self.mask as u32) >> 1) as i32,
+        }
+    }
+}
+
+fn java_string_hash(key: &str) -> i32 {
+    key.encode_utf16()
+        .fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(segment_id: i32, mask: i32) -> Segment {
+        Segment { segment_id, mask }
+    }
+
+    #[test]
+    fn hash_matches_java_string_hash_code() {
+        assert_eq!(java_string_hash(""), 0);
+        assert_eq!(java_string_hash("a"), 97);
+        assert_eq!(java_string_hash("hello"), 99162322);
+        assert_eq!(java_string_hash("Aa"), java_string_hash("BB"));
+        assert_eq!(java_string_hash("polygenelubricants"), i32::MIN);
+        assert_eq!(java_string_hash("é"), 233);
+        assert_eq!(java_string_hash("😀"), 1772899);
+    }
+
+    #[test]
+    fn split_root_in_halves() {
+        assert_eq!(Segment::ROOT.split(), (segment(0, 1), segment(1, 1)));
+        assert_eq!(segment(1, 1).split(), (segment(1, 3), segment(3, 3)));
+        assert_eq!(segment(1, 1).one_part_of(), 2);
+        assert_eq!(segment(3, 3).one_part_of(), 4);
+    }
+
+    #[test]
+    fn siblings_merge_back() {
+        assert_eq!(segment(0, 1).mergeable_segment_id(), 1);
+        assert_eq!(segment(1, 1).mergeable_segment_id(), 0);
+        assert_eq!(segment(1, 3).mergeable_segment_id(), 3);
+        assert_eq!(segment(3, 3).mergeable_segment_id(), 1);
+        assert_eq!(segment(0, 1).merged(), Segment::ROOT);
+        assert_eq!(segment(1, 1).merged(), Segment::ROOT);
+        assert_eq!(segment(3, 3).merged(), segment(1, 1));
+        let (first, second) = segment(2, 3).split();
+        assert_eq!(first.merged(), segment(2, 3));
+        assert_eq!(second.merged(), segment(2, 3));
+    }
+
+    #[test]
+    fn matches_masked_hash() {
+        assert!(Segment::ROOT.matches("hello"));
+        assert!(segment(2, 3).matches("hello"));
+        assert!(segment(1, 3).matches("a"));
+        assert!(segment(3, 3).matches("😀"));
+        assert!(segment(0, 3).matches("polygenelubricants"));
+        assert!(!segment(0, 3).matches("hello"));
+    }
+
+    #[test]
+    fn each_key_matches_one_segment() {
+        let (first, second) = Segment::ROOT.split();
+        let (first_first, first_second) = first.split();
+        let segments = [first_first, first_second, second];
+        for key in &[
+            "",
+            "a",
+            "hello",
+            "polygenelubricants",
+            "é",
+            "😀",
+            "aggregate-1",
+        ] {
+            let matching = segments.iter().filter(|s| s.matches(key)).count();
+            assert_eq!(matching, 1, "Key: {:?}", key);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use super::VecU8Message;
+use crate::axon_server::event::event_store_client::EventStoreClient;
+use crate::axon_server::event::{Event, GetAggregateSnapshotsRequest};
+use crate::axon_server::SerializedObject;
+use crate::intellij_work_around::Debuggable;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+/// Describes when the projection of an aggregate is stored as a snapshot in AxonServer and how
+/// it is restored from a snapshot.
+///
+/// Fields:
+/// * `snapshot_type`: The type name that is used for the payload of the snapshot.
+/// * `deserializer`: Decodes the payload of a snapshot back into a projection.
+/// * `trigger`: Decides if a snapshot needs to be taken after events were stored.
+pub struct SnapshotPolicy<P: 'static> {
+    snapshot_type: String,
+    deserializer: &'static (dyn Fn(Bytes) -> Result<P, prost::DecodeError> + Sync),
+    trigger: SnapshotTrigger<P>,
+}
+
+/// Decides if a snapshot needs to be taken after events were stored.
+pub enum SnapshotTrigger<P: 'static> {
+    /// Take a snapshot each time the number of events of the aggregate passes a multiple of N.
+    EveryNEvents(i64),
+    /// Take a snapshot when the predicate holds for the new projection, the sequence number of
+    /// the last event that was stored before and the sequence number of the last event that was stored now.
+    Predicate(&'static (dyn Fn(&P, i64, i64) -> bool + Sync)),
+}
+
+impl<P: 'static> Debug for SnapshotPolicy<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("[SnapshotPolicy:{:?}]", self.snapshot_type))
+    }
+}
+
+/// Creates a snapshot policy that takes a snapshot every `n` events.
+pub fn snapshot_every_n_events<P: 'static>(
+    snapshot_type: &str,
+    deserializer: &'static (dyn Fn(Bytes) -> Result<P, prost::DecodeError> + Sync),
+    n: i64,
+) -> SnapshotPolicy<P> {
+    SnapshotPolicy {
+        snapshot_type: snapshot_type.to_string(),
+        deserializer,
+        trigger: SnapshotTrigger::EveryNEvents(n),
+    }
+}
+
+/// Creates a snapshot policy that takes a snapshot whenever `predicate` holds.
+pub fn snapshot_when<P: 'static>(
+    snapshot_type: &str,
+    deserializer: &'static (dyn Fn(Bytes) -> Result<P, prost::DecodeError> + Sync),
+    predicate: &'static (dyn Fn(&P, i64, i64) -> bool + Sync),
+) -> SnapshotPolicy<P> {
+    SnapshotPolicy {
+        snapshot_type: snapshot_type.to_string(),
+        deserializer,
+        trigger: SnapshotTrigger::Predicate(predicate),
+    }
+}
+
+impl<P: VecU8Message + 'static> SnapshotPolicy<P> {
+    fn should_take_snapshot(&self, projection: &P, previous_seq: i64, seq: i64) -> bool {
+        match self.trigger {
+            SnapshotTrigger::EveryNEvents(n) => n > 0 && (seq + 1) / n > (previous_seq + 1) / n,
+            SnapshotTrigger::Predicate(predicate) => predicate(projection, previous_seq, seq),
+        }
+    }
+
+    /// Fetches the most recent snapshot for the given aggregate and decodes it.
+    ///
+    /// AxonServer lists snapshots with the highest sequence number first, so only the first one is requested.
+    /// A snapshot of another type, e.g., after the projection was renamed, is ignored, so that the projection
+    /// is restored from all events instead.
+    ///
+    /// Returns the sequence number of the last event that is included in the snapshot together with the projection.
+    pub async fn load_snapshot(
+        &self,
+        client: &mut EventStoreClient<Channel>,
+        aggregate_id: &str,
+    ) -> Result<Option<(i64, P)>> {
+        let request = GetAggregateSnapshotsRequest {
+            aggregate_id: aggregate_id.to_string(),
+            initial_sequence: 0,
+            max_sequence: i64::MAX,
+            max_results: 1,
+        };
+        let mut stream = client.list_aggregate_snapshots(request).await?.into_inner();
+        let event = match stream.message().await? {
+            Some(event) => event,
+            None => return Ok(None),
+        };
+        debug!("Snapshot: {:?}", Debuggable::from(&event));
+        let payload = event
+            .payload
+            .ok_or_else(|| anyhow!("Snapshot without payload: {:?}", aggregate_id))?;
+        if payload.r#type != self.snapshot_type {
+            warn!(
+                "Ignored snapshot of unexpected type: {:?}: {:?}: {:?}",
+                aggregate_id, payload.r#type, self.snapshot_type
+            );
+            return Ok(None);
+        }
+        let projection = (self.deserializer)(Bytes::from(payload.data))?;
+        Ok(Some((event.aggregate_sequence_number, projection)))
+    }
+
+    /// Stores a snapshot of the projection if the policy says so.
+    pub async fn maybe_store_snapshot(
+        &self,
+        client: &mut EventStoreClient<Channel>,
+        aggregate_name: &str,
+        aggregate_id: &str,
+        projection: &P,
+        previous_seq: i64,
+        seq: i64,
+    ) -> Result<()> {
+        if !self.should_take_snapshot(projection, previous_seq, seq) {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        projection.encode_u8(&mut buf)?;
+        let now = std::time::SystemTime::now();
+        let timestamp = now.duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
+        let message_identifier = Uuid::new_v4();
+        let snapshot = Event {
+            message_identifier: format!("{}", message_identifier),
+            timestamp,
+            aggregate_identifier: aggregate_id.to_string(),
+            aggregate_sequence_number: seq,
+            aggregate_type: aggregate_name.to_string(),
+            payload: Some(SerializedObject {
+                r#type: self.snapshot_type.clone(),
+                revision: "".to_string(),
+                data: buf,
+            }),
+            meta_data: HashMap::new(),
+            snapshot: true,
+        };
+        debug!("Store snapshot: {:?}", Debuggable::from(&snapshot));
+        client.append_snapshot(snapshot).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    fn every_n_events(n: i64) -> SnapshotPolicy<SerializedObject> {
+        snapshot_every_n_events("Projection", &SerializedObject::decode, n)
+    }
+
+    fn triggers(policy: &SnapshotPolicy<SerializedObject>, previous_seq: i64, seq: i64) -> bool {
+        policy.should_take_snapshot(&SerializedObject::default(), previous_seq, seq)
+    }
+
+    #[test]
+    fn every_event_triggers_when_n_is_one() {
+        let policy = every_n_events(1);
+        assert!(triggers(&policy, -1, 0));
+        assert!(triggers(&policy, 0, 1));
+        assert!(triggers(&policy, 41, 42));
+    }
+
+    #[test]
+    fn triggers_only_when_crossing_a_multiple_of_n() {
+        let policy = every_n_events(3);
+        assert!(!triggers(&policy, -1, 0));
+        assert!(!triggers(&policy, 0, 1));
+        assert!(triggers(&policy, 1, 2));
+        assert!(!triggers(&policy, 2, 3));
+        assert!(!triggers(&policy, 3, 4));
+        assert!(triggers(&policy, 4, 5));
+    }
+
+    #[test]
+    fn triggers_once_for_several_events_in_one_command() {
+        let policy = every_n_events(3);
+        assert!(triggers(&policy, -1, 4));
+        assert!(!triggers(&policy, 2, 4));
+        assert!(triggers(&policy, 3, 8));
+        assert!(triggers(&policy, 1, 7));
+    }
+
+    #[test]
+    fn never_triggers_when_n_is_not_positive() {
+        assert!(!triggers(&every_n_events(0), -1, 10));
+        assert!(!triggers(&every_n_events(-1), -1, 10));
+    }
+}
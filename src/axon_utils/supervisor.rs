@@ -0,0 +1,188 @@
+use super::command_worker::{command_worker, TheAggregateRegistry};
+use super::connection::reconnect;
+use super::AxonServerHandle;
+use anyhow::Result;
+use futures_core::Future;
+use log::{debug, warn};
+use std::cmp::min;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+/// Signal that tells supervised workers to drop their streams and reconnect to AxonServer.
+///
+/// The platform worker raises it when AxonServer sends a `RequestReconnect` instruction, or
+/// when heartbeats stop and the connection is marked unhealthy.
+#[derive(Debug, Clone)]
+pub struct ReconnectSignal {
+    sender: Arc<watch::Sender<u64>>,
+    receiver: watch::Receiver<u64>,
+    healthy: Arc<AtomicBool>,
+}
+
+impl Default for ReconnectSignal {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(0);
+        ReconnectSignal {
+            sender: Arc::new(sender),
+            receiver,
+            healthy: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl ReconnectSignal {
+    /// Asks all supervised workers that share this signal to reconnect.
+    pub fn request_reconnect(&self) {
+        let generation = *self.receiver.borrow() + 1;
+        self.sender.send(generation).ok();
+    }
+
+    /// Returns false when the connection was marked unhealthy and no new connection was established since.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Marks the connection unhealthy and asks all supervised workers to reconnect.
+    pub fn mark_unhealthy(&self) {
+        self.healthy.store(false, Ordering::SeqCst);
+        self.request_reconnect();
+    }
+
+    pub(crate) fn mark_healthy(&self) {
+        self.healthy.store(true, Ordering::SeqCst);
+    }
+
+    async fn requested(&mut self) {
+        if self.receiver.changed().await.is_err() {
+            futures_util::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Describes how long a supervisor waits before it reconnects to AxonServer.
+///
+/// The delay starts at `initial_delay` and doubles after each failed attempt up to `max_delay`.
+/// It is reset when a worker ran for at least `max_delay` before it stopped.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+struct Supervisor {
+    axon_server_handle: AxonServerHandle,
+    worker_name: String,
+    backoff: Backoff,
+    delay: Duration,
+    reconnect_signal: ReconnectSignal,
+}
+
+impl Supervisor {
+    fn new(axon_server_handle: AxonServerHandle, worker_name: &str, backoff: Backoff) -> Self {
+        let reconnect_signal = axon_server_handle.reconnect_signal.clone();
+        Supervisor {
+            axon_server_handle,
+            worker_name: worker_name.to_string(),
+            backoff,
+            delay: backoff.initial_delay,
+            reconnect_signal,
+        }
+    }
+
+    /// Runs the worker until it stops or until a reconnect is requested.
+    ///
+    /// Returns true if the worker is done. Otherwise, a new connection is established
+    /// and the worker needs to be started again.
+    async fn run<F: Future<Output = Result<()>>>(&mut self, worker: F) -> bool {
+        let started = Instant::now();
+        let outcome = tokio::select! {
+            result = worker => Some(result),
+            _ = self.reconnect_signal.requested() => None,
+        };
+        match outcome {
+            Some(Ok(())) => {
+                debug!("{}: done", self.worker_name);
+                return true;
+            }
+            Some(Err(e)) => warn!("{}: stopped with error: {:?}", self.worker_name, e),
+            None => debug!("{}: reconnect requested", self.worker_name),
+        }
+        if started.elapsed() >= self.backoff.max_delay {
+            self.delay = self.backoff.initial_delay;
+        }
+        loop {
+            debug!("{}: reconnect in {:?}", self.worker_name, self.delay);
+            sleep(self.delay).await;
+            self.delay = min(self.delay * 2, self.backoff.max_delay);
+            if let Some(axon_server_handle) = reconnect(&self.axon_server_handle).await {
+                self.axon_server_handle = axon_server_handle;
+                return false;
+            }
+        }
+    }
+}
+
+/// Runs a worker and restarts it on a fresh connection to AxonServer whenever it stops with an error
+/// or AxonServer requests a reconnect.
+///
+/// The worker is created anew for each connection by calling `worker` with a handle that holds the new
+/// channel (client id, event processor registry, etc. are shared with `axon_server_handle`). Restarting a
+/// worker re-sends its command or query subscriptions, and event processors resume from the last token
+/// in their token store. An event processor that is stopped for a reconnect unregisters itself and
+/// releases its segment claims. Returns when the worker finishes without error.
+///
+/// ```rust,ignore
+/// supervise(axon_server_handle, "Greeting event processor", Backoff::default(), |handle| {
+///     event_processor(handle, "Greeting", query_model.clone(), event_handler_registry.clone())
+/// }).await?;
+/// ```
+pub async fn supervise<F, Fut>(
+    axon_server_handle: AxonServerHandle,
+    worker_name: &str,
+    backoff: Backoff,
+    worker: F,
+) -> Result<()>
+where
+    F: Fn(AxonServerHandle) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut supervisor = Supervisor::new(axon_server_handle, worker_name, backoff);
+    loop {
+        let axon_server_handle = supervisor.axon_server_handle.clone();
+        if supervisor.run(worker(axon_server_handle)).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs a command worker that is restarted on a fresh connection to AxonServer whenever it stops.
+///
+/// See function `supervise`.
+pub async fn supervised_command_worker(
+    axon_server_handle: AxonServerHandle,
+    aggregate_registry: &mut TheAggregateRegistry,
+    backoff: Backoff,
+) -> Result<()> {
+    let mut supervisor = Supervisor::new(axon_server_handle, "Command worker", backoff);
+    loop {
+        let axon_server_handle = supervisor.axon_server_handle.clone();
+        if supervisor
+            .run(command_worker(axon_server_handle, aggregate_registry))
+            .await
+        {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::axon_server::event::Event;
+use crate::axon_server::SerializedObject;
+use anyhow::{anyhow, Result};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Converts the serialized payload of an event from one revision (and possibly type name) to the next.
+pub type Upcaster = &'static (dyn Fn(SerializedObject) -> Result<SerializedObject> + Sync);
+
+/// Registry of upcasters, keyed on the type name and revision of the payload that they convert.
+///
+/// Upcasters are applied in a chain: the output of one upcaster is looked up again, until no upcaster
+/// is registered for its type name and revision.
+#[derive(Clone, Default)]
+pub struct UpcasterRegistry {
+    upcasters: Arc<HashMap<(String, String), Upcaster>>,
+}
+
+impl Debug for UpcasterRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "[UpcasterRegistry:{:?}]",
+            self.upcasters.keys().collect::<Vec<_>>()
+        ))
+    }
+}
+
+/// Creates an empty upcaster registry that can be populated with upcasters.
+pub fn empty_upcaster_registry() -> UpcasterRegistry {
+    UpcasterRegistry::default()
+}
+
+impl UpcasterRegistry {
+    /// Registers an upcaster for payloads with the given type name and revision.
+    pub fn insert(&mut self, event_type: &str, revision: &str, upcaster: Upcaster) -> Result<()> {
+        let key = (event_type.to_string(), revision.to_string());
+        let upcasters = Arc::make_mut(&mut self.upcasters);
+        if upcasters.contains_key(&key) {
+            return Err(anyhow!("Upcaster already registered: {:?}", key));
+        }
+        upcasters.insert(key, upcaster);
+        Ok(())
+    }
+
+    /// Applies the chain of upcasters to the given payload.
+    pub fn upcast(&self, payload: SerializedObject) -> Result<SerializedObject> {
+        let mut payload = payload;
+        let mut visited = HashSet::new();
+        loop {
+            let key = (payload.r#type.clone(), payload.revision.clone());
+            let upcaster = match self.upcasters.get(&key) {
+                Some(upcaster) => upcaster,
+                None => return Ok(payload),
+            };
+            if !visited.insert(key.clone()) {
+                return Err(anyhow!("Cycle in upcasters: {:?}", key));
+            }
+            payload = upcaster(payload)?;
+            debug!(
+                "Upcasted: {:?}: {:?}: {:?}",
+                key, payload.r#type, payload.revision
+            );
+        }
+    }
+
+    /// Applies the chain of upcasters to the payload of the given event.
+    pub fn upcast_event(&self, event: Event) -> Result<Event> {
+        if self.upcasters.is_empty() {
+            return Ok(event);
+        }
+        let payload = match event.payload {
+            Some(payload) => Some(self.upcast(payload)?),
+            None => None,
+        };
+        Ok(Event { payload, ..event })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(event_type: &str, revision: &str, data: &[u8]) -> SerializedObject {
+        SerializedObject {
+            r#type: event_type.to_string(),
+            revision: revision.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Revision 1 appends a byte to the data.
+    fn to_revision_1(payload: SerializedObject) -> Result<SerializedObject> {
+        let mut data = payload.data;
+        data.push(1);
+        Ok(SerializedObject {
+            revision: "1".to_string(),
+            data,
+            ..payload
+        })
+    }
+
+    /// Revision 2 renames the type.
+    fn to_revision_2(payload: SerializedObject) -> Result<SerializedObject> {
+        Ok(SerializedObject {
+            r#type: "GreetedEvent".to_string(),
+            revision: "2".to_string(),
+            ..payload
+        })
+    }
+
+    fn back_to_revision_1(payload: SerializedObject) -> Result<SerializedObject> {
+        Ok(SerializedObject {
+            r#type: "GreetingEvent".to_string(),
+            revision: "1".to_string(),
+            ..payload
+        })
+    }
+
+    fn registry() -> UpcasterRegistry {
+        let mut upcasters = empty_upcaster_registry();
+        upcasters
+            .insert("GreetingEvent", "", &to_revision_1)
+            .unwrap();
+        upcasters
+            .insert("GreetingEvent", "1", &to_revision_2)
+            .unwrap();
+        upcasters
+    }
+
+    #[test]
+    fn upcasters_are_chained() -> Result<()> {
+        let upcasted = registry().upcast(payload("GreetingEvent", "", &[0]))?;
+        assert_eq!(upcasted, payload("GreetedEvent", "2", &[0, 1]));
+        let upcasted = registry().upcast(payload("GreetingEvent", "1", &[0]))?;
+        assert_eq!(upcasted, payload("GreetedEvent", "2", &[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_revision_is_passed_through() -> Result<()> {
+        let current = payload("GreetedEvent", "2", &[0]);
+        assert_eq!(registry().upcast(current.clone())?, current);
+        let unknown = payload("GreetingEvent", "7", &[0]);
+        assert_eq!(registry().upcast(unknown.clone())?, unknown);
+        Ok(())
+    }
+
+    #[test]
+    fn upcast_event_keeps_the_event() -> Result<()> {
+        let event = Event {
+            aggregate_identifier: "greeter-1".to_string(),
+            aggregate_sequence_number: 3,
+            payload: Some(payload("GreetingEvent", "1", &[0])),
+            ..Event::default()
+        };
+        let upcasted = registry().upcast_event(event)?;
+        assert_eq!(upcasted.aggregate_identifier, "greeter-1");
+        assert_eq!(upcasted.aggregate_sequence_number, 3);
+        assert_eq!(upcasted.payload, Some(payload("GreetedEvent", "2", &[0])));
+        Ok(())
+    }
+
+    #[test]
+    fn cycle_is_an_error() {
+        let mut upcasters = registry();
+        upcasters
+            .insert("GreetedEvent", "2", &back_to_revision_1)
+            .unwrap();
+        let error = upcasters
+            .upcast(payload("GreetingEvent", "", &[0]))
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Cycle in upcasters"));
+    }
+
+    #[test]
+    fn duplicate_registration_is_an_error() {
+        let mut upcasters = registry();
+        assert!(upcasters
+            .insert("GreetingEvent", "1", &back_to_revision_1)
+            .is_err());
+        assert_eq!(
+            upcasters
+                .upcast(payload("GreetingEvent", "1", &[0]))
+                .unwrap(),
+            payload("GreetedEvent", "2", &[0])
+        );
+    }
+}
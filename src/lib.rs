@@ -7,6 +7,8 @@
 pub mod axon_server;
 pub mod axon_utils;
 pub mod intellij_work_around;
+#[cfg(feature = "test-server")]
+pub mod test_server;
 
 #[macro_export]
 macro_rules! register {
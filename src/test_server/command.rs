@@ -0,0 +1,198 @@
+use super::{
+    error_message, instruction_ack, processing_instruction_value, response_stream, Outbox,
+    ResponseStream,
+};
+use crate::axon_server::command::command_service_server::CommandService;
+use crate::axon_server::command::{
+    command_provider_inbound, command_provider_outbound, Command, CommandProviderInbound,
+    CommandProviderOutbound, CommandResponse,
+};
+use crate::axon_server::common::meta_data_value::Data;
+use crate::axon_server::common::ProcessingKey;
+use log::debug;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+const NO_HANDLER_FOR_COMMAND: &str = "AXONIQ-4000";
+const COMMAND_DISPATCH_ERROR: &str = "AXONIQ-4003";
+
+/// Command service of the test server.
+///
+/// Commands are routed to one of the command workers that subscribed to them. Like AxonServer, the test
+/// server uses the routing key to pick the worker, so all commands for an aggregate end up at the same worker.
+#[derive(Clone, Default)]
+pub(crate) struct TestCommandService {
+    state: Arc<Mutex<CommandRouter>>,
+}
+
+#[derive(Default)]
+struct CommandRouter {
+    next_provider_id: u64,
+    providers: HashMap<u64, CommandProvider>,
+    pending: HashMap<String, PendingCommand>,
+}
+
+struct CommandProvider {
+    commands: HashSet<String>,
+    outbox: Outbox<CommandProviderInbound>,
+}
+
+struct PendingCommand {
+    provider_id: u64,
+    sender: oneshot::Sender<CommandResponse>,
+}
+
+impl CommandRouter {
+    fn handle_instruction(&mut self, provider_id: u64, instruction: CommandProviderOutbound) {
+        let provider = match self.providers.get_mut(&provider_id) {
+            Some(provider) => provider,
+            None => return,
+        };
+        match instruction.request {
+            Some(command_provider_outbound::Request::Subscribe(subscription)) => {
+                debug!("Test server: subscribe command: {:?}", subscription.command);
+                provider.commands.insert(subscription.command);
+            }
+            Some(command_provider_outbound::Request::Unsubscribe(subscription)) => {
+                provider.commands.remove(&subscription.command);
+            }
+            Some(command_provider_outbound::Request::FlowControl(flow_control)) => {
+                provider.outbox.add_permits(flow_control.permits);
+            }
+            Some(command_provider_outbound::Request::CommandResponse(response)) => {
+                if let Some(pending) = self.pending.remove(&response.request_identifier) {
+                    pending.sender.send(response).ok();
+                }
+            }
+            _ => (),
+        }
+        if !instruction.instruction_id.is_empty() {
+            provider.outbox.send_now(CommandProviderInbound {
+                instruction_id: "".to_string(),
+                request: Some(command_provider_inbound::Request::Ack(instruction_ack(
+                    instruction.instruction_id,
+                ))),
+            });
+        }
+    }
+
+    fn disconnect(&mut self, provider_id: u64) {
+        self.providers.remove(&provider_id);
+        self.pending
+            .retain(|_, pending| pending.provider_id != provider_id);
+    }
+
+    fn route(&mut self, command: Command) -> Option<oneshot::Receiver<CommandResponse>> {
+        let mut candidates: Vec<u64> = self
+            .providers
+            .iter()
+            .filter(|(_, provider)| provider.commands.contains(&command.name))
+            .map(|(provider_id, _)| *provider_id)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        candidates.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        match processing_instruction_value(
+            &command.processing_instructions,
+            ProcessingKey::RoutingKey,
+        ) {
+            Some(Data::TextValue(routing_key)) => routing_key.hash(&mut hasher),
+            _ => command.message_identifier.hash(&mut hasher),
+        }
+        let provider_id = candidates[(hasher.finish() % candidates.len() as u64) as usize];
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(
+            command.message_identifier.clone(),
+            PendingCommand {
+                provider_id,
+                sender,
+            },
+        );
+        let provider = self.providers.get_mut(&provider_id)?;
+        provider.outbox.push(CommandProviderInbound {
+            instruction_id: "".to_string(),
+            request: Some(command_provider_inbound::Request::Command(command)),
+        });
+        Some(receiver)
+    }
+}
+
+#[tonic::async_trait]
+impl CommandService for TestCommandService {
+    type OpenStreamStream = ResponseStream<CommandProviderInbound>;
+
+    async fn open_stream(
+        &self,
+        request: Request<Streaming<CommandProviderOutbound>>,
+    ) -> Result<Response<Self::OpenStreamStream>, Status> {
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let provider_id = {
+            let mut router = self.state.lock().unwrap();
+            let provider_id = router.next_provider_id;
+            router.next_provider_id += 1;
+            router.providers.insert(
+                provider_id,
+                CommandProvider {
+                    commands: HashSet::new(),
+                    outbox: Outbox::new(tx),
+                },
+            );
+            provider_id
+        };
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(instruction)) = inbound.message().await {
+                state
+                    .lock()
+                    .unwrap()
+                    .handle_instruction(provider_id, instruction);
+            }
+            debug!(
+                "Test server: command provider disconnected: {:?}",
+                provider_id
+            );
+            state.lock().unwrap().disconnect(provider_id);
+        });
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    async fn dispatch(
+        &self,
+        request: Request<Command>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let command = request.into_inner();
+        let message_identifier = command.message_identifier.clone();
+        let name = command.name.clone();
+        let receiver = self.state.lock().unwrap().route(command);
+        let (error_code, message) = match receiver {
+            Some(receiver) => match receiver.await {
+                Ok(response) => return Ok(Response::new(response)),
+                Err(_) => (
+                    COMMAND_DISPATCH_ERROR,
+                    format!("Command handler disconnected: {:?}", name),
+                ),
+            },
+            None => (
+                NO_HANDLER_FOR_COMMAND,
+                format!("No handler for command: {:?}", name),
+            ),
+        };
+        Ok(Response::new(CommandResponse {
+            message_identifier: format!("{}", Uuid::new_v4()),
+            error_code: error_code.to_string(),
+            error_message: Some(error_message(error_code, message)),
+            payload: None,
+            meta_data: HashMap::new(),
+            processing_instructions: Vec::new(),
+            request_identifier: message_identifier,
+        }))
+    }
+}
@@ -0,0 +1,355 @@
+use super::{response_stream, ResponseStream};
+use crate::axon_server::event::event_store_server::EventStore;
+use crate::axon_server::event::{
+    Confirmation, Event, EventWithToken, GetAggregateEventsRequest, GetAggregateSnapshotsRequest,
+    GetEventsRequest, GetFirstTokenRequest, GetLastTokenRequest, GetTokenAtRequest,
+    QueryEventsRequest, QueryEventsResponse, ReadHighestSequenceNrRequest,
+    ReadHighestSequenceNrResponse, TrackingToken,
+};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Request, Response, Status, Streaming};
+
+const INVALID_SEQUENCE: &str = "AXONIQ-2000";
+
+/// Event store of the test server. Events and snapshots are kept in memory.
+#[derive(Clone)]
+pub(crate) struct TestEventStore {
+    storage: Arc<Mutex<EventStorage>>,
+    appended: Arc<watch::Sender<i64>>,
+    appended_receiver: watch::Receiver<i64>,
+}
+
+#[derive(Default)]
+struct EventStorage {
+    events: Vec<Event>,
+    snapshots: Vec<Event>,
+    highest_sequence_numbers: HashMap<String, i64>,
+}
+
+impl Default for TestEventStore {
+    fn default() -> Self {
+        let (appended, appended_receiver) = watch::channel(-1);
+        TestEventStore {
+            storage: Arc::new(Mutex::new(EventStorage::default())),
+            appended: Arc::new(appended),
+            appended_receiver,
+        }
+    }
+}
+
+impl TestEventStore {
+    pub(crate) fn events(&self) -> Vec<Event> {
+        self.storage.lock().unwrap().events.clone()
+    }
+
+    pub(crate) fn snapshots(&self) -> Vec<Event> {
+        self.storage.lock().unwrap().snapshots.clone()
+    }
+}
+
+impl EventStorage {
+    /// Returns an error unless the events continue the sequences of their aggregates.
+    fn check_sequence_numbers(&self, events: &[Event]) -> Option<Status> {
+        let mut highest = HashMap::new();
+        for event in events {
+            if event.aggregate_identifier.is_empty() {
+                continue;
+            }
+            let aggregate_id = &event.aggregate_identifier;
+            let expected = highest
+                .get(aggregate_id)
+                .or_else(|| self.highest_sequence_numbers.get(aggregate_id))
+                .map(|sequence_number| sequence_number + 1)
+                .unwrap_or(0);
+            if event.aggregate_sequence_number != expected {
+                return Some(invalid_sequence(
+                    aggregate_id,
+                    event.aggregate_sequence_number,
+                    expected,
+                ));
+            }
+            highest.insert(aggregate_id, expected);
+        }
+        None
+    }
+
+    /// Appends the events and returns the token of the last one.
+    fn append(&mut self, events: Vec<Event>) -> i64 {
+        for event in &events {
+            if !event.aggregate_identifier.is_empty() {
+                self.highest_sequence_numbers.insert(
+                    event.aggregate_identifier.clone(),
+                    event.aggregate_sequence_number,
+                );
+            }
+        }
+        self.events.extend(events);
+        self.events.len() as i64 - 1
+    }
+
+    fn aggregate_events(&self, request: &GetAggregateEventsRequest) -> Vec<Event> {
+        let max_sequence = match request.max_sequence {
+            max_sequence if max_sequence > 0 => max_sequence,
+            _ => i64::MAX,
+        };
+        let mut initial_sequence = request.initial_sequence;
+        let mut result = Vec::new();
+        if request.allow_snapshots {
+            let snapshot = self
+                .snapshots
+                .iter()
+                .filter(|snapshot| snapshot.aggregate_identifier == request.aggregate_id)
+                .filter(|snapshot| snapshot.aggregate_sequence_number >= initial_sequence)
+                .filter(|snapshot| snapshot.aggregate_sequence_number <= max_sequence)
+                .max_by_key(|snapshot| snapshot.aggregate_sequence_number);
+            if let Some(snapshot) = snapshot {
+                initial_sequence = snapshot.aggregate_sequence_number + 1;
+                result.push(snapshot.clone());
+            }
+        }
+        result.extend(
+            self.events
+                .iter()
+                .filter(|event| event.aggregate_identifier == request.aggregate_id)
+                .filter(|event| event.aggregate_sequence_number >= initial_sequence)
+                .filter(|event| event.aggregate_sequence_number <= max_sequence)
+                .cloned(),
+        );
+        result
+    }
+
+    fn aggregate_snapshots(&self, request: &GetAggregateSnapshotsRequest) -> Vec<Event> {
+        let max_sequence = match request.max_sequence {
+            max_sequence if max_sequence > 0 => max_sequence,
+            _ => i64::MAX,
+        };
+        let max_results = match request.max_results {
+            max_results if max_results > 0 => max_results as usize,
+            _ => usize::MAX,
+        };
+        let mut result: Vec<Event> = self
+            .snapshots
+            .iter()
+            .filter(|snapshot| snapshot.aggregate_identifier == request.aggregate_id)
+            .filter(|snapshot| snapshot.aggregate_sequence_number >= request.initial_sequence)
+            .filter(|snapshot| snapshot.aggregate_sequence_number <= max_sequence)
+            .cloned()
+            .collect();
+        result.sort_by_key(|snapshot| -snapshot.aggregate_sequence_number);
+        result.truncate(max_results);
+        result
+    }
+}
+
+fn invalid_sequence(aggregate_id: &str, sequence_number: i64, expected: i64) -> Status {
+    let mut meta_data = MetadataMap::new();
+    meta_data.insert(
+        "axoniq-errorcode",
+        MetadataValue::from_static(INVALID_SEQUENCE),
+    );
+    Status::with_metadata(
+        Code::OutOfRange,
+        format!(
+            "Invalid sequence number for aggregate {:?}: {:?}, expected: {:?}",
+            aggregate_id, sequence_number, expected
+        ),
+        meta_data,
+    )
+}
+
+fn tracking_token(token: i64) -> Response<TrackingToken> {
+    Response::new(TrackingToken { token })
+}
+
+#[tonic::async_trait]
+impl EventStore for TestEventStore {
+    async fn append_event(
+        &self,
+        request: Request<Streaming<Event>>,
+    ) -> Result<Response<Confirmation>, Status> {
+        let mut inbound = request.into_inner();
+        let mut events = Vec::new();
+        while let Some(event) = inbound.message().await? {
+            events.push(event);
+        }
+        let last_token = {
+            let mut storage = self.storage.lock().unwrap();
+            if let Some(status) = storage.check_sequence_numbers(&events) {
+                return Err(status);
+            }
+            storage.append(events)
+        };
+        debug!("Test server: appended events: last token: {:?}", last_token);
+        self.appended.send(last_token).ok();
+        Ok(Response::new(Confirmation { success: true }))
+    }
+
+    async fn append_snapshot(
+        &self,
+        request: Request<Event>,
+    ) -> Result<Response<Confirmation>, Status> {
+        let snapshot = Event {
+            snapshot: true,
+            ..request.into_inner()
+        };
+        self.storage.lock().unwrap().snapshots.push(snapshot);
+        Ok(Response::new(Confirmation { success: true }))
+    }
+
+    type ListAggregateEventsStream = ResponseStream<Event>;
+
+    async fn list_aggregate_events(
+        &self,
+        request: Request<GetAggregateEventsRequest>,
+    ) -> Result<Response<Self::ListAggregateEventsStream>, Status> {
+        let events = self
+            .storage
+            .lock()
+            .unwrap()
+            .aggregate_events(request.get_ref());
+        let (tx, rx) = mpsc::unbounded_channel();
+        for event in events {
+            tx.send(Ok(event)).ok();
+        }
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    type ListAggregateSnapshotsStream = ResponseStream<Event>;
+
+    async fn list_aggregate_snapshots(
+        &self,
+        request: Request<GetAggregateSnapshotsRequest>,
+    ) -> Result<Response<Self::ListAggregateSnapshotsStream>, Status> {
+        let snapshots = self
+            .storage
+            .lock()
+            .unwrap()
+            .aggregate_snapshots(request.get_ref());
+        let (tx, rx) = mpsc::unbounded_channel();
+        for snapshot in snapshots {
+            tx.send(Ok(snapshot)).ok();
+        }
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    type ListEventsStream = ResponseStream<EventWithToken>;
+
+    async fn list_events(
+        &self,
+        request: Request<Streaming<GetEventsRequest>>,
+    ) -> Result<Response<Self::ListEventsStream>, Status> {
+        let mut inbound = request.into_inner();
+        let storage = self.storage.clone();
+        let mut appended = self.appended_receiver.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut next_token: Option<i64> = None;
+            let mut permits: i64 = 0;
+            loop {
+                if let Some(token) = next_token.as_mut() {
+                    while permits > 0 {
+                        let event = storage.lock().unwrap().events.get(*token as usize).cloned();
+                        let event = match event {
+                            Some(event) => event,
+                            None => break,
+                        };
+                        let event_with_token = EventWithToken {
+                            token: *token,
+                            event: Some(event),
+                        };
+                        if tx.send(Ok(event_with_token)).is_err() {
+                            return;
+                        }
+                        *token += 1;
+                        permits -= 1;
+                    }
+                }
+                tokio::select! {
+                    request = inbound.message() => match request {
+                        Ok(Some(request)) => {
+                            if next_token.is_none() {
+                                debug!("Test server: list events: {:?}: {:?}", request.processor, request.tracking_token);
+                                next_token = Some(request.tracking_token.max(0));
+                            }
+                            permits += request.number_of_permits;
+                        }
+                        _ => break,
+                    },
+                    changed = appended.changed() => if changed.is_err() {
+                        break;
+                    },
+                }
+            }
+            debug!("Test server: list events: closed");
+        });
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    async fn read_highest_sequence_nr(
+        &self,
+        request: Request<ReadHighestSequenceNrRequest>,
+    ) -> Result<Response<ReadHighestSequenceNrResponse>, Status> {
+        let to_sequence_nr = self
+            .storage
+            .lock()
+            .unwrap()
+            .highest_sequence_numbers
+            .get(&request.get_ref().aggregate_id)
+            .cloned()
+            .unwrap_or(-1);
+        Ok(Response::new(ReadHighestSequenceNrResponse {
+            to_sequence_nr,
+        }))
+    }
+
+    type QueryEventsStream = ResponseStream<QueryEventsResponse>;
+
+    async fn query_events(
+        &self,
+        _request: Request<Streaming<QueryEventsRequest>>,
+    ) -> Result<Response<Self::QueryEventsStream>, Status> {
+        Err(Status::unimplemented(
+            "Ad-hoc event store queries are not supported by the test server",
+        ))
+    }
+
+    async fn get_first_token(
+        &self,
+        _request: Request<GetFirstTokenRequest>,
+    ) -> Result<Response<TrackingToken>, Status> {
+        if self.storage.lock().unwrap().events.is_empty() {
+            Ok(tracking_token(-1))
+        } else {
+            Ok(tracking_token(0))
+        }
+    }
+
+    async fn get_last_token(
+        &self,
+        _request: Request<GetLastTokenRequest>,
+    ) -> Result<Response<TrackingToken>, Status> {
+        let storage = self.storage.lock().unwrap();
+        Ok(tracking_token(storage.events.len() as i64 - 1))
+    }
+
+    async fn get_token_at(
+        &self,
+        request: Request<GetTokenAtRequest>,
+    ) -> Result<Response<TrackingToken>, Status> {
+        let instant = request.get_ref().instant;
+        let storage = self.storage.lock().unwrap();
+        let events = &storage.events;
+        if events.is_empty() {
+            return Ok(tracking_token(-1));
+        }
+        let token = events
+            .iter()
+            .position(|event| event.timestamp >= instant)
+            .unwrap_or(events.len());
+        Ok(tracking_token(token as i64))
+    }
+}
@@ -0,0 +1,211 @@
+//! # Test Server
+//!
+//! Module `test_server` (feature `test-server`) provides an in-process stand-in for AxonServer, so that
+//! command workers, query processors and event processors can be exercised end to end in `cargo test`.
+//!
+//! The test server keeps everything in memory. It routes commands and queries between the connected
+//! clients (respecting their flow control permits), and it stores events and snapshots with tokens.
+//! It does not support subscription queries, ad-hoc event store queries or the event scheduler.
+//!
+//! ```ignore
+//! let test_server = start_test_server().await?;
+//! let axon_server_handle = test_server.connect("test").await?;
+//! tokio::spawn(command_worker(axon_server_handle.clone(), &mut aggregate_registry));
+//! axon_server_handle.send_command("GreetCommand", Box::new(&command)).await?;
+//! assert_eq!(test_server.events().len(), 1);
+//! ```
+
+use crate::axon_server::command::command_service_server::CommandServiceServer;
+use crate::axon_server::common::meta_data_value::Data;
+use crate::axon_server::common::{
+    ErrorMessage, InstructionAck, MetaDataValue, ProcessingInstruction, ProcessingKey,
+};
+use crate::axon_server::control::platform_service_server::PlatformServiceServer;
+use crate::axon_server::event::event_store_server::EventStoreServer;
+use crate::axon_server::event::Event;
+use crate::axon_server::query::query_service_server::QueryServiceServer;
+use crate::axon_utils::{axon_server_handle_builder, AxonServerHandle};
+use anyhow::Result;
+use async_stream::stream;
+use futures_core::stream::Stream;
+use log::{debug, warn};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tonic::transport::Server;
+use tonic::Status;
+
+mod command;
+mod event_store;
+mod platform;
+mod query;
+
+use command::TestCommandService;
+use event_store::TestEventStore;
+use platform::TestPlatformService;
+use query::TestQueryService;
+
+const TEST_SERVER_HOST: &str = "127.0.0.1";
+const TEST_SERVER_LOCATION: &str = "test-server";
+
+/// Stream of messages from the test server to a client.
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync>>;
+
+/// A running test server. The server stops when this struct is dropped.
+pub struct TestServer {
+    port: u16,
+    event_store: TestEventStore,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+/// Starts a test server on a free port of the loopback interface.
+pub async fn start_test_server() -> Result<TestServer> {
+    let listener = TcpListener::bind((TEST_SERVER_HOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    debug!("Test server: start: {:?}", port);
+    let event_store = TestEventStore::default();
+    let router = Server::builder()
+        .add_service(PlatformServiceServer::new(TestPlatformService::new(port)))
+        .add_service(CommandServiceServer::new(TestCommandService::default()))
+        .add_service(QueryServiceServer::new(TestQueryService::default()))
+        .add_service(EventStoreServer::new(event_store.clone()));
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+    let incoming = stream! {
+        loop {
+            yield listener.accept().await.map(|(socket, _)| socket);
+        }
+    };
+    tokio::spawn(async move {
+        let signal = async {
+            shutdown_signal.await.ok();
+        };
+        if let Err(e) = router.serve_with_incoming_shutdown(incoming, signal).await {
+            warn!("Test server stopped: {:?}", e);
+        }
+    });
+    Ok(TestServer {
+        port,
+        event_store,
+        shutdown: Some(shutdown),
+    })
+}
+
+impl TestServer {
+    /// Returns the port on which the test server listens.
+    pub fn port(&self) -> u32 {
+        self.port as u32
+    }
+
+    /// Connects to the test server.
+    pub async fn connect(&self, label: &str) -> Result<AxonServerHandle> {
+        axon_server_handle_builder(label)
+            .with_server(TEST_SERVER_HOST, self.port())
+            .connect()
+            .await
+    }
+
+    /// Returns all events that were appended to the event store, in the order of their tokens.
+    pub fn events(&self) -> Vec<Event> {
+        self.event_store.events()
+    }
+
+    /// Returns all snapshots that were appended to the event store.
+    pub fn snapshots(&self) -> Vec<Event> {
+        self.event_store.snapshots()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+/// Messages for a client that are held back until the client grants permits.
+struct Outbox<T> {
+    sender: UnboundedSender<Result<T, Status>>,
+    permits: i64,
+    queue: VecDeque<T>,
+}
+
+impl<T> Outbox<T> {
+    fn new(sender: UnboundedSender<Result<T, Status>>) -> Self {
+        Outbox {
+            sender,
+            permits: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Sends a message that does not need a permit.
+    fn send_now(&self, message: T) {
+        self.sender.send(Ok(message)).ok();
+    }
+
+    /// Sends a message as soon as the client has a permit for it.
+    fn push(&mut self, message: T) {
+        self.queue.push_back(message);
+        self.flush();
+    }
+
+    fn add_permits(&mut self, permits: i64) {
+        self.permits += permits;
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        while self.permits > 0 {
+            match self.queue.pop_front() {
+                Some(message) => {
+                    self.permits -= 1;
+                    self.sender.send(Ok(message)).ok();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn response_stream<T: Send + Sync + Unpin + 'static>(
+    mut receiver: UnboundedReceiver<Result<T, Status>>,
+) -> ResponseStream<T> {
+    Box::pin(stream! {
+        while let Some(message) = receiver.recv().await {
+            yield message;
+        }
+    })
+}
+
+fn error_message(error_code: &str, message: String) -> ErrorMessage {
+    ErrorMessage {
+        message,
+        location: TEST_SERVER_LOCATION.to_string(),
+        details: Vec::new(),
+        error_code: error_code.to_string(),
+    }
+}
+
+fn instruction_ack(instruction_id: String) -> InstructionAck {
+    InstructionAck {
+        instruction_id,
+        success: true,
+        error: None,
+    }
+}
+
+fn processing_instruction_value(
+    instructions: &[ProcessingInstruction],
+    key: ProcessingKey,
+) -> Option<&Data> {
+    instructions
+        .iter()
+        .find(|instruction| instruction.key == key as i32)
+        .and_then(|instruction| match &instruction.value {
+            Some(MetaDataValue { data }) => data.as_ref(),
+            None => None,
+        })
+}
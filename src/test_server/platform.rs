@@ -0,0 +1,73 @@
+use super::{instruction_ack, response_stream, ResponseStream, TEST_SERVER_HOST};
+use crate::axon_server::control::platform_service_server::PlatformService;
+use crate::axon_server::control::{
+    platform_inbound_instruction, platform_outbound_instruction, ClientIdentification, NodeInfo,
+    PlatformInboundInstruction, PlatformInfo, PlatformOutboundInstruction,
+};
+use log::debug;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Platform service of the test server. It directs clients to itself and acknowledges heartbeats, like
+/// AxonServer does.
+pub(crate) struct TestPlatformService {
+    port: u16,
+}
+
+impl TestPlatformService {
+    pub(crate) fn new(port: u16) -> Self {
+        TestPlatformService { port }
+    }
+}
+
+#[tonic::async_trait]
+impl PlatformService for TestPlatformService {
+    async fn get_platform_server(
+        &self,
+        request: Request<ClientIdentification>,
+    ) -> Result<Response<PlatformInfo>, Status> {
+        debug!(
+            "Test server: platform server: {:?}",
+            request.get_ref().client_id
+        );
+        Ok(Response::new(PlatformInfo {
+            primary: Some(NodeInfo {
+                host_name: TEST_SERVER_HOST.to_string(),
+                grpc_port: self.port as i32,
+                http_port: 0,
+                version: 0,
+                node_name: "test-server".to_string(),
+            }),
+            same_connection: true,
+        }))
+    }
+
+    type OpenStreamStream = ResponseStream<PlatformOutboundInstruction>;
+
+    async fn open_stream(
+        &self,
+        request: Request<Streaming<PlatformInboundInstruction>>,
+    ) -> Result<Response<Self::OpenStreamStream>, Status> {
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(Some(instruction)) = inbound.message().await {
+                if let Some(platform_inbound_instruction::Request::Heartbeat(_)) =
+                    instruction.request
+                {
+                    let ack = PlatformOutboundInstruction {
+                        instruction_id: "".to_string(),
+                        request: Some(platform_outbound_instruction::Request::Ack(
+                            instruction_ack(instruction.instruction_id),
+                        )),
+                    };
+                    if tx.send(Ok(ack)).is_err() {
+                        break;
+                    }
+                }
+            }
+            debug!("Test server: platform stream closed");
+        });
+        Ok(Response::new(response_stream(rx)))
+    }
+}
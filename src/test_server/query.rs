@@ -0,0 +1,215 @@
+use super::{error_message, instruction_ack, response_stream, Outbox, ResponseStream};
+use crate::axon_server::query::query_service_server::QueryService;
+use crate::axon_server::query::{
+    query_provider_inbound, query_provider_outbound, QueryProviderInbound, QueryProviderOutbound,
+    QueryRequest, QueryResponse, SubscriptionQueryRequest, SubscriptionQueryResponse,
+};
+use log::debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+const NO_HANDLER_FOR_QUERY: &str = "AXONIQ-5000";
+
+/// Query service of the test server.
+///
+/// A query is sent to one query processor of each component that subscribed to it, and the responses of all
+/// of them are streamed back to the sender. Subscription queries are not supported.
+#[derive(Clone, Default)]
+pub(crate) struct TestQueryService {
+    state: Arc<Mutex<QueryRouter>>,
+}
+
+#[derive(Default)]
+struct QueryRouter {
+    next_provider_id: u64,
+    providers: HashMap<u64, QueryProvider>,
+    pending: HashMap<String, PendingQuery>,
+}
+
+struct QueryProvider {
+    component_name: String,
+    queries: HashSet<String>,
+    outbox: Outbox<QueryProviderInbound>,
+}
+
+struct PendingQuery {
+    provider_ids: HashSet<u64>,
+    sender: UnboundedSender<Result<QueryResponse, Status>>,
+}
+
+impl QueryRouter {
+    fn handle_instruction(&mut self, provider_id: u64, instruction: QueryProviderOutbound) {
+        let provider = match self.providers.get_mut(&provider_id) {
+            Some(provider) => provider,
+            None => return,
+        };
+        match instruction.request {
+            Some(query_provider_outbound::Request::Subscribe(subscription)) => {
+                debug!("Test server: subscribe query: {:?}", subscription.query);
+                provider.component_name = subscription.component_name;
+                provider.queries.insert(subscription.query);
+            }
+            Some(query_provider_outbound::Request::Unsubscribe(subscription)) => {
+                provider.queries.remove(&subscription.query);
+            }
+            Some(query_provider_outbound::Request::FlowControl(flow_control)) => {
+                provider.outbox.add_permits(flow_control.permits);
+            }
+            Some(query_provider_outbound::Request::QueryResponse(response)) => {
+                if let Some(pending) = self.pending.get(&response.request_identifier) {
+                    pending.sender.send(Ok(response)).ok();
+                }
+            }
+            Some(query_provider_outbound::Request::QueryComplete(complete)) => {
+                self.complete(&complete.request_id, provider_id);
+            }
+            _ => (),
+        }
+        if !instruction.instruction_id.is_empty() {
+            if let Some(provider) = self.providers.get(&provider_id) {
+                provider.outbox.send_now(QueryProviderInbound {
+                    instruction_id: "".to_string(),
+                    request: Some(query_provider_inbound::Request::Ack(instruction_ack(
+                        instruction.instruction_id,
+                    ))),
+                });
+            }
+        }
+    }
+
+    /// Ends the response stream of a query when the last query processor has completed it.
+    fn complete(&mut self, request_id: &str, provider_id: u64) {
+        let done = match self.pending.get_mut(request_id) {
+            Some(pending) => {
+                pending.provider_ids.remove(&provider_id);
+                pending.provider_ids.is_empty()
+            }
+            None => false,
+        };
+        if done {
+            self.pending.remove(request_id);
+        }
+    }
+
+    fn disconnect(&mut self, provider_id: u64) {
+        self.providers.remove(&provider_id);
+        let request_ids: Vec<String> = self.pending.keys().cloned().collect();
+        for request_id in request_ids {
+            self.complete(&request_id, provider_id);
+        }
+    }
+
+    fn route(
+        &mut self,
+        query: QueryRequest,
+        sender: UnboundedSender<Result<QueryResponse, Status>>,
+    ) {
+        let mut targets = BTreeMap::new();
+        for (provider_id, provider) in &self.providers {
+            if provider.queries.contains(&query.query) {
+                let target = targets
+                    .entry(provider.component_name.clone())
+                    .or_insert(*provider_id);
+                *target = (*target).min(*provider_id);
+            }
+        }
+        if targets.is_empty() {
+            let message = format!("No handler for query: {:?}", query.query);
+            sender
+                .send(Ok(QueryResponse {
+                    message_identifier: format!("{}", Uuid::new_v4()),
+                    error_code: NO_HANDLER_FOR_QUERY.to_string(),
+                    error_message: Some(error_message(NO_HANDLER_FOR_QUERY, message)),
+                    payload: None,
+                    meta_data: HashMap::new(),
+                    processing_instructions: Vec::new(),
+                    request_identifier: query.message_identifier,
+                }))
+                .ok();
+            return;
+        }
+        let provider_ids: HashSet<u64> = targets.values().cloned().collect();
+        for provider_id in &provider_ids {
+            if let Some(provider) = self.providers.get_mut(provider_id) {
+                provider.outbox.push(QueryProviderInbound {
+                    instruction_id: "".to_string(),
+                    request: Some(query_provider_inbound::Request::Query(query.clone())),
+                });
+            }
+        }
+        self.pending.insert(
+            query.message_identifier,
+            PendingQuery {
+                provider_ids,
+                sender,
+            },
+        );
+    }
+}
+
+#[tonic::async_trait]
+impl QueryService for TestQueryService {
+    type OpenStreamStream = ResponseStream<QueryProviderInbound>;
+
+    async fn open_stream(
+        &self,
+        request: Request<Streaming<QueryProviderOutbound>>,
+    ) -> Result<Response<Self::OpenStreamStream>, Status> {
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let provider_id = {
+            let mut router = self.state.lock().unwrap();
+            let provider_id = router.next_provider_id;
+            router.next_provider_id += 1;
+            router.providers.insert(
+                provider_id,
+                QueryProvider {
+                    component_name: "".to_string(),
+                    queries: HashSet::new(),
+                    outbox: Outbox::new(tx),
+                },
+            );
+            provider_id
+        };
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(instruction)) = inbound.message().await {
+                state
+                    .lock()
+                    .unwrap()
+                    .handle_instruction(provider_id, instruction);
+            }
+            debug!(
+                "Test server: query provider disconnected: {:?}",
+                provider_id
+            );
+            state.lock().unwrap().disconnect(provider_id);
+        });
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    type QueryStream = ResponseStream<QueryResponse>;
+
+    async fn query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::QueryStream>, Status> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().route(request.into_inner(), tx);
+        Ok(Response::new(response_stream(rx)))
+    }
+
+    type SubscriptionStream = ResponseStream<SubscriptionQueryResponse>;
+
+    async fn subscription(
+        &self,
+        _request: Request<Streaming<SubscriptionQueryRequest>>,
+    ) -> Result<Response<Self::SubscriptionStream>, Status> {
+        Err(Status::unimplemented(
+            "Subscription queries are not supported by the test server",
+        ))
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use dendrite::axon_server::SerializedObject;
+use dendrite::axon_utils::{
+    axon_serialize, command_worker, create_aggregate_definition, empty_aggregate_registry,
+    empty_handler_registry, event_processor, query_processor, query_result_with_payloads,
+    AggregateContext, AggregateContextTrait, AggregateRegistry, ApplicableTo, CommandSink,
+    HandlerRegistry, QueryContext, QueryResult, QuerySink, TheHandlerRegistry, TokenStore,
+};
+use dendrite::test_server::start_test_server;
+use prost::Message;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+#[derive(Clone, PartialEq, Message)]
+struct Greeter {
+    #[prost(uint32, tag = "1")]
+    greetings: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GreetCommand {
+    #[prost(string, tag = "1")]
+    aggregate_identifier: String,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GreetedEvent {
+    #[prost(string, tag = "1")]
+    message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct SearchQuery {}
+
+#[derive(Clone, PartialEq, Message)]
+struct Greeting {
+    #[prost(string, tag = "1")]
+    message: String,
+}
+
+impl ApplicableTo<Greeter> for GreetedEvent {
+    fn apply_to(&self, projection: &mut Greeter) -> Result<()> {
+        projection.greetings += 1;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn ApplicableTo<Greeter>> {
+        Box::from(self.clone())
+    }
+}
+
+/// Query model that keeps greetings and its token in memory.
+#[derive(Clone, Default)]
+struct Greetings {
+    token: Arc<Mutex<Option<i64>>>,
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+#[tonic::async_trait]
+impl TokenStore for Greetings {
+    async fn store_token(&self, token: i64) {
+        *self.token.lock().unwrap() = Some(token);
+    }
+
+    async fn retrieve_token(&self) -> Result<i64> {
+        self.token
+            .lock()
+            .unwrap()
+            .ok_or_else(|| anyhow!("No token stored"))
+    }
+}
+
+impl QueryContext for Greetings {}
+
+type Context = Arc<async_lock::Mutex<AggregateContext<Greeter>>>;
+
+async fn handle_greet(command: GreetCommand, context: Context) -> Result<()> {
+    let mut context = context.lock().await;
+    context
+        .get_projection(&command.aggregate_identifier)
+        .await?;
+    context.emit(
+        "GreetedEvent",
+        Box::new(GreetedEvent {
+            message: command.message,
+        }),
+    )
+}
+
+async fn apply_greeted(event: GreetedEvent, mut greeter: Greeter) -> Result<Option<Greeter>> {
+    event.apply_to(&mut greeter)?;
+    Ok(Some(greeter))
+}
+
+async fn handle_greeted(event: GreetedEvent, greetings: Greetings) -> Result<()> {
+    greetings.messages.lock().unwrap().push(event.message);
+    Ok(())
+}
+
+async fn handle_search(_query: SearchQuery, greetings: Greetings) -> Result<Option<QueryResult>> {
+    let mut payloads = Vec::new();
+    for message in greetings.messages.lock().unwrap().iter() {
+        payloads.push(axon_serialize(
+            "Greeting",
+            &Greeting {
+                message: message.clone(),
+            },
+        )?);
+    }
+    Ok(Some(query_result_with_payloads(payloads)))
+}
+
+/// Retries the given operation until it returns a value, because workers subscribe asynchronously.
+async fn eventually<T, F: Future<Output = Result<Option<T>>>>(
+    operation: impl Fn() -> F,
+) -> Result<T> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let error = match operation().await {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => anyhow!("No result yet"),
+            Err(error) => error,
+        };
+        if Instant::now() > deadline {
+            return Err(error.context("Timeout"));
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn decode_greetings(payloads: &[SerializedObject]) -> Result<Vec<String>> {
+    let mut messages = Vec::new();
+    for payload in payloads {
+        assert_eq!(payload.r#type, "Greeting");
+        messages.push(Greeting::decode(payload.data.as_slice())?.message);
+    }
+    Ok(messages)
+}
+
+#[tokio::test]
+async fn command_results_in_query_result() -> Result<()> {
+    let test_server = start_test_server().await?;
+    let axon_server_handle = test_server.connect("round-trip-test").await?;
+
+    let mut command_handlers: TheHandlerRegistry<Context, SerializedObject> =
+        empty_handler_registry();
+    command_handlers.insert("GreetCommand", &GreetCommand::decode, &|c, p| {
+        Box::pin(handle_greet(c, p))
+    })?;
+    let mut sourcing_handlers: TheHandlerRegistry<Greeter, Greeter> = empty_handler_registry();
+    sourcing_handlers.insert_with_output("GreetedEvent", &GreetedEvent::decode, &|c, p| {
+        Box::pin(apply_greeted(c, p))
+    })?;
+    let mut aggregate_registry = empty_aggregate_registry();
+    aggregate_registry.insert(Arc::new(Arc::new(create_aggregate_definition(
+        "Greeter".to_string(),
+        Box::from(Greeter::default as fn() -> Greeter),
+        command_handlers,
+        sourcing_handlers,
+    ))))?;
+    let handle = axon_server_handle.clone();
+    let command_worker =
+        tokio::spawn(async move { command_worker(handle, &mut aggregate_registry).await });
+
+    let greetings = Greetings::default();
+    let mut event_handlers: TheHandlerRegistry<Greetings, Option<Greetings>> =
+        empty_handler_registry();
+    event_handlers.insert("GreetedEvent", &GreetedEvent::decode, &|c, p| {
+        Box::pin(handle_greeted(c, p))
+    })?;
+    let event_processor = tokio::spawn(event_processor(
+        axon_server_handle.clone(),
+        "Greetings",
+        greetings.clone(),
+        event_handlers,
+    ));
+
+    let mut query_handlers: TheHandlerRegistry<Greetings, QueryResult> = empty_handler_registry();
+    query_handlers.insert_with_output("SearchQuery", &SearchQuery::decode, &|c, p| {
+        Box::pin(handle_search(c, p))
+    })?;
+    let query_processor = tokio::spawn(query_processor(
+        axon_server_handle.clone(),
+        greetings.clone(),
+        query_handlers,
+    ));
+
+    let command = GreetCommand {
+        aggregate_identifier: "greeter-1".to_string(),
+        message: "Hello, World!".to_string(),
+    };
+    eventually(|| async {
+        axon_server_handle
+            .send_command("GreetCommand", Box::new(&command))
+            .await
+            .map(Some)
+    })
+    .await?;
+
+    let events = test_server.events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].aggregate_identifier, "greeter-1");
+    assert_eq!(events[0].aggregate_sequence_number, 0);
+
+    let messages = eventually(|| async {
+        let payloads = axon_server_handle
+            .send_query("SearchQuery", Box::new(&SearchQuery {}))
+            .await?;
+        let messages = decode_greetings(&payloads)?;
+        Ok(Some(messages).filter(|messages| !messages.is_empty()))
+    })
+    .await?;
+    assert_eq!(messages, vec!["Hello, World!".to_string()]);
+    assert_eq!(*greetings.token.lock().unwrap(), Some(0));
+
+    command_worker.abort();
+    event_processor.abort();
+    query_processor.abort();
+    Ok(())
+}
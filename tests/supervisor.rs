@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Result};
+use dendrite::axon_utils::{
+    empty_handler_registry, event_processor, supervise, AxonServerHandle, Backoff, Segment,
+    TokenStore,
+};
+use dendrite::test_server::start_test_server;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const PROCESSOR_NAME: &str = "Supervised";
+
+/// Token store that refuses to claim a segment that is still claimed, like a shared token store would.
+#[derive(Clone, Default)]
+struct ClaimingTokenStore {
+    token: Arc<Mutex<i64>>,
+    claims: Arc<Mutex<HashMap<i32, String>>>,
+    claim_count: Arc<Mutex<usize>>,
+}
+
+#[tonic::async_trait]
+impl TokenStore for ClaimingTokenStore {
+    async fn store_token(&self, token: i64) {
+        *self.token.lock().unwrap() = token;
+    }
+
+    async fn retrieve_token(&self) -> Result<i64> {
+        Ok(*self.token.lock().unwrap())
+    }
+
+    async fn claim_segment(&self, segment: &Segment, owner: &str) -> Result<bool> {
+        let mut claims = self.claims.lock().unwrap();
+        if claims.contains_key(&segment.segment_id) {
+            return Ok(false);
+        }
+        claims.insert(segment.segment_id, owner.to_string());
+        *self.claim_count.lock().unwrap() += 1;
+        Ok(true)
+    }
+
+    async fn release_segment(&self, segment: &Segment, owner: &str) -> Result<()> {
+        let mut claims = self.claims.lock().unwrap();
+        match claims.get(&segment.segment_id) {
+            Some(claimed_by) if claimed_by == owner => {
+                claims.remove(&segment.segment_id);
+                Ok(())
+            }
+            _ => Err(anyhow!("Segment not claimed by: {:?}", owner)),
+        }
+    }
+}
+
+async fn wait_until(condition: impl Fn() -> bool) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        if Instant::now() > deadline {
+            return Err(anyhow!("Timeout"));
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    Ok(())
+}
+
+fn is_registered(axon_server_handle: &AxonServerHandle) -> bool {
+    axon_server_handle
+        .event_processors
+        .names()
+        .contains(&PROCESSOR_NAME.to_string())
+}
+
+#[tokio::test]
+async fn supervised_event_processor_registers_again_after_reconnect() -> Result<()> {
+    let test_server = start_test_server().await?;
+    let axon_server_handle = test_server.connect("supervisor-test").await?;
+    let token_store = ClaimingTokenStore::default();
+    let backoff = Backoff {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(100),
+    };
+
+    let query_model = token_store.clone();
+    let supervisor = tokio::spawn(supervise(
+        axon_server_handle.clone(),
+        PROCESSOR_NAME,
+        backoff,
+        move |handle| {
+            event_processor(
+                handle,
+                PROCESSOR_NAME,
+                query_model.clone(),
+                empty_handler_registry(),
+            )
+        },
+    ));
+
+    wait_until(|| *token_store.claim_count.lock().unwrap() == 1).await?;
+    assert!(is_registered(&axon_server_handle));
+
+    axon_server_handle.reconnect_signal.request_reconnect();
+
+    wait_until(|| *token_store.claim_count.lock().unwrap() == 2).await?;
+    assert!(is_registered(&axon_server_handle));
+    assert_eq!(token_store.claims.lock().unwrap().len(), 1);
+
+    supervisor.abort();
+    Ok(())
+}